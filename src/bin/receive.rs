@@ -4,6 +4,8 @@ use rabbitmq_stream_client::types::{ByteCapacity, OffsetSpecification, ResponseC
 use std::io::stdin;
 use tokio::task;
 
+// Stream creation errors are matched step by step, as in the client's examples
+#[allow(clippy::collapsible_if, clippy::collapsible_match)]
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     use rabbitmq_stream_client::Environment;
@@ -37,13 +39,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let handle = consumer.handle();
     task::spawn(async move {
         while let Some(delivery) = consumer.next().await {
-            let d = delivery.unwrap();
+            let d = match delivery {
+                Ok(d) => d,
+                Err(e) => {
+                    println!("Error receiving message: {:?}", e);
+                    continue;
+                }
+            };
+            let Some(data) = d.message().data() else {
+                println!("Skipping empty message with offset: {}", d.offset());
+                continue;
+            };
             println!(
                 "Got message: \n{} with offset: {}",
-                d.message()
-                    .data()
-                    .map(|data| String::from_utf8(data.to_vec()).unwrap())
-                    .unwrap(),
+                String::from_utf8_lossy(data),
                 d.offset(),
             );
         }
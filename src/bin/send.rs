@@ -1,9 +1,17 @@
 use rabbitmq_stream_client::error::StreamCreateError;
 use rabbitmq_stream_client::types::{ByteCapacity, Message, ResponseCode};
+use supercode_rust::connect;
+use tracing_subscriber::EnvFilter;
+
+// Stream creation errors are matched step by step, as in the client's examples
+#[allow(clippy::collapsible_if, clippy::collapsible_match)]
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    use rabbitmq_stream_client::Environment;
-    let environment = Environment::builder().build().await?;
+    // Connection retries are logged, filtered by `RUST_LOG` (`info` by default)
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+    let (attempts, delay) = connect::attempts_from_env();
+    let environment = connect::connect_with_retry(attempts, delay).await?;
     let stream = "Server2Runner";
     let create_response = environment
         .stream_creator()
@@ -0,0 +1,141 @@
+use std::sync::Mutex;
+
+use tracing::{info, warn};
+
+use crate::service::available_memory;
+
+// Host pressure the breaker decides on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HostMetrics {
+    // 1-minute load average divided by the number of CPUs
+    pub load_per_cpu: f64,
+    // `MemAvailable`, in KB
+    pub available_memory: u64,
+}
+
+impl HostMetrics {
+    // From /proc; `None` where it can't be read
+    pub fn read() -> Option<Self> {
+        let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+        let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+        let cpus = std::thread::available_parallelism().map_or(1, usize::from);
+        Some(HostMetrics {
+            load_per_cpu: load / cpus as f64,
+            available_memory: available_memory()?,
+        })
+    }
+}
+
+// Rejects new submissions while the host is under pressure. It trips when the load per CPU
+// exceeds `max_load` or available memory drops below `min_memory`, and resets once both
+// are back a margin inside their thresholds, so readings hovering at a threshold don't
+// flip it on every submission.
+pub struct CircuitBreaker {
+    max_load: Option<f64>,
+    min_memory: Option<u64>,
+    tripped: Mutex<bool>,
+}
+
+// Share of a threshold readings must recover by before the breaker resets
+const RECOVERY_MARGIN: f64 = 0.1;
+
+impl CircuitBreaker {
+    pub fn new(max_load: Option<f64>, min_memory: Option<u64>) -> Self {
+        CircuitBreaker {
+            max_load,
+            min_memory,
+            tripped: Mutex::new(false),
+        }
+    }
+
+    // Enabled by `BREAKER_MAX_LOAD` (load average per CPU) and/or `BREAKER_MIN_MEMORY` (KB)
+    pub fn from_env() -> Option<Self> {
+        fn var<T: std::str::FromStr>(key: &str) -> Option<T> {
+            std::env::var(key)
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+        }
+        let max_load = var("BREAKER_MAX_LOAD");
+        let min_memory = var("BREAKER_MIN_MEMORY");
+        (max_load.is_some() || min_memory.is_some())
+            .then(|| CircuitBreaker::new(max_load, min_memory))
+    }
+
+    // Whether a submission may run under `metrics`, updating the breaker; otherwise the
+    // reason to give the client for trying later
+    pub fn check(&self, metrics: HostMetrics) -> Result<(), String> {
+        let mut tripped = self.tripped.lock().unwrap();
+        // While tripped, thresholds are tightened by the recovery margin
+        let margin = if *tripped { RECOVERY_MARGIN } else { 0.0 };
+        let mut pressure = vec![];
+        if let Some(max) = self.max_load
+            && metrics.load_per_cpu > max * (1.0 - margin)
+        {
+            pressure.push(format!(
+                "load {:.2} per CPU, limit {:.2}",
+                metrics.load_per_cpu, max
+            ));
+        }
+        if let Some(min) = self.min_memory
+            && (metrics.available_memory as f64) < min as f64 * (1.0 + margin)
+        {
+            pressure.push(format!(
+                "{} KB memory available, {} KB required",
+                metrics.available_memory, min
+            ));
+        }
+        let reason = pressure.join(", ");
+        match (*tripped, pressure.is_empty()) {
+            (false, false) => warn!(reason, "circuit breaker tripped"),
+            (true, true) => info!("circuit breaker reset"),
+            _ => {}
+        }
+        *tripped = !pressure.is_empty();
+        if pressure.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Host under pressure, try later: {}", reason))
+        }
+    }
+
+    // `check` against the host's current metrics, letting submissions through when they
+    // can't be read
+    pub fn check_host(&self) -> Result<(), String> {
+        HostMetrics::read().map_or(Ok(()), |metrics| self.check(metrics))
+    }
+}
+
+#[cfg(test)]
+mod breaker_test {
+    use super::*;
+
+    fn metrics(load_per_cpu: f64, available_memory: u64) -> HostMetrics {
+        HostMetrics {
+            load_per_cpu,
+            available_memory,
+        }
+    }
+
+    #[test]
+    fn trips_and_recovers() {
+        let breaker = CircuitBreaker::new(Some(2.0), Some(1_000_000));
+        assert!(breaker.check(metrics(0.5, 4_000_000)).is_ok());
+
+        let error = breaker.check(metrics(3.5, 4_000_000)).unwrap_err();
+        assert_eq!(
+            error,
+            "Host under pressure, try later: load 3.50 per CPU, limit 2.00"
+        );
+        // Still tripped just under the threshold, until readings recover past the margin
+        assert!(breaker.check(metrics(1.9, 4_000_000)).is_err());
+        assert!(breaker.check(metrics(1.5, 4_000_000)).is_ok());
+        assert!(breaker.check(metrics(1.9, 4_000_000)).is_ok());
+
+        assert!(breaker.check(metrics(0.5, 200_000)).is_err());
+        assert!(breaker.check(metrics(0.5, 1_050_000)).is_err());
+        assert!(breaker.check(metrics(0.5, 2_000_000)).is_ok());
+
+        let load_only = CircuitBreaker::new(Some(2.0), None);
+        assert!(load_only.check(metrics(0.5, 0)).is_ok());
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::service::ResponseData;
+
+// Outcome of handing a submission to the `Coalescer`
+pub enum Coalesced {
+    // The submission was executed; `None` when it failed
+    Ran(Option<Arc<ResponseData>>),
+    // Identical to a recent run, whose result is shared unless duplicates go unanswered
+    Shared(Option<Arc<ResponseData>>),
+}
+
+// `submit_id` and a hash of the submission
+type Key = (String, u64);
+
+enum Entry {
+    Running,
+    // When the run finished, and its result
+    Finished(Instant, Arc<ResponseData>),
+}
+
+// Shares one execution between identical submissions (same `submit_id` and content)
+// arriving while it runs or within `window` after it finished, e.g. during a client retry
+// storm
+pub struct Coalescer {
+    window: Duration,
+    // Publish the shared result once per duplicate rather than only for the first run
+    answer_duplicates: bool,
+    recent: Mutex<HashMap<Key, Entry>>,
+    // Signalled whenever a run finishes, for the duplicates waiting on it
+    finished: Condvar,
+}
+
+// A run in progress, whose entry is taken out again if it doesn't finish with a result
+struct InFlight<'a> {
+    coalescer: &'a Coalescer,
+    key: Key,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut recent = self.coalescer.recent.lock().unwrap();
+        if let Some(Entry::Running) = recent.get(&self.key) {
+            recent.remove(&self.key);
+        }
+        self.coalescer.finished.notify_all();
+    }
+}
+
+impl Coalescer {
+    pub fn new(window: Duration, answer_duplicates: bool) -> Self {
+        Coalescer {
+            window,
+            answer_duplicates,
+            recent: Mutex::default(),
+            finished: Condvar::new(),
+        }
+    }
+
+    // Enabled by `COALESCE_WINDOW_MS`; `COALESCE_ANSWER_DUPLICATES=false` answers only once
+    pub fn from_env() -> Option<Self> {
+        let window = std::env::var("COALESCE_WINDOW_MS").ok()?.parse().ok()?;
+        let answer_duplicates = std::env::var("COALESCE_ANSWER_DUPLICATES")
+            .map(|value| value != "false" && value != "0")
+            .unwrap_or(true);
+        Some(Coalescer::new(
+            Duration::from_millis(window),
+            answer_duplicates,
+        ))
+    }
+
+    // Runs the submission in `message` unless an identical one finished within the window,
+    // waiting for the result of one still running. Failed runs aren't remembered, so the
+    // duplicates waiting on one execute again, one at a time.
+    pub fn run(
+        &self,
+        submit_id: &str,
+        message: &str,
+        now: Instant,
+        run: impl FnOnce() -> Option<ResponseData>,
+    ) -> Coalesced {
+        let mut hasher = DefaultHasher::new();
+        message.hash(&mut hasher);
+        let key = (submit_id.to_string(), hasher.finish());
+        let mut recent = self.recent.lock().unwrap();
+        loop {
+            self.forget_expired(&mut recent, now);
+            match recent.get(&key) {
+                Some(Entry::Finished(_, result)) => {
+                    return Coalesced::Shared(self.answer_duplicates.then(|| result.clone()));
+                }
+                Some(Entry::Running) => recent = self.finished.wait(recent).unwrap(),
+                None => break,
+            }
+        }
+        recent.insert(key.clone(), Entry::Running);
+        drop(recent);
+        let in_flight = InFlight {
+            coalescer: self,
+            key,
+        };
+        let result = run().map(Arc::new);
+        if let Some(result) = &result {
+            self.recent.lock().unwrap().insert(
+                in_flight.key.clone(),
+                Entry::Finished(Instant::now(), result.clone()),
+            );
+        }
+        drop(in_flight);
+        Coalesced::Ran(result)
+    }
+
+    // Drop results that finished longer than the window before `now`
+    fn forget_expired(&self, recent: &mut HashMap<Key, Entry>, now: Instant) {
+        recent.retain(|_, entry| match entry {
+            Entry::Running => true,
+            Entry::Finished(finished, _) => now.duration_since(*finished) <= self.window,
+        });
+    }
+}
+
+#[cfg(test)]
+mod coalesce_test {
+    use std::sync::Barrier;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use super::*;
+    use crate::service::{FormData, SandboxConfig};
+
+    fn response(submit_id: &str) -> Option<ResponseData> {
+        let form_data = FormData {
+            submit_id: submit_id.to_string(),
+            ..Default::default()
+        };
+        Some(ResponseData::new(
+            &SandboxConfig::default(),
+            &form_data,
+            vec![],
+        ))
+    }
+
+    #[test]
+    fn identical_submissions_share_one_run() {
+        for answer_duplicates in [true, false] {
+            let coalescer = Coalescer::new(Duration::from_millis(500), answer_duplicates);
+            let start = Instant::now();
+            let mut runs = 0;
+            let mut responses = 0;
+            for offset in [0, 10, 20] {
+                let now = start + Duration::from_millis(offset);
+                let outcome = coalescer.run("1", "submission", now, || {
+                    runs += 1;
+                    response("1")
+                });
+                if let Coalesced::Ran(Some(_)) | Coalesced::Shared(Some(_)) = outcome {
+                    responses += 1;
+                }
+            }
+            assert_eq!(runs, 1);
+            assert_eq!(responses, if answer_duplicates { 3 } else { 1 });
+
+            // Different content, or the same content after the window, runs again
+            let later = start + Duration::from_millis(20);
+            coalescer.run("1", "changed", later, || {
+                runs += 1;
+                response("1")
+            });
+            coalescer.run("1", "submission", start + Duration::from_secs(1), || {
+                runs += 1;
+                response("1")
+            });
+            assert_eq!(runs, 3);
+        }
+    }
+
+    #[test]
+    fn concurrent_submissions_wait_for_one_run() {
+        for answer_duplicates in [true, false] {
+            let coalescer = Coalescer::new(Duration::from_millis(500), answer_duplicates);
+            let runs = AtomicUsize::new(0);
+            let barrier = Barrier::new(3);
+            let outcomes: Vec<Coalesced> = thread::scope(|scope| {
+                let handles: Vec<_> = (0..3)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            barrier.wait();
+                            coalescer.run("1", "submission", Instant::now(), || {
+                                runs.fetch_add(1, Ordering::SeqCst);
+                                // Long enough for the others to arrive while it runs
+                                thread::sleep(Duration::from_millis(200));
+                                response("1")
+                            })
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            });
+            assert_eq!(runs.load(Ordering::SeqCst), 1);
+            let ran = outcomes
+                .iter()
+                .filter(|outcome| matches!(outcome, Coalesced::Ran(Some(_))))
+                .count();
+            let shared = outcomes
+                .iter()
+                .filter(|outcome| matches!(outcome, Coalesced::Shared(Some(_))))
+                .count();
+            assert_eq!(ran, 1);
+            assert_eq!(shared, if answer_duplicates { 2 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn window_counts_from_when_run_finished() {
+        let coalescer = Coalescer::new(Duration::from_millis(100), true);
+        let start = Instant::now();
+        coalescer.run("1", "submission", start, || {
+            thread::sleep(Duration::from_millis(150));
+            response("1")
+        });
+        // Past the window from when the first arrived, but not from when it finished
+        let outcome = coalescer.run("1", "submission", Instant::now(), || response("1"));
+        assert!(matches!(outcome, Coalesced::Shared(Some(_))));
+    }
+
+    #[test]
+    fn failed_runs_not_shared() {
+        let coalescer = Coalescer::new(Duration::from_millis(500), true);
+        let now = Instant::now();
+        let mut runs = 0;
+        for _ in 0..2 {
+            coalescer.run("1", "submission", now, || {
+                runs += 1;
+                None
+            });
+        }
+        assert_eq!(runs, 2);
+    }
+}
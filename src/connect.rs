@@ -0,0 +1,99 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+
+use rabbitmq_stream_client::{Environment, error::ClientError};
+use tracing::{error, warn};
+
+// `BROKER_CONNECT_ATTEMPTS` (5 by default) and `BROKER_CONNECT_DELAY_MS` (500 by default)
+pub fn attempts_from_env() -> (u32, Duration) {
+    fn var<T: std::str::FromStr>(key: &str) -> Option<T> {
+        std::env::var(key).ok().and_then(|value| value.parse().ok())
+    }
+    (
+        var("BROKER_CONNECT_ATTEMPTS").unwrap_or(5).max(1),
+        Duration::from_millis(var("BROKER_CONNECT_DELAY_MS").unwrap_or(500)),
+    )
+}
+
+// Connect to the broker, which may still be starting when the runner is, retrying with
+// exponential backoff. Returns the last error once `max_attempts` have failed.
+pub async fn connect_with_retry(
+    max_attempts: u32,
+    base_delay: Duration,
+) -> Result<Environment, ClientError> {
+    retry(max_attempts, base_delay, || Environment::builder().build()).await
+}
+
+// Delay before retrying after the `attempt`-th failure: between half and all of
+// `base_delay * 2^(attempt - 1)`, so runners started together don't retry in lockstep
+fn backoff(attempt: u32, base_delay: Duration) -> Duration {
+    let delay = base_delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+    let jitter = (uuid::Uuid::new_v4().as_u128() % 1000) as u32;
+    delay / 2 + delay / 2 * jitter / 1000
+}
+
+async fn retry<T, E: Debug, F: Future<Output = Result<T, E>>>(
+    max_attempts: u32,
+    base_delay: Duration,
+    mut attempt: impl FnMut() -> F,
+) -> Result<T, E> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempts >= max_attempts => {
+                error!(attempts, "giving up connecting to broker");
+                return Err(e);
+            }
+            Err(e) => {
+                let delay = backoff(attempts, base_delay);
+                warn!(
+                    attempt = attempts,
+                    max_attempts,
+                    retry_in_ms = delay.as_millis() as u64,
+                    error = ?e,
+                    "connecting to broker failed"
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod connect_test {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn retried_until_connected() {
+        let calls = Cell::new(0);
+        let connected = retry(5, Duration::from_millis(1), || {
+            calls.set(calls.get() + 1);
+            let call = calls.get();
+            async move { if call < 3 { Err(call) } else { Ok("connected") } }
+        })
+        .await;
+        assert_eq!(connected, Ok("connected"));
+        assert_eq!(calls.get(), 3);
+
+        calls.set(0);
+        let failed: Result<(), u32> = retry(4, Duration::from_millis(1), || {
+            calls.set(calls.get() + 1);
+            let call = calls.get();
+            async move { Err(call) }
+        })
+        .await;
+        assert_eq!(failed, Err(4));
+        assert_eq!(calls.get(), 4);
+
+        for attempt in 1..=4 {
+            let delay = backoff(attempt, Duration::from_millis(100));
+            let full = Duration::from_millis(100 << (attempt - 1));
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+    }
+}
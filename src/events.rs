@@ -0,0 +1,169 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rabbitmq_stream_client::{Environment, error::ProducerCreateError, types::Message};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::service::Phase;
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Progress of a submission through the runner. Between `Started` and `Done`, the phase of
+// the commands starting, as far as the runner sees: commands sharing a container report
+// only the first one's.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Queued,
+    Started,
+    Compiling,
+    Running,
+    Done,
+    Failed,
+}
+
+impl Stage {
+    pub fn of(phase: Phase) -> Self {
+        match phase {
+            Phase::Build => Stage::Compiling,
+            Phase::Run | Phase::Check => Stage::Running,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LifecycleEvent {
+    pub submit_id: String,
+    pub stage: Stage,
+    pub timestamp: u64, // Milliseconds since the Unix epoch
+    // Set on `Queued` when queue positions are reported: how many submissions were waiting
+    // to start when this one was received, itself included
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+}
+
+// Cheap handle for emitting lifecycle events, a no-op when events are disabled
+#[derive(Clone, Default)]
+pub struct EventSink {
+    sender: Option<mpsc::UnboundedSender<LifecycleEvent>>,
+    // Submissions queued but not started yet, when queue positions are reported
+    queued: Option<Arc<AtomicUsize>>,
+}
+
+// Keeps a submission counted as queued until dropped
+pub struct QueueTicket(Option<Arc<AtomicUsize>>);
+
+impl EventSink {
+    pub fn new(sender: mpsc::UnboundedSender<LifecycleEvent>) -> Self {
+        EventSink {
+            sender: Some(sender),
+            queued: None,
+        }
+    }
+
+    // Report each submission's position in the queue on its `Queued` event
+    pub fn with_queue_positions(self) -> Self {
+        EventSink {
+            queued: Some(Arc::default()),
+            ..self
+        }
+    }
+
+    pub fn emit(&self, submit_id: &str, stage: Stage) {
+        self.send(submit_id, stage, None);
+    }
+
+    // Emit `Queued` for a submission waiting to start, which it is until the ticket is dropped
+    pub fn enqueue(&self, submit_id: &str) -> QueueTicket {
+        let position = self
+            .queued
+            .as_ref()
+            .map(|queued| queued.fetch_add(1, Ordering::SeqCst) + 1);
+        self.send(submit_id, Stage::Queued, position);
+        QueueTicket(self.queued.clone())
+    }
+
+    fn send(&self, submit_id: &str, stage: Stage, queue_position: Option<usize>) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(LifecycleEvent {
+                submit_id: submit_id.to_string(),
+                stage,
+                timestamp: now_millis(),
+                queue_position,
+            });
+        }
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        if let Some(queued) = &self.0 {
+            queued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// Publish every emitted event to `stream` from a dedicated task
+pub async fn spawn_publisher(
+    environment: &Environment,
+    stream: &str,
+) -> Result<EventSink, ProducerCreateError> {
+    let producer = environment.producer().build(stream).await?;
+    let (sender, mut receiver) = mpsc::unbounded_channel::<LifecycleEvent>();
+    tokio::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            let message = Message::builder()
+                .body(serde_yaml::to_string(&event).unwrap_or_default())
+                .build();
+            if let Err(e) = producer.send_with_confirm(message).await {
+                error!(error = ?e, "publishing lifecycle event failed");
+            }
+        }
+    });
+    Ok(EventSink::new(sender))
+}
+
+#[cfg(test)]
+mod events_test {
+    use super::*;
+
+    #[test]
+    fn queue_positions_increase() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let events = EventSink::new(sender).with_queue_positions();
+        let first = events.enqueue("a");
+        let second = events.enqueue("b");
+        let _third = events.enqueue("c");
+        drop(first);
+        // Started submissions leave the queue
+        let _fourth = events.enqueue("d");
+        drop(second);
+        let _fifth = events.enqueue("e");
+
+        let mut positions = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            assert_eq!(event.stage, Stage::Queued);
+            positions.push((event.submit_id, event.queue_position.unwrap()));
+        }
+        let positions: Vec<(&str, usize)> = positions
+            .iter()
+            .map(|(submit_id, position)| (submit_id.as_str(), *position))
+            .collect();
+        assert_eq!(
+            positions,
+            [("a", 1), ("b", 2), ("c", 3), ("d", 3), ("e", 3)]
+        );
+
+        // Positions are left out unless asked for
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let _ticket = EventSink::new(sender).enqueue("a");
+        assert_eq!(receiver.try_recv().unwrap().queue_position, None);
+    }
+}
@@ -0,0 +1,122 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use axum::{Router, extract::State, http::StatusCode, routing::get};
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::net::TcpListener;
+
+// What `/readyz` reports on, shared with the consumer loop that keeps it current
+#[derive(Clone)]
+pub struct Health {
+    broker_connected: Arc<AtomicBool>,
+    // The executor every submission is run with
+    executor: PathBuf,
+}
+
+impl Health {
+    pub fn new(sandbox_root: &str) -> Self {
+        Health {
+            broker_connected: Arc::default(),
+            executor: Path::new(sandbox_root).join("sandbox"),
+        }
+    }
+
+    pub fn set_broker_connected(&self, connected: bool) {
+        self.broker_connected.store(connected, Ordering::SeqCst);
+    }
+
+    fn ready(&self) -> Result<(), &'static str> {
+        if !self.broker_connected.load(Ordering::SeqCst) {
+            return Err("broker connection down");
+        }
+        if !self.executor.exists() {
+            return Err("sandbox folder missing");
+        }
+        Ok(())
+    }
+}
+
+// `HEALTH_PORT`, when the endpoints are enabled
+pub fn port_from_env() -> Option<u16> {
+    std::env::var("HEALTH_PORT")
+        .ok()
+        .and_then(|port| port.trim().parse().ok())
+}
+
+// `/healthz` answers as long as the process is up; `/readyz` only while the broker is
+// connected and the sandbox folder is present, with 503 otherwise so load balancers route
+// around the runner. `/metrics` is served when given the metrics handle.
+pub fn router(health: Health, metrics: Option<PrometheusHandle>) -> Router {
+    let router = match metrics {
+        Some(metrics) => {
+            Router::new().route("/metrics", get(move || async move { metrics.render() }))
+        }
+        None => Router::new(),
+    };
+    router
+        .route("/healthz", get(|| async { "ok" }))
+        .route(
+            "/readyz",
+            get(|State(health): State<Health>| async move {
+                match health.ready() {
+                    Ok(()) => (StatusCode::OK, "ready"),
+                    Err(reason) => (StatusCode::SERVICE_UNAVAILABLE, reason),
+                }
+            }),
+        )
+        .with_state(health)
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    health: Health,
+    metrics: Option<PrometheusHandle>,
+) -> std::io::Result<()> {
+    axum::serve(listener, router(health, metrics)).await
+}
+
+#[cfg(test)]
+mod health_test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    async fn status(port: u16, path: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_broker() {
+        let root = std::env::temp_dir().join(format!("health-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("sandbox")).unwrap();
+        let health = Health::new(root.to_str().unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, health.clone(), None));
+
+        assert_eq!(status(port, "/healthz").await, "HTTP/1.1 200 OK");
+        assert_eq!(status(port, "/metrics").await, "HTTP/1.1 404 Not Found");
+        assert_eq!(
+            status(port, "/readyz").await,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+        health.set_broker_connected(true);
+        assert_eq!(status(port, "/readyz").await, "HTTP/1.1 200 OK");
+
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            status(port, "/readyz").await,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+    }
+}
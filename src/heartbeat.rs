@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use rabbitmq_stream_client::{Environment, error::ProducerCreateError, types::Message};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::events::now_millis;
+use crate::telemetry;
+
+// Periodic liveness signal, so monitoring can tell a wedged runner from an idle one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Heartbeat {
+    pub instance_id: String,
+    pub in_flight: usize,
+    pub uptime_ms: u64,
+    pub timestamp: u64, // Milliseconds since the Unix epoch
+}
+
+// Number of submissions currently executing
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+// Counts a submission as in flight until dropped
+pub struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn start(&self) -> InFlightGuard {
+        telemetry::in_flight_changed(self.0.fetch_add(1, Ordering::SeqCst) + 1);
+        InFlightGuard(self.0.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        telemetry::in_flight_changed(self.0.fetch_sub(1, Ordering::SeqCst) - 1);
+    }
+}
+
+// `HEARTBEAT_INSTANCE_ID`, falling back to the container hostname, then a random id
+pub fn instance_id() -> String {
+    std::env::var("HEARTBEAT_INSTANCE_ID")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| uuid::Uuid::new_v4().to_string())
+}
+
+// `HEARTBEAT_INTERVAL_MS`, every 10 seconds by default
+pub fn interval_from_env() -> Duration {
+    Duration::from_millis(
+        std::env::var("HEARTBEAT_INTERVAL_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(10_000),
+    )
+}
+
+// Send a heartbeat to `sender` every `interval`, starting immediately, until the
+// receiving side goes away
+pub fn spawn_heartbeats(
+    instance_id: String,
+    interval: Duration,
+    in_flight: InFlight,
+    sender: mpsc::UnboundedSender<Heartbeat>,
+) {
+    let started = Instant::now();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let heartbeat = Heartbeat {
+                instance_id: instance_id.clone(),
+                in_flight: in_flight.count(),
+                uptime_ms: started.elapsed().as_millis() as u64,
+                timestamp: now_millis(),
+            };
+            if sender.send(heartbeat).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// Publish heartbeats to `stream` from a dedicated task
+pub async fn spawn_publisher(
+    environment: &Environment,
+    stream: &str,
+    interval: Duration,
+    in_flight: InFlight,
+) -> Result<(), ProducerCreateError> {
+    let producer = environment.producer().build(stream).await?;
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Heartbeat>();
+    spawn_heartbeats(instance_id(), interval, in_flight, sender);
+    tokio::spawn(async move {
+        while let Some(heartbeat) = receiver.recv().await {
+            let message = Message::builder()
+                .body(serde_yaml::to_string(&heartbeat).unwrap_or_default())
+                .build();
+            if let Err(e) = producer.send_with_confirm(message).await {
+                error!(error = ?e, "publishing heartbeat failed");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod heartbeat_test {
+    use super::*;
+
+    // The paused clock only moves when every task is waiting on it, so ticks land exactly
+    #[tokio::test(start_paused = true)]
+    async fn published_at_configured_cadence() {
+        let in_flight = InFlight::default();
+        let running = in_flight.start();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let interval = Duration::from_millis(50);
+        spawn_heartbeats("runner-1".to_string(), interval, in_flight.clone(), sender);
+
+        tokio::time::sleep(Duration::from_millis(275)).await;
+        drop(running);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut heartbeats = vec![];
+        while let Ok(heartbeat) = receiver.try_recv() {
+            heartbeats.push(heartbeat);
+        }
+        // Ticks at 0, 50, ..., 300 ms
+        assert_eq!(heartbeats.len(), 7);
+        assert!(heartbeats.iter().all(|h| h.instance_id == "runner-1"));
+        assert_eq!(heartbeats[0].in_flight, 1);
+        assert_eq!(heartbeats.last().unwrap().in_flight, 0);
+        for pair in heartbeats.windows(2) {
+            let gap = pair[1].uptime_ms - pair[0].uptime_ms;
+            assert_eq!(gap, 50);
+            assert!(pair[1].timestamp >= pair[0].timestamp);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_yaml::{Mapping, Value};
+use tracing::error;
+
+use crate::service::{FormData, ResponseData};
+
+const REDACTED: &str = "[redacted]";
+
+// The last `capacity` submissions and their responses, kept on disk for reproducing
+// intermittent failures. Each is a YAML file named by a sequence number, so the oldest
+// sorts first and is the one evicted.
+pub struct History {
+    dir: PathBuf,
+    capacity: usize,
+    // Leave command inputs, `.env` contents and workspaces, where sources usually are, out
+    // of the history
+    redact: bool,
+    next: Mutex<u64>,
+}
+
+impl History {
+    pub fn new(dir: impl Into<PathBuf>, capacity: usize, redact: bool) -> std::io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let next = entries(&dir)?.last().map_or(0, |(seq, _)| seq + 1);
+        Ok(History {
+            dir,
+            capacity: capacity.max(1),
+            redact,
+            next: Mutex::new(next),
+        })
+    }
+
+    // Enabled by `SUBMISSION_HISTORY_DIR`, keeping `SUBMISSION_HISTORY_SIZE` submissions
+    // (20 by default), redacted when `SUBMISSION_HISTORY_REDACT` is set
+    pub fn from_env() -> std::io::Result<Option<Self>> {
+        let Ok(dir) = std::env::var("SUBMISSION_HISTORY_DIR") else {
+            return Ok(None);
+        };
+        let capacity = std::env::var("SUBMISSION_HISTORY_SIZE")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(20);
+        let redact = std::env::var("SUBMISSION_HISTORY_REDACT")
+            .is_ok_and(|value| matches!(value.trim(), "1" | "true" | "yes" | "on"));
+        History::new(dir, capacity, redact).map(Some)
+    }
+
+    // Add a submission with its response, `None` when it failed, evicting the oldest
+    // entries beyond the capacity. Errors are logged, as the history is only a debugging aid.
+    pub fn record(&self, form_data: &FormData, response: Option<&ResponseData>) {
+        if let Err(e) = self.try_record(form_data, response) {
+            error!(
+                submit_id = %form_data.submit_id,
+                error = %e,
+                "recording submission in history failed"
+            );
+        }
+    }
+
+    fn try_record(
+        &self,
+        form_data: &FormData,
+        response: Option<&ResponseData>,
+    ) -> Result<(), String> {
+        let mut submission = serde_yaml::to_value(form_data).map_err(|e| e.to_string())?;
+        if self.redact {
+            redact(&mut submission);
+        }
+        let mut entry = Mapping::new();
+        entry.insert("submission".into(), submission);
+        entry.insert(
+            "response".into(),
+            serde_yaml::to_value(response).map_err(|e| e.to_string())?,
+        );
+        let contents = serde_yaml::to_string(&entry).map_err(|e| e.to_string())?;
+
+        let mut next = self.next.lock().unwrap();
+        fs::write(self.dir.join(format!("{:010}.yaml", *next)), contents)
+            .map_err(|e| e.to_string())?;
+        *next += 1;
+        let entries = entries(&self.dir).map_err(|e| e.to_string())?;
+        for (_, path) in &entries[..entries.len().saturating_sub(self.capacity)] {
+            fs::remove_file(path).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // Every recorded entry, oldest first
+    pub fn read(&self) -> std::io::Result<Vec<String>> {
+        entries(&self.dir)?
+            .into_iter()
+            .map(|(_, path)| fs::read_to_string(path))
+            .collect()
+    }
+}
+
+// History files in `dir` with their sequence numbers, in order
+fn entries(dir: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let seq = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".yaml"))
+            .and_then(|seq| seq.parse().ok());
+        if let Some(seq) = seq {
+            entries.push((seq, path));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+fn redact(submission: &mut Value) {
+    for key in ["env_file", "workspace"] {
+        if let Some(value) = submission.get_mut(key).filter(|value| !value.is_null()) {
+            *value = REDACTED.into();
+        }
+    }
+    let commands = submission
+        .get_mut("commands")
+        .and_then(Value::as_sequence_mut);
+    for command in commands.into_iter().flatten() {
+        if let Some(input) = command.get_mut("input") {
+            *input = REDACTED.into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod history_test {
+    use super::*;
+    use crate::service::{CMD, SandboxConfig};
+
+    #[test]
+    fn most_recent_kept() {
+        let dir = std::env::temp_dir().join(format!("history-{}", uuid::Uuid::new_v4()));
+        let history = History::new(&dir, 3, true).unwrap();
+        let config = SandboxConfig::default();
+        for index in 0..5 {
+            let form_data = FormData {
+                commands: vec![CMD {
+                    command: "./main".to_string(),
+                    input: "int main() {}".to_string(),
+                    ..Default::default()
+                }],
+                image: "gcc:14.2",
+                submit_id: format!("submission-{}", index),
+                ..Default::default()
+            };
+            let response = ResponseData::new(&config, &form_data, vec![]);
+            history.record(&form_data, (index != 3).then_some(&response));
+        }
+
+        let entries: Vec<Value> = history
+            .read()
+            .unwrap()
+            .iter()
+            .map(|entry| serde_yaml::from_str(entry).unwrap())
+            .collect();
+        let ids: Vec<&str> = entries
+            .iter()
+            .map(|entry| entry["submission"]["submit_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["submission-2", "submission-3", "submission-4"]);
+        assert!(entries[1]["response"].is_null());
+        assert_eq!(entries[2]["response"]["submit_id"], "submission-4");
+        assert_eq!(entries[2]["submission"]["commands"][0]["input"], REDACTED);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+
+        // Numbering carries on after a restart
+        let reopened = History::new(&dir, 3, false).unwrap();
+        reopened.record(&FormData::default(), None);
+        assert!(dir.join("0000000005.yaml").is_file());
+        assert!(!dir.join("0000000002.yaml").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs;
+
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use crate::service::Config;
+use crate::strict;
+use crate::verdict::Comparator;
+
+// Defaults for images matching `image` (exact, or a prefix when it ends with `*`), read
+// from the manifest named by `SANDBOX_IMAGE_MANIFEST`:
+//
+// - image: "gcc:*"
+//   limits: {time_limit: 2, memory_limit: 512000}
+//   cpu_ulimit: true
+//   capabilities: [SYS_PTRACE]
+//   seccomp_profile: /etc/supercode/gcc.json
+//   entrypoint: ""
+//   home: .home
+//   weight: 2
+//   comparator: Float
+//   memlock: 8388608
+//
+// When several entries match, earlier ones take precedence.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ImageDefaults {
+    pub image: String,
+    // `Config` fields used for commands that leave them out
+    #[serde(default)]
+    pub limits: Mapping,
+    #[serde(default)]
+    pub cpu_ulimit: Option<bool>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub seccomp_profile: Option<String>,
+    #[serde(default)]
+    pub entrypoint: Option<String>,
+    #[serde(default)]
+    pub home: Option<String>,
+    // Share of `SANDBOX_MAX_CONCURRENT_RUNS` each container takes, 1 when unset
+    #[serde(default)]
+    pub weight: Option<usize>,
+    // Comparator for commands that don't choose one, e.g. `Float` where the language
+    // prints floats differently from the reference solution
+    #[serde(default)]
+    pub comparator: Option<Comparator>,
+    // `--ulimit memlock` in bytes, and the most a submission for the image may ask for
+    #[serde(default)]
+    pub memlock: Option<u64>,
+}
+
+pub fn parse_image_manifest(contents: &str) -> Result<Vec<ImageDefaults>, String> {
+    let entries: Vec<ImageDefaults> =
+        serde_yaml::from_str(contents).map_err(|e| format!("Invalid image manifest: {}", e))?;
+    let fields = strict::struct_fields::<Config>();
+    for entry in &entries {
+        for key in entry.limits.keys() {
+            if !key.as_str().is_some_and(|key| fields.contains(&key)) {
+                return Err(format!(
+                    "Invalid image manifest: unknown limit {:?} for {}",
+                    key, entry.image
+                ));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub fn load_image_manifest(path: &str) -> Result<Vec<ImageDefaults>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read image manifest {}: {}", path, e))?;
+    parse_image_manifest(&contents)
+}
+
+// Image patterns from the file named by `SANDBOX_ALLOWED_IMAGES_FILE`, one per line, with
+// blank lines and `#` comments skipped
+pub fn parse_allowed_images(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn load_allowed_images(path: &str) -> Result<Vec<String>, String> {
+    fs::read_to_string(path)
+        .map(|contents| parse_allowed_images(&contents))
+        .map_err(|e| format!("Failed to read image allowlist {}: {}", path, e))
+}
+
+// Fill the limits each command of a submission leaves out from the entries matching its
+// image. Limits given by the submission always win.
+pub fn merge_limits(submission: &mut Value, entries: &[&ImageDefaults]) {
+    let Some(commands) = submission
+        .get_mut("commands")
+        .and_then(Value::as_sequence_mut)
+    else {
+        return;
+    };
+    for command in commands {
+        let Some(command) = command.as_mapping_mut() else {
+            continue;
+        };
+        let config = command
+            .entry(Value::from("config"))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+        let Some(config) = config.as_mapping_mut() else {
+            continue;
+        };
+        for entry in entries {
+            for (key, value) in &entry.limits {
+                if !config.contains_key(key) {
+                    config.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod images_test {
+    use super::*;
+
+    #[test]
+    fn manifest_parsing() {
+        let entries = parse_image_manifest(
+            r#"
+- image: "gcc:*"
+  limits: {time_limit: 2, memory_limit: 512000}
+  capabilities: [SYS_PTRACE]
+  seccomp_profile: /etc/supercode/gcc.json
+- image: openjdk:21
+  cpu_ulimit: true
+  entrypoint: ""
+"#,
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].image, "gcc:*");
+        assert_eq!(entries[0].limits.len(), 2);
+        assert_eq!(entries[0].capabilities, ["SYS_PTRACE"]);
+        assert_eq!(entries[1].cpu_ulimit, Some(true));
+        assert_eq!(entries[1].entrypoint.as_deref(), Some(""));
+
+        assert!(parse_image_manifest("- image: gcc\n  limits: {memory_limt: 1}\n").is_err());
+        assert!(parse_image_manifest("- image: gcc\n  seccomp: x\n").is_err());
+    }
+
+    #[test]
+    fn allowlist_parsing() {
+        let allowed = parse_allowed_images("# compilers\ngcc:*\n\n  openjdk:21  \n");
+        assert_eq!(allowed, ["gcc:*", "openjdk:21"]);
+        assert!(load_allowed_images("/nonexistent/allowlist").is_err());
+    }
+
+    #[test]
+    fn submission_limits_take_precedence() {
+        let entries = parse_image_manifest(
+            r#"
+- image: gcc:14.2
+  limits: {time_limit: 3}
+- image: "gcc:*"
+  limits: {time_limit: 2, memory_limit: 512000, memory_reserved: 4096000}
+"#,
+        )
+        .unwrap();
+        let mut submission: Value = serde_yaml::from_str(
+            r#"
+image: gcc:14.2
+commands:
+  - command: ./main
+    config: {memory_limit: 65536}
+  - command: ./main
+"#,
+        )
+        .unwrap();
+        merge_limits(&mut submission, &entries.iter().collect::<Vec<_>>());
+        let config =
+            |index: usize, key: &str| submission["commands"][index]["config"][key].as_u64();
+        assert_eq!(config(0, "memory_limit"), Some(65536));
+        assert_eq!(config(0, "time_limit"), Some(3));
+        assert_eq!(config(0, "memory_reserved"), Some(4096000));
+        assert_eq!(config(1, "memory_limit"), Some(512000));
+        assert_eq!(config(1, "large_stack"), None);
+    }
+}
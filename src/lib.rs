@@ -0,0 +1,2 @@
+// Shared by the runner and the broker examples in `src/bin`
+pub mod connect;
@@ -1,98 +1,897 @@
+use breaker::CircuitBreaker;
+use coalesce::{Coalesced, Coalescer};
+use events::{EventSink, Stage, now_millis};
 use futures::StreamExt;
+use health::Health;
+use heartbeat::InFlight;
+use history::History;
+use offsets::OffsetTracker;
 use rabbitmq_stream_client::{
-    Environment,
-    error::StreamCreateError,
+    Consumer, Environment, NoDedup, Producer,
+    error::{ConsumerCreateError, StreamCreateError},
     types::{ByteCapacity, Message, OffsetSpecification, ResponseCode},
 };
-use service::{FormData, ResponseData, sandbox_service};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use retry::{RetryEnvelope, RetryPolicy, RetryQueue, remaining_delay};
+use service::{
+    FormData, Progress, ResponseData, SandboxConfig, acquire_run, admit, apply_image_defaults,
+    decode_message, parse_form_data, queue_wait_ms, read_form_data, sandbox_service_with_progress,
+    validate_form_data, verify_executor, verify_runtime, verify_template,
+};
+use signing::{SIGNATURE_PROPERTY, Signer};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use supercode_rust::connect;
+use throttle::Throttle;
+use tokio::runtime::Handle;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
 
+mod breaker;
+mod coalesce;
+mod events;
+mod health;
+mod heartbeat;
+mod history;
+mod images;
+mod offsets;
+mod pipeline;
+mod resources;
+mod retry;
+mod seccomp;
 mod service;
+mod signing;
+mod storage;
+mod strict;
+mod telemetry;
+mod throttle;
+mod verdict;
+mod workspace;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Build Consumer
-    let environment = Environment::builder().build().await?;
-    let receive_stream = "Server2Runner";
+async fn create_stream(environment: &Environment, stream: &str) {
     let create_response = environment
         .stream_creator()
         .max_length(ByteCapacity::GB(1))
-        .create(receive_stream)
+        .create(stream)
         .await;
-    if let Err(e) = create_response {
-        if let StreamCreateError::Create { stream, status } = e {
-            match status {
-                ResponseCode::StreamAlreadyExists => {}
-                err => {
-                    println!("Error creating stream: {:?} {:?}", stream, err);
-                }
+    if let Err(StreamCreateError::Create { stream, status }) = create_response {
+        match status {
+            ResponseCode::StreamAlreadyExists => {}
+            err => {
+                error!(stream, code = ?err, "creating stream failed");
             }
         }
     }
-    let mut consumer = environment
+}
+
+// Consume submissions on `stream` as `name`, which the consumer's offset is stored under
+async fn subscribe(
+    environment: &Environment,
+    stream: &str,
+    name: &str,
+    start: OffsetSpecification,
+) -> Result<Consumer, ConsumerCreateError> {
+    environment
         .consumer()
-        .offset(OffsetSpecification::Next)
-        .build(receive_stream)
+        .name(name)
+        .offset(start)
+        .build(stream)
         .await
-        .unwrap();
-    // Build Producer
-    let send_stream = "Runner2Server";
-    let create_response = environment
-        .stream_creator()
-        .max_length(ByteCapacity::GB(1))
-        .create(send_stream)
-        .await;
-    if let Err(e) = create_response {
-        if let StreamCreateError::Create { stream, status } = e {
-            match status {
-                ResponseCode::StreamAlreadyExists => {}
-                err => {
-                    println!("Error creating stream: {:?} {:?}", stream, err);
+}
+
+// Where the consumer named `name` left off on `stream`, or `bootstrap` when it never
+// stored an offset
+async fn stored_start(
+    environment: &Environment,
+    stream: &str,
+    name: &str,
+    bootstrap: OffsetSpecification,
+) -> Result<OffsetSpecification, ConsumerCreateError> {
+    // Stored offsets are only queried through a consumer
+    let probe = subscribe(environment, stream, name, OffsetSpecification::Next).await?;
+    let stored = probe.query_offset().await;
+    if let Err(e) = probe.handle().close().await {
+        warn!(error = ?e, "closing offset query consumer failed");
+    }
+    match stored {
+        Ok(offset) => {
+            info!(offset, "resuming after stored offset");
+            Ok(OffsetSpecification::Offset(offset + 1))
+        }
+        Err(e) => {
+            info!(start = ?bootstrap, reason = %e, "no stored offset");
+            Ok(bootstrap)
+        }
+    }
+}
+
+// Mark the delivery at `offset` handled, storing the consumer's offset once everything
+// before it was
+async fn commit_offset(consumer: &Consumer, tracker: &mut OffsetTracker, offset: u64) {
+    if let Some(done) = tracker.finished(offset)
+        && let Err(e) = consumer.store_offset(done).await
+    {
+        warn!(offset = done, error = ?e, "storing consumer offset failed");
+    }
+}
+
+// The offset of a finished submission job, logging it when it panicked. Either way the
+// delivery counts as handled, so one that panics isn't redelivered on every restart.
+fn finished_job(
+    job_offsets: &mut HashMap<tokio::task::Id, u64>,
+    job: Result<(tokio::task::Id, ()), tokio::task::JoinError>,
+) -> u64 {
+    let id = match job {
+        Ok((id, ())) => id,
+        Err(e) => {
+            error!(error = ?e, "running submission failed");
+            e.id()
+        }
+    };
+    job_offsets.remove(&id).expect("every job has an offset")
+}
+
+// How a submission reached the runner, for the queueing its response reports
+#[derive(Clone, Copy)]
+struct Arrival {
+    // Epoch millis
+    received_at: u64,
+    // Whether every job slot was taken when it came up
+    waited_for_slot: bool,
+}
+
+// Runs a submission that arrived as `arrival`, handing incremental updates to `on_update`
+// when it asked for them. The outcome is kept in `history` when given.
+fn process_submission(
+    config: &SandboxConfig,
+    events: &EventSink,
+    form_data: &FormData,
+    arrival: Arrival,
+    history: Option<&History>,
+    on_update: &mut dyn FnMut(ResponseData),
+) -> Option<ResponseData> {
+    let response = run_submission(config, events, form_data, arrival, on_update);
+    telemetry::job_processed(response.is_none());
+    if let Some(history) = history {
+        history.record(form_data, response.as_ref());
+    }
+    response
+}
+
+fn run_submission(
+    config: &SandboxConfig,
+    events: &EventSink,
+    form_data: &FormData,
+    arrival: Arrival,
+    on_update: &mut dyn FnMut(ResponseData),
+) -> Option<ResponseData> {
+    let queued = events.enqueue(&form_data.submit_id);
+    let permit = acquire_run(config, form_data);
+    drop(queued);
+    let wait = queue_wait_ms(form_data, arrival.received_at, now_millis());
+    info!(queue_wait_ms = wait, "submission started");
+    events.emit(&form_data.submit_id, Stage::Started);
+    let mut on_progress = |progress: Progress| match progress {
+        Progress::Phase(phase) => events.emit(&form_data.submit_id, Stage::of(phase)),
+        Progress::Result(command, result) => {
+            on_update(ResponseData::update(form_data, command, result.clone()))
+        }
+    };
+    match sandbox_service_with_progress(config, form_data, &mut on_progress) {
+        Ok(run) => {
+            events.emit(&form_data.submit_id, Stage::Done);
+            let mut response = ResponseData::new(config, form_data, run.results);
+            response.wall_time_ms = run.wall_time_ms;
+            response.queue_wait_ms = Some(wait);
+            response.hit_concurrency_limit = arrival.waited_for_slot || permit.waited;
+            Some(response)
+        }
+        Err(e) => {
+            error!(retryable = e.is_retryable(), error = %e, "submission failed");
+            events.emit(&form_data.submit_id, Stage::Failed);
+            None
+        }
+    }
+}
+
+// Handle for publishing results, signing each one when a signing secret is configured.
+// Results are queued to a dedicated task that owns the producer, along with the span of
+// the submission they belong to.
+#[derive(Clone)]
+struct ResultPublisher {
+    sender: mpsc::UnboundedSender<(Span, Message)>,
+    signer: Option<Signer>,
+}
+
+impl ResultPublisher {
+    // Start the task publishing to `producer`. It sends queued results in order, waiting
+    // for each to be confirmed, and closes the producer once every handle is dropped and
+    // the queue is drained.
+    fn spawn(
+        producer: Producer<NoDedup>,
+        signer: Option<Signer>,
+        throttle: Option<Throttle>,
+    ) -> (Self, JoinHandle<()>) {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(Span, Message)>();
+        let task = tokio::spawn(async move {
+            while let Some((span, message)) = receiver.recv().await {
+                async {
+                    if let Some(throttle) = &throttle {
+                        throttle.acquire().await;
+                    }
+                    match producer.send_with_confirm(message).await {
+                        Ok(_) => info!("result sent"),
+                        Err(e) => error!(error = ?e, "publishing result failed"),
+                    }
                 }
+                .instrument(span)
+                .await;
+            }
+            if let Err(e) = producer.close().await {
+                error!(error = ?e, "closing result producer failed");
             }
+        });
+        (ResultPublisher { sender, signer }, task)
+    }
+
+    fn publish(&self, result: &ResponseData) {
+        let body = result.encode();
+        let mut builder = Message::builder();
+        if let Some(signer) = &self.signer {
+            builder = builder
+                .application_properties()
+                .insert(SIGNATURE_PROPERTY, signer.sign(body.as_bytes()))
+                .message_builder();
+        }
+        let message = builder.body(body).build();
+        if self.sender.send((Span::current(), message)).is_err() {
+            error!("publishing result failed: publisher stopped");
         }
     }
-    let producer = Arc::new(Mutex::new(environment.producer().build(send_stream).await?));
-    while let Some(delivery) = consumer.next().await {
-        let d = delivery.unwrap();
-        let message = d
-            .message()
-            .data()
-            .map(|data| String::from_utf8(data.to_vec()))
-            .unwrap()
-            .unwrap();
-        print!("{}", message);
-        let form_data: FormData = serde_yaml::from_str(&message).unwrap();
-        let commands = form_data.commands.clone();
-        let image = form_data.image;
-        let result = match sandbox_service(commands, image) {
-            Ok(res) => res,
-            Err(_) => {
+}
+
+// Resolves on the first SIGTERM or SIGINT
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+// What handling a submission needs, cloned into every job handling one
+#[derive(Clone)]
+struct Runner {
+    config: Arc<SandboxConfig>,
+    events: EventSink,
+    publisher: ResultPublisher,
+    history: Option<Arc<History>>,
+    in_flight: InFlight,
+    coalescer: Option<Arc<Coalescer>>,
+    // Redelivered submissions are skipped when deduplication is enabled
+    seen: Option<Arc<SeenSubmissions>>,
+    // New submissions are turned away while the host is under pressure
+    breaker: Option<Arc<CircuitBreaker>>,
+    // Failed submissions are only retried when a retry stream is configured
+    retry: Option<Arc<RetryQueue>>,
+    runtime: Handle,
+    // One permit per submission that may be handled at once
+    slots: Arc<Semaphore>,
+}
+
+// Whether a `submit_id` was handled recently
+enum Seen {
+    New,
+    // Its response, `None` while it is still running
+    Duplicate(Option<Arc<ResponseData>>),
+}
+
+// The `capacity` most recently handled `submit_id`s with their responses, so a submission
+// the broker redelivers isn't compiled and run again. Entries older than `window`, when
+// given, no longer count. This is best effort: the cache lives in memory, so after a
+// restart the runner doesn't know what it handled before, and an id evicted by newer
+// ones runs again. A larger capacity catches older redeliveries for more memory.
+struct SeenSubmissions {
+    capacity: usize,
+    window: Option<Duration>,
+    // Publish the cached response again for a duplicate, rather than staying silent
+    resend: bool,
+    recent: Mutex<SeenEntries>,
+}
+
+// Entries by `submit_id`, and the ids from least to most recently used
+type SeenEntries = (
+    HashMap<String, (Instant, Option<Arc<ResponseData>>)>,
+    VecDeque<String>,
+);
+
+impl SeenSubmissions {
+    fn new(capacity: usize, window: Option<Duration>, resend: bool) -> Self {
+        SeenSubmissions {
+            capacity: capacity.max(1),
+            window,
+            resend,
+            recent: Mutex::default(),
+        }
+    }
+
+    // Enabled by `DEDUP_CAPACITY`; `DEDUP_WINDOW_MS` limits how long ids are remembered and
+    // `DEDUP_RESEND` publishes the cached response for duplicates
+    fn from_env() -> Option<Self> {
+        let capacity = std::env::var("DEDUP_CAPACITY").ok()?.trim().parse().ok()?;
+        let window = std::env::var("DEDUP_WINDOW_MS")
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_millis);
+        let resend = std::env::var("DEDUP_RESEND")
+            .is_ok_and(|value| matches!(value.trim(), "1" | "true" | "yes" | "on"));
+        Some(SeenSubmissions::new(capacity, window, resend))
+    }
+
+    // Record that `submit_id` is being handled at `now`, unless it already was
+    fn begin(&self, submit_id: &str, now: Instant) -> Seen {
+        let mut guard = self.recent.lock().unwrap();
+        let (entries, order) = &mut *guard;
+        let fresh = |(seen, _): &(Instant, _)| {
+            self.window
+                .is_none_or(|window| now.duration_since(*seen) <= window)
+        };
+        let seen = match entries.get(submit_id) {
+            Some(entry) if fresh(entry) => Seen::Duplicate(entry.1.clone()),
+            _ => Seen::New,
+        };
+        order.retain(|id| id != submit_id);
+        order.push_back(submit_id.to_string());
+        if let Seen::New = seen {
+            entries.insert(submit_id.to_string(), (now, None));
+            while order.len() > self.capacity {
+                if let Some(evicted) = order.pop_front() {
+                    entries.remove(&evicted);
+                }
+            }
+        }
+        seen
+    }
+
+    // Keep the response of a submission that ran, or forget it when it failed so a
+    // redelivery runs it again
+    fn finish(&self, submit_id: &str, response: Option<Arc<ResponseData>>) {
+        let mut guard = self.recent.lock().unwrap();
+        let (entries, order) = &mut *guard;
+        match response {
+            Some(response) => {
+                if let Some(entry) = entries.get_mut(submit_id) {
+                    entry.1 = Some(response);
+                }
+            }
+            None => {
+                entries.remove(submit_id);
+                order.retain(|id| id != submit_id);
+            }
+        }
+    }
+}
+
+// `MAX_CONCURRENT_JOBS`, the number of CPUs by default
+fn max_concurrent_jobs() -> usize {
+    std::env::var("MAX_CONCURRENT_JOBS")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|&jobs| jobs > 0)
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from))
+}
+
+// One of the job slots, held while a submission is handled
+struct JobSlot {
+    _permit: OwnedSemaphorePermit,
+    // Whether all were taken when it was asked for
+    waited: bool,
+}
+
+// Wait for one of the `slots` to handle a submission in, reporting when all are taken
+async fn job_slot(slots: &Arc<Semaphore>, in_flight: &InFlight) -> JobSlot {
+    if let Ok(permit) = slots.clone().try_acquire_owned() {
+        return JobSlot {
+            _permit: permit,
+            waited: false,
+        };
+    }
+    info!(in_flight = in_flight.count(), "all job slots busy, waiting");
+    let permit = slots
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("job slots are never closed");
+    JobSlot {
+        _permit: permit,
+        waited: true,
+    }
+}
+
+// Submissions are handled on tokio's blocking pool, since running one blocks on docker
+// for as long as its commands take. The methods below block accordingly and must not be
+// called from async code.
+impl Runner {
+    fn run(&self, form_data: &FormData, arrival: Arrival) -> Option<ResponseData> {
+        let on_update = &mut |update: ResponseData| self.publisher.publish(&update);
+        let _running = self.in_flight.start();
+        let history = self.history.as_deref();
+        process_submission(
+            &self.config,
+            &self.events,
+            form_data,
+            arrival,
+            history,
+            on_update,
+        )
+    }
+
+    fn requeue(&self, form_data: FormData, attempt: u32) {
+        if let Some(queue) = &self.retry {
+            self.runtime.block_on(queue.requeue(form_data, attempt));
+        }
+    }
+
+    // Handle a submission that arrived as `arrival`, from parsing it to publishing its result.
+    // Runs in the delivery's span, which gets the `submit_id` once it is parsed.
+    fn handle_submission(&self, message: String, arrival: Arrival) {
+        debug!(message = %message, "message received");
+        let message = match decode_message(message)
+            .and_then(|message| apply_image_defaults(&self.config, message))
+        {
+            Ok(message) => message,
+            Err(e) => {
+                error!(error = %e, "parsing submission failed");
+                return;
+            }
+        };
+        let form_data = match parse_form_data(&self.config, &message) {
+            Ok(form_data) => form_data,
+            Err(e) => {
+                error!(error = %e, "parsing submission failed");
+                return;
+            }
+        };
+        Span::current().record("submit_id", form_data.submit_id.as_str());
+        info!(
+            image = form_data.image,
+            commands = form_data.commands.len(),
+            "message received"
+        );
+        // Every worker sees every submission, so one this pool can't satisfy is left for a
+        // pool that has the labels
+        if let Err(e) = admit(&self.config, &form_data) {
+            info!(reason = %e, "skipping submission");
+            return;
+        }
+        if let Some(Err(reason)) = self.breaker.as_ref().map(|breaker| breaker.check_host()) {
+            warn!(reason, "rejecting submission");
+            self.publisher
+                .publish(&ResponseData::rejected(&form_data, reason));
+            return;
+        }
+        if let Some(seen) = &self.seen
+            && let Seen::Duplicate(response) = seen.begin(&form_data.submit_id, Instant::now())
+        {
+            info!("skipping duplicate submission");
+            if let Some(response) = response.filter(|_| seen.resend) {
+                self.publisher.publish(&response);
+            }
+            return;
+        }
+        let run = || self.run(&form_data, arrival);
+        let outcome = match &self.coalescer {
+            Some(coalescer) => coalescer.run(&form_data.submit_id, &message, Instant::now(), run),
+            None => Coalesced::Ran(run().map(Arc::new)),
+        };
+        if let Some(seen) = &self.seen
+            && let Coalesced::Ran(response) | Coalesced::Shared(response) = &outcome
+        {
+            seen.finish(&form_data.submit_id, response.clone());
+        }
+        match outcome {
+            Coalesced::Ran(Some(result)) | Coalesced::Shared(Some(result)) => {
+                self.publisher.publish(&result)
+            }
+            Coalesced::Ran(None) => self.requeue(form_data, 0),
+            Coalesced::Shared(None) => {}
+        }
+    }
+
+    // Handle a submission from the retry stream, whose backoff the retry worker waited out
+    // before it arrived as `arrival`
+    fn handle_retry(&self, message: String, arrival: Arrival) {
+        let envelope: RetryEnvelope = match serde_yaml::from_str(&message) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                error!(error = %e, "parsing retried submission failed");
+                return;
+            }
+        };
+        Span::current().record("submit_id", envelope.form_data.submit_id.as_str());
+        info!(attempt = envelope.attempt, "retried message received");
+        if let Err(e) = admit(&self.config, &envelope.form_data) {
+            info!(reason = %e, "skipping retried submission");
+            return;
+        }
+        let arrival = Arrival {
+            received_at: arrival.received_at.max(envelope.not_before),
+            ..arrival
+        };
+        match self.run(&envelope.form_data, arrival) {
+            Some(result) => self.publisher.publish(&result),
+            None => self.requeue(envelope.form_data, envelope.attempt),
+        }
+    }
+}
+
+// Re-run submissions from the retry stream one at a time
+fn spawn_retry_worker(
+    mut consumer: Consumer,
+    runner: Runner,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let delivery = tokio::select! {
+                _ = shutdown.changed() => break,
+                delivery = consumer.next() => delivery,
+            };
+            let delivery = match delivery {
+                Some(Ok(delivery)) => delivery,
+                Some(Err(e)) => {
+                    error!(error = ?e, "receiving retried submission failed, skipping it");
+                    continue;
+                }
+                None => break,
+            };
+            let Some(Ok(message)) = delivery
+                .message()
+                .data()
+                .map(|data| String::from_utf8(data.to_vec()))
+            else {
                 continue;
+            };
+            // Backoff is deliberate, so a retry only takes a job slot once it is due. One that
+            // doesn't parse runs at once, for `handle_retry` to report.
+            let not_before = serde_yaml::from_str::<RetryEnvelope>(&message)
+                .map_or(0, |envelope| envelope.not_before);
+            tokio::time::sleep(remaining_delay(not_before)).await;
+            let waiting_since = now_millis();
+            let slot = job_slot(&runner.slots, &runner.in_flight).await;
+            let arrival = Arrival {
+                received_at: waiting_since,
+                waited_for_slot: slot.waited,
+            };
+            let runner = runner.clone();
+            let span = info_span!("retry", submit_id = field::Empty);
+            let job = tokio::task::spawn_blocking(move || {
+                span.in_scope(|| runner.handle_retry(message, arrival));
+                drop(slot);
+            });
+            if let Err(e) = job.await {
+                error!(error = ?e, "running retried submission failed");
             }
+        }
+        if let Err(e) = consumer.handle().close().await {
+            error!(error = ?e, "closing retry consumer failed");
+        }
+    })
+}
+
+// One-off mode: run the job in `input` without a broker and print its `ResponseData`
+fn run_local(
+    config: &SandboxConfig,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut message = String::new();
+    input.read_to_string(&mut message)?;
+    let message = apply_image_defaults(config, decode_message(message)?)?;
+    let form_data = parse_form_data(config, &message)?;
+    admit(config, &form_data)?;
+    let run = sandbox_service_with_progress(config, &form_data, &mut |_| {})?;
+    let mut result = ResponseData::new(config, &form_data, run.results);
+    result.wall_time_ms = run.wall_time_ms;
+    write!(output, "{}", result.encode())?;
+    Ok(())
+}
+
+// Dry-run mode: check the job in `input` and print `valid` or each problem found, one per
+// line. Returns whether the job is valid.
+fn validate_local(
+    config: &SandboxConfig,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut message = String::new();
+    input.read_to_string(&mut message)?;
+    let problems =
+        match decode_message(message).and_then(|message| apply_image_defaults(config, message)) {
+            Ok(message) => match read_form_data(config, &message) {
+                Ok(form_data) => validate_form_data(config, &form_data).err().map(|e| e.0),
+                Err(e) => Some(vec![e]),
+            },
+            Err(e) => Some(vec![e]),
         };
-        let result = ResponseData {
-            sandbox_results: result,
-            submit_id: form_data.submit_id,
+    match &problems {
+        Some(problems) => writeln!(output, "{}", problems.join("\n"))?,
+        None => writeln!(output, "valid")?,
+    }
+    Ok(problems.is_none())
+}
+
+// Logs go to stderr, filtered by `RUST_LOG` (`info` by default), as JSON lines when
+// `LOG_FORMAT=json`
+fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr);
+    if std::env::var("LOG_FORMAT").is_ok_and(|format| format.trim() == "json") {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init_tracing();
+    let mut config = SandboxConfig::from_env();
+    if let Ok(path) = std::env::var("SANDBOX_IMAGE_MANIFEST") {
+        config.image_manifest = images::load_image_manifest(&path)?;
+    }
+    if let Ok(path) = std::env::var("SANDBOX_ALLOWED_IMAGES_FILE") {
+        let allowed = images::load_allowed_images(&path)?;
+        config
+            .allowed_images
+            .get_or_insert_default()
+            .extend(allowed);
+    }
+    let args: Vec<String> = std::env::args().collect();
+    // `--validate` checks the job on stdin without running it, so it needs no executor
+    if args.get(1).map(String::as_str) == Some("--validate") {
+        if !validate_local(&config, io::stdin().lock(), io::stdout().lock())? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    // Fails fast when the runtime is missing rather than on the first submission
+    let version = verify_runtime(&config)?;
+    info!(runtime = %config.container_runtime, version = %version, "container runtime found");
+    verify_executor(&config)?;
+    verify_template(&config)?;
+    // `run [FILE]` executes a single job from FILE, or from stdin when FILE is omitted or `-`
+    if args.get(1).map(String::as_str) == Some("run") {
+        return match args.get(2).map(String::as_str) {
+            None | Some("-") => run_local(&config, io::stdin().lock(), io::stdout().lock()),
+            Some(path) => run_local(&config, File::open(path)?, io::stdout().lock()),
         };
-        let producer = producer.clone();
-        let message = Message::builder()
-            .body(serde_yaml::to_string(&result).unwrap_or_default())
-            .build();
+    }
+    let history = History::from_env()?.map(Arc::new);
+    // `history` prints the recorded submissions, oldest first
+    if args.get(1).map(String::as_str) == Some("history") {
+        let history = history.ok_or("SUBMISSION_HISTORY_DIR is not set")?;
+        for entry in history.read()? {
+            print!("---\n{}", entry);
+        }
+        return Ok(());
+    }
+    // Health endpoints and metrics are only served when a port is configured, from before
+    // the broker is connected so liveness can be probed while connecting
+    let health = Health::new(&config.sandbox_root);
+    if let Some(port) = health::port_from_env() {
+        let metrics = telemetry::install()?;
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+        let health = health.clone();
         tokio::spawn(async move {
-            producer
-                .lock()
-                .await
-                .send_with_confirm(message)
-                .await
-                .unwrap();
+            if let Err(e) = health::serve(listener, health, Some(metrics)).await {
+                error!(error = %e, "health endpoint failed");
+            }
+        });
+    }
+    // Build Consumer
+    let (attempts, delay) = connect::attempts_from_env();
+    let environment = connect::connect_with_retry(attempts, delay).await?;
+    let receive_stream = "Server2Runner";
+    create_stream(&environment, receive_stream).await;
+    // Offsets are stored as deliveries are handled, so a restart carries on where the last
+    // run left off
+    let consumer_name = offsets::consumer_name_from_env();
+    let bootstrap = offsets::bootstrap_from_env();
+    let start = stored_start(
+        &environment,
+        receive_stream,
+        &consumer_name,
+        bootstrap.clone(),
+    )
+    .await?;
+    let mut consumer = subscribe(&environment, receive_stream, &consumer_name, start).await?;
+    let mut offsets = OffsetTracker::default();
+    health.set_broker_connected(true);
+    // Build Producer
+    let send_stream = "Runner2Server";
+    create_stream(&environment, send_stream).await;
+    let (publisher, publishing) = ResultPublisher::spawn(
+        environment.producer().build(send_stream).await?,
+        Signer::from_env(),
+        Throttle::from_env(),
+    );
+    // Lifecycle events are only published when a stream is configured, with queue positions
+    // when `EVENTS_QUEUE_POSITION` is set
+    let events = match std::env::var("EVENTS_STREAM") {
+        Ok(events_stream) => {
+            create_stream(&environment, &events_stream).await;
+            let events = events::spawn_publisher(&environment, &events_stream).await?;
+            if std::env::var("EVENTS_QUEUE_POSITION")
+                .is_ok_and(|value| matches!(value.trim(), "1" | "true" | "yes" | "on"))
+            {
+                events.with_queue_positions()
+            } else {
+                events
+            }
+        }
+        Err(_) => EventSink::default(),
+    };
+    // Heartbeats are only published when a stream is configured
+    let in_flight = InFlight::default();
+    if let Ok(heartbeat_stream) = std::env::var("HEARTBEAT_STREAM") {
+        create_stream(&environment, &heartbeat_stream).await;
+        let interval = heartbeat::interval_from_env();
+        heartbeat::spawn_publisher(&environment, &heartbeat_stream, interval, in_flight.clone())
+            .await?;
+    }
+    // Failed submissions are only retried when a retry stream is configured
+    let retry = match std::env::var("RETRY_STREAM") {
+        Ok(retry_stream) => {
+            let dead_letter_stream = std::env::var("DEAD_LETTER_STREAM")
+                .unwrap_or_else(|_| format!("{}.dead-letter", retry_stream));
+            create_stream(&environment, &retry_stream).await;
+            create_stream(&environment, &dead_letter_stream).await;
+            let queue = RetryQueue::new(
+                &environment,
+                RetryPolicy::from_env(),
+                &retry_stream,
+                &dead_letter_stream,
+            )
+            .await?;
+            let retry_consumer = environment
+                .consumer()
+                .offset(OffsetSpecification::Next)
+                .build(&retry_stream)
+                .await?;
+            Some((Arc::new(queue), retry_consumer))
+        }
+        Err(_) => None,
+    };
+    let runner = Runner {
+        config: Arc::new(config),
+        events,
+        publisher,
+        history,
+        in_flight,
+        coalescer: Coalescer::from_env().map(Arc::new),
+        seen: SeenSubmissions::from_env().map(Arc::new),
+        breaker: CircuitBreaker::from_env().map(Arc::new),
+        retry: retry.as_ref().map(|(queue, _)| queue.clone()),
+        runtime: Handle::current(),
+        slots: Arc::new(Semaphore::new(max_concurrent_jobs())),
+    };
+    let (stop, shutdown) = watch::channel(false);
+    let retry_worker = retry
+        .map(|(_, retry_consumer)| spawn_retry_worker(retry_consumer, runner.clone(), shutdown));
+    // Submissions being handled, each as a job on the blocking pool, with their offsets
+    let mut jobs = JoinSet::new();
+    let mut job_offsets = HashMap::new();
+    // A signal stops new deliveries; submissions already started run to completion
+    let signalled = shutdown_signal();
+    tokio::pin!(signalled);
+    loop {
+        // Deliveries are only taken once there is a slot to handle them in
+        let waiting_since = now_millis();
+        let slot = tokio::select! {
+            _ = &mut signalled => {
+                info!("shutting down, no longer accepting submissions");
+                break;
+            }
+            slot = job_slot(&runner.slots, &runner.in_flight) => slot,
+        };
+        let delivery = tokio::select! {
+            _ = &mut signalled => {
+                info!("shutting down, no longer accepting submissions");
+                break;
+            }
+            Some(job) = jobs.join_next_with_id() => {
+                let offset = finished_job(&mut job_offsets, job);
+                commit_offset(&consumer, &mut offsets, offset).await;
+                continue;
+            }
+            delivery = consumer.next() => delivery,
+        };
+        // The stream only ends when the broker dropped the subscription
+        let Some(delivery) = delivery else {
+            warn!("submission stream closed, resubscribing");
+            health.set_broker_connected(false);
+            let start = match offsets.next() {
+                Some(next) => OffsetSpecification::Offset(next),
+                None => {
+                    stored_start(
+                        &environment,
+                        receive_stream,
+                        &consumer_name,
+                        bootstrap.clone(),
+                    )
+                    .await?
+                }
+            };
+            consumer = subscribe(&environment, receive_stream, &consumer_name, start).await?;
+            health.set_broker_connected(true);
+            telemetry::broker_reconnected();
+            continue;
+        };
+        let d = match delivery {
+            Ok(d) => d,
+            Err(e) => {
+                error!(error = ?e, "receiving submission failed, skipping it");
+                continue;
+            }
+        };
+        // Deliveries queue up on the broker while every slot is taken, so one taken up after
+        // waiting for a slot counts as received when the wait began
+        let arrival = Arrival {
+            received_at: if slot.waited {
+                waiting_since
+            } else {
+                now_millis()
+            },
+            waited_for_slot: slot.waited,
+        };
+        let offset = d.offset();
+        offsets.delivered(offset);
+        // Everything logged about the delivery, up to its result being sent
+        let span = info_span!("submission", offset, submit_id = field::Empty);
+        let Some(Ok(message)) = d
+            .message()
+            .data()
+            .map(|data| String::from_utf8(data.to_vec()))
+        else {
+            span.in_scope(|| error!("parsing submission failed: empty or not UTF-8"));
+            commit_offset(&consumer, &mut offsets, offset).await;
+            continue;
+        };
+        let runner = runner.clone();
+        let job = jobs.spawn_blocking(move || {
+            span.in_scope(|| runner.handle_submission(message, arrival));
+            drop(slot);
         });
+        job_offsets.insert(job.id(), offset);
+    }
+    // Submissions already started still run to completion and publish their results
+    while let Some(job) = jobs.join_next_with_id().await {
+        let offset = finished_job(&mut job_offsets, job);
+        commit_offset(&consumer, &mut offsets, offset).await;
     }
+    let _ = stop.send(true);
+    if let Some(worker) = retry_worker {
+        let _ = worker.await;
+    }
+    // With the last handle gone, the publishing task sends what is queued and stops
+    drop(runner);
+    let _ = publishing.await;
+    health.set_broker_connected(false);
+    consumer.handle().close().await?;
     Ok(())
 }
 
 #[cfg(test)]
+// The broker tests match stream creation errors step by step, as in the client's examples
+#[allow(clippy::collapsible_if, clippy::collapsible_match)]
 mod main_test {
     use rabbitmq_stream_client::{
         Environment,
@@ -100,7 +899,79 @@ mod main_test {
         types::{ByteCapacity, Message, ResponseCode},
     };
 
-    use crate::service::{CMD, Config, FormData};
+    use tokio::sync::mpsc;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::Semaphore;
+
+    use crate::events::{EventSink, Stage, now_millis};
+    use crate::heartbeat::InFlight;
+    use crate::service::{CMD, Config, FormData, SandboxConfig};
+    use crate::{
+        Arrival, Seen, SeenSubmissions, job_slot, process_submission, run_local, validate_local,
+    };
+
+    #[tokio::test]
+    async fn jobs_wait_for_slot() {
+        let slots = Arc::new(Semaphore::new(2));
+        let in_flight = InFlight::default();
+        let first = job_slot(&slots, &in_flight).await;
+        let _second = job_slot(&slots, &in_flight).await;
+        assert!(!first.waited);
+        let third = tokio::spawn({
+            let slots = slots.clone();
+            async move { job_slot(&slots, &InFlight::default()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!third.is_finished());
+        drop(first);
+        let third = tokio::time::timeout(Duration::from_secs(1), third).await;
+        let third = third.unwrap().unwrap();
+        assert!(third.waited);
+        assert_eq!(slots.available_permits(), 0);
+    }
+
+    #[test]
+    fn duplicates_skipped() {
+        use std::time::Instant;
+
+        use crate::service::ResponseData;
+
+        let seen = SeenSubmissions::new(2, Some(Duration::from_secs(60)), true);
+        let start = Instant::now();
+        let form_data = FormData {
+            submit_id: "a".to_string(),
+            ..Default::default()
+        };
+        let response = Arc::new(ResponseData::new(
+            &SandboxConfig::default(),
+            &form_data,
+            vec![],
+        ));
+        assert!(matches!(seen.begin("a", start), Seen::New));
+        // Redelivered while still running
+        assert!(matches!(seen.begin("a", start), Seen::Duplicate(None)));
+        seen.finish("a", Some(response));
+        let Seen::Duplicate(Some(cached)) = seen.begin("a", start) else {
+            panic!("response not cached");
+        };
+        assert_eq!(cached.submit_id, "a");
+
+        // Least recently used ids are evicted first
+        assert!(matches!(seen.begin("b", start), Seen::New));
+        assert!(matches!(seen.begin("a", start), Seen::Duplicate(_)));
+        assert!(matches!(seen.begin("c", start), Seen::New));
+        assert!(matches!(seen.begin("b", start), Seen::New));
+        assert!(matches!(seen.begin("a", start), Seen::New));
+
+        // Failed runs are forgotten, and entries expire after the window
+        seen.finish("a", None);
+        assert!(matches!(seen.begin("a", start), Seen::New));
+        let later = start + Duration::from_secs(61);
+        assert!(matches!(seen.begin("a", later), Seen::New));
+    }
 
     #[tokio::test]
     async fn gcc_version() -> Result<(), Box<dyn std::error::Error>> {
@@ -138,12 +1009,15 @@ mod main_test {
                 large_stack: false,
                 output_limit: 0,
                 process_limit: 0,
+                ..Default::default()
             },
+            ..Default::default()
         }];
         let form_data = FormData {
             commands,
             image: "gcc:14.2",
             submit_id: "......".to_string(),
+            ..Default::default()
         };
 
         producer
@@ -204,7 +1078,9 @@ int main() {
                     large_stack: false,
                     output_limit: 0,
                     process_limit: 0,
+                    ..Default::default()
                 },
+                ..Default::default()
             },
             CMD {
                 command: "gcc".to_string(),
@@ -218,7 +1094,9 @@ int main() {
                     large_stack: false,
                     output_limit: 0,
                     process_limit: 0,
+                    ..Default::default()
                 },
+                ..Default::default()
             },
             CMD {
                 command: "./main".to_string(),
@@ -232,13 +1110,16 @@ int main() {
                     large_stack: false,
                     output_limit: 0,
                     process_limit: 0,
+                    ..Default::default()
                 },
+                ..Default::default()
             },
         ];
         let form_data = FormData {
             commands,
             image: "gcc:14.2",
             submit_id: "......".to_string(),
+            ..Default::default()
         };
 
         print!("{}", serde_yaml::to_string(&form_data).unwrap());
@@ -302,7 +1183,9 @@ int main() {
                     large_stack: false,
                     output_limit: 0,
                     process_limit: 0,
+                    ..Default::default()
                 },
+                ..Default::default()
             },
             CMD {
                 command: "g++".to_string(),
@@ -316,7 +1199,9 @@ int main() {
                     large_stack: false,
                     output_limit: 0,
                     process_limit: 0,
+                    ..Default::default()
                 },
+                ..Default::default()
             },
             CMD {
                 command: "./main".to_string(),
@@ -330,13 +1215,16 @@ int main() {
                     large_stack: false,
                     output_limit: 0,
                     process_limit: 0,
+                    ..Default::default()
                 },
+                ..Default::default()
             },
         ];
         let form_data = FormData {
             commands,
             image: "gcc:14.2",
             submit_id: "......".to_string(),
+            ..Default::default()
         };
 
         print!("{}", serde_yaml::to_string(&form_data).unwrap());
@@ -352,4 +1240,162 @@ int main() {
         producer.close().await?;
         Ok(())
     }
+
+    #[test]
+    fn lifecycle_events() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let events = EventSink::new(sender);
+        let form_data = FormData {
+            commands: vec![
+                CMD {
+                    command: "gcc".to_string(),
+                    args: vec!["--version".to_string()],
+                    ..Default::default()
+                },
+                CMD {
+                    command: "echo".to_string(),
+                    args: vec!["ran".to_string()],
+                    ..Default::default()
+                },
+            ],
+            image: "gcc:14.2",
+            submit_id: "lifecycle".to_string(),
+            // One stage per command, so the runner sees each phase start
+            incremental: true,
+            ..Default::default()
+        };
+        let response = process_submission(
+            &SandboxConfig::default(),
+            &events,
+            &form_data,
+            Arrival {
+                received_at: now_millis(),
+                waited_for_slot: false,
+            },
+            None,
+            &mut |_| {},
+        );
+        assert!(response.is_some());
+        let mut stages = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            assert_eq!(event.submit_id, "lifecycle");
+            stages.push(event.stage);
+        }
+        assert_eq!(
+            stages,
+            [
+                Stage::Queued,
+                Stage::Started,
+                Stage::Compiling,
+                Stage::Running,
+                Stage::Done
+            ]
+        );
+    }
+
+    #[test]
+    fn incremental_updates() {
+        let cmd = |n: usize| CMD {
+            command: "echo".to_string(),
+            args: vec![n.to_string()],
+            ..Default::default()
+        };
+        let form_data = FormData {
+            commands: (0..3).map(cmd).collect(),
+            image: "gcc:14.2",
+            submit_id: "incremental".to_string(),
+            incremental: true,
+            ..Default::default()
+        };
+        let mut updates = vec![];
+        let response = process_submission(
+            &SandboxConfig::default(),
+            &EventSink::default(),
+            &form_data,
+            Arrival {
+                received_at: now_millis(),
+                waited_for_slot: false,
+            },
+            None,
+            &mut |update| updates.push(update),
+        );
+        assert_eq!(updates.len(), 3);
+        for (index, update) in updates.iter().enumerate() {
+            assert_eq!(update.submit_id, "incremental");
+            assert_eq!(update.update, Some(index));
+            assert_eq!(update.sandbox_results.len(), 1);
+        }
+        // The full response is the final marker
+        let response = response.unwrap();
+        assert_eq!(response.update, None);
+        assert_eq!(response.sandbox_results.len(), 3);
+    }
+
+    #[test]
+    fn validate_from_stdin() {
+        let job = r#"
+commands:
+- command: gcc
+  args: [--version]
+  input: ''
+  config:
+    time_limit: 1
+    time_reserved: 1
+    memory_limit: 256000
+    memory_reserved: 4096000
+    large_stack: false
+    output_limit: 0
+    process_limit: 0
+image: gcc:14.2
+submit_id: local
+"#;
+        let config = SandboxConfig {
+            max_time_limit: Some(1),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        assert!(validate_local(&config, job.as_bytes(), &mut output).unwrap());
+        assert_eq!(output, b"valid\n");
+
+        let mut output = Vec::new();
+        let slow = job.replace("time_limit: 1", "time_limit: 3");
+        assert!(!validate_local(&config, slow.as_bytes(), &mut output).unwrap());
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Command 0 (gcc): time_limit is 3, at most 1 is allowed\n"
+        );
+        let mut output = Vec::new();
+        assert!(!validate_local(&config, &b"commands: 1"[..], &mut output).unwrap());
+        assert!(
+            String::from_utf8(output)
+                .unwrap()
+                .starts_with("Invalid submission")
+        );
+    }
+
+    #[test]
+    fn run_local_from_stdin() {
+        let job = r#"
+commands:
+- command: gcc
+  args:
+  - --version
+  input: ''
+  config:
+    time_limit: 1
+    time_reserved: 1
+    memory_limit: 256000
+    memory_reserved: 4096000
+    large_stack: false
+    output_limit: 0
+    process_limit: 0
+image: gcc:14.2
+submit_id: local
+"#;
+        let mut output = Vec::new();
+        run_local(&SandboxConfig::default(), job.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("submit_id: local"));
+        assert!(output.contains("gcc (GCC) 14.2.0"));
+    }
 }
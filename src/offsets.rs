@@ -0,0 +1,80 @@
+use std::collections::BTreeSet;
+
+use rabbitmq_stream_client::types::OffsetSpecification;
+
+// Where a consumer that never stored an offset starts: `OFFSET_BOOTSTRAP=first` takes every
+// submission still in the stream, anything else only those sent from now on
+pub fn bootstrap_from_env() -> OffsetSpecification {
+    match std::env::var("OFFSET_BOOTSTRAP") {
+        Ok(value) if value.trim().eq_ignore_ascii_case("first") => OffsetSpecification::First,
+        _ => OffsetSpecification::Next,
+    }
+}
+
+// Name the broker stores the consumer's offset under, `CONSUMER_NAME` or `supercode-runner`.
+// Runners sharing a name share their position in the stream.
+pub fn consumer_name_from_env() -> String {
+    std::env::var("CONSUMER_NAME").unwrap_or_else(|_| "supercode-runner".to_string())
+}
+
+// Deliveries still being handled. Submissions finish out of order, so the offset stored is
+// the last one before which everything finished, and a restart redelivers whatever was cut
+// short instead of skipping it.
+#[derive(Default)]
+pub struct OffsetTracker {
+    pending: BTreeSet<u64>,
+    // First and last offsets delivered since subscribing
+    first: Option<u64>,
+    last: Option<u64>,
+    stored: Option<u64>,
+}
+
+impl OffsetTracker {
+    pub fn delivered(&mut self, offset: u64) {
+        self.pending.insert(offset);
+        self.first.get_or_insert(offset);
+        self.last = Some(self.last.map_or(offset, |last| last.max(offset)));
+    }
+
+    // Mark a delivery handled, returning the offset to store when it moved forward
+    pub fn finished(&mut self, offset: u64) -> Option<u64> {
+        self.pending.remove(&offset);
+        let done = match self.pending.first() {
+            Some(&pending) if Some(pending) > self.first => pending - 1,
+            Some(_) => return None,
+            None => self.last?,
+        };
+        if self.stored.is_some_and(|stored| stored >= done) {
+            return None;
+        }
+        self.stored = Some(done);
+        Some(done)
+    }
+
+    // Where to carry on after resubscribing, past everything already delivered
+    pub fn next(&self) -> Option<u64> {
+        self.last.map(|last| last + 1)
+    }
+}
+
+#[cfg(test)]
+mod offsets_test {
+    use super::*;
+
+    #[test]
+    fn stored_behind_unfinished_deliveries() {
+        let mut tracker = OffsetTracker::default();
+        assert_eq!(tracker.next(), None);
+        for offset in 10..14 {
+            tracker.delivered(offset);
+        }
+        // Nothing before the first delivery is known to be handled
+        assert_eq!(tracker.finished(11), None);
+        assert_eq!(tracker.finished(13), None);
+        assert_eq!(tracker.finished(10), Some(11));
+        tracker.delivered(14);
+        assert_eq!(tracker.finished(14), None);
+        assert_eq!(tracker.finished(12), Some(14));
+        assert_eq!(tracker.next(), Some(15));
+    }
+}
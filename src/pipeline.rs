@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+
+use crate::service::{Config, FormData, Phase, SandboxConfig, docker_flags};
+
+// Sanitized record of what ran for a submission, for audit and reproducibility.
+// Arguments, inputs, expected outputs and env file contents are left out since they
+// carry source code and secrets.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PipelineManifest {
+    pub image: String,
+    pub platform: Option<String>,
+    // Container runtime the pipeline ran on, by program name
+    pub runtime: String,
+    // `docker run` flags, without mounts and paths
+    pub flags: Vec<String>,
+    pub normalize_line_endings: bool,
+    pub parallel: bool,
+    pub commands: Vec<CommandManifest>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CommandManifest {
+    // Program name only, e.g. `gcc` for `/usr/bin/gcc`
+    pub command: String,
+    pub arg_count: usize,
+    pub phase: Phase,
+    pub config: Config,
+    pub has_input: bool,
+    pub parallel_group: Option<String>,
+}
+
+impl PipelineManifest {
+    pub fn new(config: &SandboxConfig, form_data: &FormData) -> Self {
+        PipelineManifest {
+            image: form_data.image.to_string(),
+            platform: form_data.platform.clone().or(config.platform.clone()),
+            runtime: config
+                .container_runtime
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            flags: docker_flags(config, form_data).unwrap_or_default(),
+            normalize_line_endings: form_data.normalize_line_endings,
+            parallel: form_data.parallel,
+            commands: form_data
+                .commands
+                .iter()
+                .map(|cmd| CommandManifest {
+                    command: cmd
+                        .command
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                    arg_count: cmd.args.len(),
+                    phase: cmd.phase(),
+                    config: cmd.config.clone(),
+                    has_input: !cmd.input.is_empty() || cmd.input_ref.is_some(),
+                    parallel_group: cmd.parallel_group.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod pipeline_test {
+    use super::*;
+    use crate::service::CMD;
+
+    #[test]
+    fn manifest_reflects_submission() {
+        let config = SandboxConfig {
+            drop_capabilities: true,
+            seccomp_profile: Some("profile.json".to_string()),
+            ..Default::default()
+        };
+        let form_data = FormData {
+            commands: vec![
+                CMD {
+                    command: "/usr/bin/gcc".to_string(),
+                    args: vec!["main.c".to_string(), "-o".to_string(), "main".to_string()],
+                    ..Default::default()
+                },
+                CMD {
+                    command: "bash".to_string(),
+                    args: vec!["-c".to_string(), "echo $SECRET_SOURCE".to_string()],
+                    input: "1 2\n".to_string(),
+                    config: Config {
+                        time_limit: 2,
+                        memory_limit: 128000,
+                        ..Default::default()
+                    },
+                    parallel_group: Some("tests".to_string()),
+                    ..Default::default()
+                },
+            ],
+            image: "gcc:14.2",
+            platform: Some("linux/amd64".to_string()),
+            env_file: Some("TOKEN=hunter2\n".to_string()),
+            ..Default::default()
+        };
+        let manifest = PipelineManifest::new(&config, &form_data);
+        assert_eq!(manifest.image, "gcc:14.2");
+        assert_eq!(manifest.platform.as_deref(), Some("linux/amd64"));
+        assert_eq!(manifest.runtime, "docker");
+        assert_eq!(
+            manifest.flags,
+            [
+                "--rm",
+                "--init",
+                "--log-driver",
+                "none",
+                "--platform",
+                "linux/amd64",
+                "--cap-drop",
+                "ALL",
+                "--env-file",
+                "--security-opt",
+                "seccomp=profile.json"
+            ]
+        );
+        assert_eq!(manifest.commands.len(), 2);
+        assert_eq!(manifest.commands[0].command, "gcc");
+        assert_eq!(manifest.commands[0].arg_count, 3);
+        assert_eq!(manifest.commands[0].phase, Phase::Build);
+        assert!(!manifest.commands[0].has_input);
+        assert_eq!(manifest.commands[1].command, "bash");
+        assert_eq!(manifest.commands[1].phase, Phase::Run);
+        assert_eq!(manifest.commands[1].config.time_limit, 2);
+        assert_eq!(manifest.commands[1].config.memory_limit, 128000);
+        assert!(manifest.commands[1].has_input);
+        assert_eq!(
+            manifest.commands[1].parallel_group.as_deref(),
+            Some("tests")
+        );
+
+        let serialized = serde_yaml::to_string(&manifest).unwrap();
+        assert!(!serialized.contains("SECRET_SOURCE"));
+        assert!(!serialized.contains("hunter2"));
+    }
+}
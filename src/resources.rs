@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+
+use crate::service::Config;
+
+// A requested limit next to the usage observed for it, in milliseconds for times and
+// otherwise in the unit of the matching `Config` field. A limit of 0 means unlimited.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ResourceUsage {
+    pub limit: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ResourceSummary {
+    // CPU time
+    pub time: ResourceUsage,
+    pub wall_time: ResourceUsage,
+    pub memory: ResourceUsage,
+    pub output: ResourceUsage,
+    pub processes: ResourceUsage,
+}
+
+// What the wrapper around each command observed of a run, as a line it appends to the
+// summary file of the container, `resources.yaml`, e.g.
+// `- {run: 0, wall_time: 1520, cpu_time: 1480, output_bytes: 6, peak_processes: 2}`.
+// `run` is the run's index among those the container was given, and times are in
+// milliseconds. The peak is missing where the kernel doesn't track one, and the output where
+// stdout or stderr isn't a file.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ObservedUsage {
+    pub run: usize,
+    pub wall_time: u64,
+    pub cpu_time: u64,
+    #[serde(default)]
+    pub output_bytes: Option<u64>,
+    #[serde(default)]
+    pub peak_processes: Option<u64>,
+}
+
+impl ObservedUsage {
+    // The most of each, for a command run several times
+    pub fn max(self, other: Self) -> Self {
+        ObservedUsage {
+            run: self.run.max(other.run),
+            wall_time: self.wall_time.max(other.wall_time),
+            cpu_time: self.cpu_time.max(other.cpu_time),
+            output_bytes: self.output_bytes.max(other.output_bytes),
+            peak_processes: self.peak_processes.max(other.peak_processes),
+        }
+    }
+}
+
+pub fn parse_summary(contents: &str) -> Result<Vec<ObservedUsage>, String> {
+    if contents.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    serde_yaml::from_str(contents).map_err(|e| format!("Invalid resource summary: {}", e))
+}
+
+impl ResourceSummary {
+    // Summary of the requested limits against the executor's `time` and `memory` and what the
+    // wrapper observed, when it got to write a summary
+    pub fn observed(config: &Config, time: u64, memory: u64, usage: Option<ObservedUsage>) -> Self {
+        let millis = |seconds: Option<u64>| seconds.unwrap_or(0) * 1000;
+        ResourceSummary {
+            time: ResourceUsage {
+                limit: millis(config.cpu_time_limit.or(Some(config.time_limit))),
+                observed: Some(usage.map_or(time, |usage| usage.cpu_time)),
+            },
+            wall_time: ResourceUsage {
+                limit: millis(config.wall_time_limit),
+                observed: usage.map(|usage| usage.wall_time),
+            },
+            memory: ResourceUsage {
+                limit: config.memory_limit,
+                observed: Some(memory),
+            },
+            output: ResourceUsage {
+                limit: config.output_limit,
+                observed: usage.and_then(|usage| usage.output_bytes),
+            },
+            processes: ResourceUsage {
+                limit: config.process_limit,
+                observed: usage.and_then(|usage| usage.peak_processes),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod resources_test {
+    use super::*;
+
+    #[test]
+    fn observed_against_limits() {
+        let config = Config {
+            time_limit: 2,
+            wall_time_limit: Some(5),
+            memory_limit: 128000,
+            output_limit: 4096,
+            process_limit: 8,
+            ..Default::default()
+        };
+        let usage = parse_summary(
+            "- {run: 0, wall_time: 1520, cpu_time: 1480, output_bytes: 6, peak_processes: 2}\n\
+             - {run: 1, wall_time: 30, cpu_time: 10, output_bytes: , peak_processes: }\n",
+        )
+        .unwrap();
+        assert_eq!(usage[1].output_bytes, None);
+        let summary = ResourceSummary::observed(&config, 1500, 2048, Some(usage[0]));
+        assert_eq!(summary.time.limit, 2000);
+        assert_eq!(summary.time.observed, Some(1480));
+        assert_eq!(summary.wall_time.limit, 5000);
+        assert_eq!(summary.wall_time.observed, Some(1520));
+        assert_eq!(summary.memory.limit, config.memory_limit);
+        assert_eq!(summary.memory.observed, Some(2048));
+        assert_eq!(summary.output.observed, Some(6));
+        assert_eq!(summary.processes.limit, 8);
+        assert_eq!(summary.processes.observed, Some(2));
+        // Usage no higher than it was allowed
+        assert!(summary.time.observed.unwrap() <= summary.time.limit);
+        assert!(summary.wall_time.observed.unwrap() <= summary.wall_time.limit);
+        assert!(summary.output.observed.unwrap() <= summary.output.limit);
+
+        // Without a summary, only what the executor measured
+        let summary = ResourceSummary::observed(&config, 1500, 2048, None);
+        assert_eq!(summary.time.observed, Some(1500));
+        assert_eq!(summary.wall_time.observed, None);
+        assert_eq!(summary.processes.observed, None);
+        assert!(
+            !serde_json::to_string(&summary.processes)
+                .unwrap()
+                .contains("observed")
+        );
+        assert!(parse_summary("").unwrap().is_empty());
+        assert!(parse_summary("- {run: 0}\n").is_err());
+    }
+}
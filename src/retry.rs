@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use rabbitmq_stream_client::{Environment, NoDedup, Producer, types::Message};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::events::now_millis;
+use crate::service::FormData;
+
+// How submissions that failed for infrastructure reasons are retried
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        RetryPolicy {
+            max_attempts: std::env::var("RETRY_MAX_ATTEMPTS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(3),
+            base_delay: Duration::from_millis(
+                std::env::var("RETRY_BASE_DELAY_MS")
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(1000),
+            ),
+        }
+    }
+
+    // Exponential backoff: base_delay, 2 * base_delay, 4 * base_delay, ...
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay * 2u32.saturating_pow(attempt.saturating_sub(1))
+    }
+
+    // What to do with a submission whose `attempt`-th try (0 = first delivery) failed
+    pub fn next(&self, attempt: u32, now: u64) -> RetryDecision {
+        let attempt = attempt + 1;
+        if attempt > self.max_attempts {
+            RetryDecision::DeadLetter
+        } else {
+            RetryDecision::Retry {
+                attempt,
+                not_before: now + self.delay(attempt).as_millis() as u64,
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RetryDecision {
+    Retry { attempt: u32, not_before: u64 },
+    DeadLetter,
+}
+
+// Message body on the retry and dead-letter streams
+#[derive(Serialize, Deserialize)]
+pub struct RetryEnvelope<'a> {
+    pub attempt: u32,
+    pub not_before: u64, // Milliseconds since the Unix epoch
+    #[serde(borrow)]
+    pub form_data: FormData<'a>,
+}
+
+// Time left until a retried submission may run again
+pub fn remaining_delay(not_before: u64) -> Duration {
+    Duration::from_millis(not_before.saturating_sub(now_millis()))
+}
+
+pub struct RetryQueue {
+    pub policy: RetryPolicy,
+    retry_producer: Producer<NoDedup>,
+    dead_letter_producer: Producer<NoDedup>,
+}
+
+impl RetryQueue {
+    pub async fn new(
+        environment: &Environment,
+        policy: RetryPolicy,
+        retry_stream: &str,
+        dead_letter_stream: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(RetryQueue {
+            policy,
+            retry_producer: environment.producer().build(retry_stream).await?,
+            dead_letter_producer: environment.producer().build(dead_letter_stream).await?,
+        })
+    }
+
+    // Send a failed submission to the retry stream, or dead-letter it once out of attempts
+    pub async fn requeue(&self, form_data: FormData<'_>, attempt: u32) {
+        let (producer, envelope) = match self.policy.next(attempt, now_millis()) {
+            RetryDecision::Retry {
+                attempt,
+                not_before,
+            } => (
+                &self.retry_producer,
+                RetryEnvelope {
+                    attempt,
+                    not_before,
+                    form_data,
+                },
+            ),
+            RetryDecision::DeadLetter => (
+                &self.dead_letter_producer,
+                RetryEnvelope {
+                    attempt,
+                    not_before: 0,
+                    form_data,
+                },
+            ),
+        };
+        let message = Message::builder()
+            .body(serde_yaml::to_string(&envelope).unwrap_or_default())
+            .build();
+        if let Err(e) = producer.send_with_confirm(message).await {
+            error!(
+                submit_id = %envelope.form_data.submit_id,
+                error = ?e,
+                "requeueing submission failed"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod retry_test {
+    use super::*;
+
+    #[test]
+    fn requeued_until_dead_lettered() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+        };
+        let mut attempt = 0;
+        let mut delays = vec![];
+        while let RetryDecision::Retry {
+            attempt: next,
+            not_before,
+        } = policy.next(attempt, 1000)
+        {
+            assert_eq!(next, attempt + 1);
+            delays.push(not_before - 1000);
+            attempt = next;
+        }
+        assert_eq!(attempt, 3);
+        assert_eq!(delays, [100, 200, 400]);
+        assert_eq!(policy.next(attempt, 1000), RetryDecision::DeadLetter);
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let envelope = RetryEnvelope {
+            attempt: 2,
+            not_before: 1234,
+            form_data: FormData {
+                image: "gcc:14.2",
+                submit_id: "retry".to_string(),
+                ..Default::default()
+            },
+        };
+        let body = serde_yaml::to_string(&envelope).unwrap();
+        let parsed: RetryEnvelope = serde_yaml::from_str(&body).unwrap();
+        assert_eq!(parsed.attempt, 2);
+        assert_eq!(parsed.not_before, 1234);
+        assert_eq!(parsed.form_data.submit_id, "retry");
+    }
+}
@@ -0,0 +1,25 @@
+// Signal delivered when a seccomp filter kills a process for a blocked syscall
+pub const SIGSYS: i32 = 31;
+
+// Syscalls that may have got a process killed by seccomp. Nothing reports the syscall
+// itself, so these are all of the deployment's deny-list, empty when it isn't configured.
+pub fn possibly_blocked_syscalls(signal: Option<i32>, denied: &[String]) -> Option<Vec<String>> {
+    (signal == Some(SIGSYS)).then(|| denied.to_vec())
+}
+
+#[cfg(test)]
+mod seccomp_test {
+    use super::*;
+
+    #[test]
+    fn annotates_sigsys_only() {
+        let denied = vec!["socket".to_string(), "ptrace".to_string()];
+        assert_eq!(possibly_blocked_syscalls(Some(9), &denied), None);
+        assert_eq!(possibly_blocked_syscalls(None, &denied), None);
+        assert_eq!(
+            possibly_blocked_syscalls(Some(SIGSYS), &denied),
+            Some(denied.clone())
+        );
+        assert_eq!(possibly_blocked_syscalls(Some(SIGSYS), &[]), Some(vec![]));
+    }
+}
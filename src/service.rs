@@ -1,27 +1,1272 @@
+use std::fmt;
+use std::io::Read;
+use std::io::{self, Write};
+use std::ops::Range;
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{fs, os::unix::fs::PermissionsExt};
 
+use base64::prelude::{BASE64_STANDARD, Engine};
+use flate2::{Compression, write::GzEncoder};
 use fs_extra::{copy_items, dir};
+use ring::digest;
 use serde::{Deserialize, Serialize};
+use tracing::{Span, info, warn};
 use uuid::Uuid;
 
+use crate::events;
+use crate::images::{self, ImageDefaults};
+use crate::pipeline::PipelineManifest;
+use crate::resources::{self, ObservedUsage, ResourceSummary};
+use crate::seccomp;
+use crate::storage::{self, LocalStore, ObjectStore};
+use crate::strict;
+use crate::telemetry;
+use crate::verdict::{self, Comparator, Verdict};
+use crate::workspace::{self, WorkspaceLimits};
+
+// Where the sandbox template is mounted inside containers, and its default host location
 const SANDBOX_FOLDER: &str = "sandbox";
+// Temp directories of submissions that name a tenant live under `tenants/<tenant>/`
+const TENANTS_FOLDER: &str = "tenants";
 
-#[derive(Serialize, Deserialize)]
+// Deployment-wide settings for the runner, as opposed to the per-command `Config`
+#[derive(Clone, Debug)]
+pub struct SandboxConfig {
+    // Pass `--ulimit cpu=...` so the kernel kills processes exceeding their CPU budget
+    pub cpu_ulimit: bool,
+    // Default `--platform` for `docker run`, e.g. `linux/amd64`
+    pub platform: Option<String>,
+    // Host memory in KB that must stay free on top of a submission's `memory_reserved`,
+    // checked before starting a container. `None` disables the check.
+    pub min_free_memory: Option<u64>,
+    // Default pull policy for submissions that don't choose one
+    pub pull_policy: PullPolicy,
+    // Ceiling on the summed `time` of all commands in a submission, in the same unit
+    pub total_time_limit: Option<u64>,
+    // Outputs larger than this many bytes are sent gzip-compressed
+    pub compress_threshold: Option<usize>,
+    // Custom seccomp profile passed as `--security-opt seccomp=...`
+    pub seccomp_profile: Option<String>,
+    // Syscalls the seccomp profile kills on, used to explain SIGSYS deaths
+    pub denied_syscalls: Vec<String>,
+    // Run containers with `--cap-drop ALL`
+    pub drop_capabilities: bool,
+    // Capabilities added back for images matching a pattern, e.g. `gcc:*` => [SYS_PTRACE]
+    pub image_capabilities: Vec<(String, Vec<String>)>,
+    // Run with `--init` so orphaned processes are reaped and signals are forwarded
+    pub init: bool,
+    // SHA-256 in hex of the executor binary the runner is built against, checked against
+    // `sandbox` in `sandbox_root`
+    pub executor_version: Option<String>,
+    // Refuse to start on an incompatible executor instead of only warning
+    pub executor_version_strict: bool,
+    // Cap on simultaneous `docker pull`s across submissions, independent of running containers
+    pub max_concurrent_pulls: Option<usize>,
+    // Cap on the summed weight of submissions running at once; images weigh 1 unless their
+    // manifest entry says otherwise
+    pub max_concurrent_runs: Option<usize>,
+    // Cap on containers running at once with the working directory bind-mounted, for storage
+    // backends that slow down under many mounts; independent of `max_concurrent_runs`
+    pub max_concurrent_mounts: Option<usize>,
+    // Reject submissions with keys that `FormData`, `CMD` or `Config` don't define
+    pub strict_yaml: bool,
+    // `--entrypoint` for every container; an empty string clears the image's own entrypoint
+    // so `./sandbox` is run directly
+    pub entrypoint: Option<String>,
+    // Ceilings on the sandbox template, checked at startup to catch runaway per-run copies
+    pub template_max_bytes: Option<u64>,
+    pub template_max_files: Option<u64>,
+    pub template_max_depth: Option<usize>,
+    // Refuse to start on an oversized template instead of only warning
+    pub template_strict: bool,
+    // Shell script run after the user's commands, even when they fail. Its output is
+    // logged and left out of the results.
+    pub teardown_command: Option<String>,
+    // Give containers docker's default network; otherwise they run with `--network none`
+    pub networked: bool,
+    // `--dns` servers and `--add-host` entries (`host:ip`), only applied when networked
+    pub dns: Vec<String>,
+    pub add_hosts: Vec<String>,
+    // Per-image defaults from the image manifest, taking precedence over the settings above
+    pub image_manifest: Vec<ImageDefaults>,
+    // Images submissions may run, exactly or by a prefix ending with `*`; any image when unset
+    pub allowed_images: Option<Vec<String>>,
+    // Reject executor results that parse but don't add up, e.g. a result missing per command
+    pub validate_results: bool,
+    // Directory inside the sandbox created writable for each run and exported as `$HOME`,
+    // for toolchains like pip, gradle and npm that need one
+    pub home: Option<String>,
+    // Where `input_ref`s of commands are fetched from
+    pub input_store: Option<Arc<dyn ObjectStore>>,
+    // Return a per-command summary of requested limits against observed usage, run under the
+    // same shell as `command_reports`, which writes what it sees to a summary file. See
+    // `ObservedUsage`.
+    pub resource_summary: bool,
+    // Return what the executor itself logged to stderr, for diagnosing executor problems
+    pub executor_stderr: bool,
+    // Labels this worker can satisfy; submissions requiring others are left to other pools
+    pub labels: Vec<String>,
+    // Report how many bytes and lines each command wrote, even when its output is discarded
+    pub output_counts: bool,
+    // Add a one-line `summary` of the verdicts and resource usage to each response
+    pub response_summary: bool,
+    // `--log-driver` for containers. Output already comes back through `results.yaml`, so
+    // by default nothing is sent to the host's logs; `None` keeps docker's own default.
+    pub log_driver: Option<String>,
+    // Check that every file the commands reference exists before starting any container,
+    // failing with the full list of missing ones
+    pub preflight_files: bool,
+    // Ceiling in seconds on how long one container may run before it is killed, on top of
+    // the commands' own limits; `None` only uses the commands' limits
+    pub max_run_time: Option<u64>,
+    // Seconds added to every container's run time for starting and stopping it
+    pub run_time_grace: u64,
+    // `--cgroup-parent` for containers, e.g. a systemd slice per tenant. `{tenant}` is
+    // replaced with the submission's tenant, or `default` when it has none.
+    pub cgroup_parent: Option<String>,
+    // Directory holding the sandbox template with the executor, relative to the working
+    // directory unless absolute
+    pub sandbox_root: String,
+    // Registry images are pulled from when they don't name one, e.g.
+    // `registry.example.com/mirror`
+    pub registry_prefix: Option<String>,
+    // Buffering for commands that don't choose one
+    pub output_buffering: Buffering,
+    // Inspect each container once it stops and return docker's account of how it ended.
+    // Containers are then kept until inspected rather than started with `--rm`.
+    pub container_state: bool,
+    // Ceilings on a submission's workspace tarball once unpacked
+    pub workspace_limits: WorkspaceLimits,
+    // Relative speed of this host, e.g. 1.25 for one that needs a quarter more time than the
+    // reference machine. Reported times are divided by it so they compare across hosts.
+    pub time_factor: Option<f64>,
+    // KB a container may use on top of the largest `memory_limit` of the submission, for
+    // the executor itself and the page cache. The total is passed as `--memory`, so a
+    // runaway process is OOM-killed inside its container. Off by default, leaving the
+    // executor's own enforcement as the only limit: telling OOM kills apart means keeping
+    // containers around to inspect them instead of `--rm`.
+    pub container_memory_headroom: Option<u64>,
+    // Bytes processes may lock into memory, passed as `--ulimit memlock=...`, e.g. for
+    // programs using `mlock` or io_uring. Also the most a submission may ask for;
+    // `None` keeps docker's default and refuses submissions asking for any.
+    pub memlock: Option<u64>,
+    // Ceilings on the number of args of a command and their summed length in bytes, checked
+    // before anything runs so an oversized argv is a submission error rather than a
+    // failure inside the container
+    pub max_args: Option<usize>,
+    pub max_args_bytes: Option<usize>,
+    // Compare the working directory before and after each container runs and report the
+    // files its commands created or modified, e.g. to catch "pure computation" programs
+    // caching answers on disk
+    pub report_file_writes: bool,
+    // Pass environment variables and `--add-host` entries sorted by name, so equivalent
+    // submissions start containers with byte-identical arguments and `.env` files. DNS
+    // servers keep their order, which is their priority.
+    pub sorted_args: bool,
+    // Give every successful command a verdict, `NoExpectedOutput` for those without
+    // expected outputs, so clients always see the same result shape
+    pub always_judge: bool,
+    // Most times a submission may ask for one command to be run
+    pub max_repeat: Option<u32>,
+    // Ceilings on the limits of each command, in the units of `time_limit` and `memory_limit`,
+    // applied as submissions are parsed. Larger limits, and a `memory_limit` of 0, are lowered
+    // to them.
+    pub max_time_limit: Option<u64>,
+    pub max_memory_limit: Option<u64>,
+    // Return the limits each command ran with, after image defaults and the ceilings above
+    pub effective_limits: bool,
+    // Run every command under a shell that reports on it once it exits, for what the
+    // executor doesn't: its exit status, when it started and finished, whether it read all of
+    // its input, the signal that killed it and its page faults and CPU time. See
+    // `REPORT_SCRIPT`.
+    pub command_reports: bool,
+    // CLI containers are run with, e.g. `podman`, which must accept docker's flags
+    pub container_runtime: String,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            cpu_ulimit: false,
+            platform: None,
+            min_free_memory: None,
+            pull_policy: PullPolicy::default(),
+            total_time_limit: None,
+            compress_threshold: None,
+            seccomp_profile: None,
+            denied_syscalls: vec![],
+            drop_capabilities: false,
+            image_capabilities: vec![],
+            init: true,
+            executor_version: None,
+            executor_version_strict: false,
+            max_concurrent_pulls: None,
+            max_concurrent_runs: None,
+            max_concurrent_mounts: None,
+            strict_yaml: false,
+            entrypoint: None,
+            template_max_bytes: None,
+            template_max_files: None,
+            template_max_depth: None,
+            template_strict: false,
+            teardown_command: None,
+            networked: true,
+            dns: vec![],
+            add_hosts: vec![],
+            image_manifest: vec![],
+            allowed_images: None,
+            validate_results: true,
+            home: None,
+            input_store: None,
+            resource_summary: false,
+            executor_stderr: false,
+            labels: vec![],
+            output_counts: false,
+            response_summary: false,
+            log_driver: Some("none".to_string()),
+            preflight_files: false,
+            max_run_time: None,
+            run_time_grace: 10,
+            cgroup_parent: None,
+            sandbox_root: SANDBOX_FOLDER.to_string(),
+            registry_prefix: None,
+            output_buffering: Buffering::default(),
+            container_state: false,
+            workspace_limits: WorkspaceLimits {
+                max_bytes: 64 * 1024 * 1024,
+                max_entries: 10_000,
+            },
+            time_factor: None,
+            container_memory_headroom: None,
+            memlock: None,
+            max_args: Some(4096),
+            max_args_bytes: Some(1024 * 1024),
+            report_file_writes: false,
+            sorted_args: false,
+            always_judge: false,
+            max_repeat: Some(100),
+            max_time_limit: None,
+            max_memory_limit: None,
+            effective_limits: false,
+            command_reports: false,
+            container_runtime: "docker".to_string(),
+        }
+    }
+}
+
+impl SandboxConfig {
+    pub fn from_env() -> Self {
+        SandboxConfig {
+            cpu_ulimit: env_flag("SANDBOX_CPU_ULIMIT", false),
+            platform: std::env::var("SANDBOX_PLATFORM").ok(),
+            min_free_memory: env_parse("SANDBOX_MIN_FREE_MEMORY"),
+            pull_policy: env_parse("SANDBOX_PULL_POLICY").unwrap_or_default(),
+            total_time_limit: env_parse("SANDBOX_TOTAL_TIME_LIMIT"),
+            compress_threshold: env_parse("SANDBOX_COMPRESS_THRESHOLD"),
+            seccomp_profile: std::env::var("SANDBOX_SECCOMP_PROFILE").ok(),
+            denied_syscalls: env_list("SANDBOX_DENIED_SYSCALLS"),
+            drop_capabilities: env_flag("SANDBOX_CAP_DROP_ALL", false),
+            image_capabilities: std::env::var("SANDBOX_IMAGE_CAPABILITIES")
+                .map(|value| parse_image_capabilities(&value))
+                .unwrap_or_default(),
+            init: env_flag("SANDBOX_INIT", true),
+            executor_version: std::env::var("SANDBOX_EXECUTOR_VERSION").ok(),
+            executor_version_strict: env_flag("SANDBOX_EXECUTOR_VERSION_STRICT", false),
+            max_concurrent_pulls: env_parse("SANDBOX_MAX_CONCURRENT_PULLS"),
+            max_concurrent_runs: env_parse("SANDBOX_MAX_CONCURRENT_RUNS"),
+            max_concurrent_mounts: env_parse("SANDBOX_MAX_CONCURRENT_MOUNTS"),
+            strict_yaml: env_flag("SANDBOX_STRICT_YAML", false),
+            entrypoint: std::env::var("SANDBOX_ENTRYPOINT").ok(),
+            template_max_bytes: env_parse("SANDBOX_TEMPLATE_MAX_BYTES"),
+            template_max_files: env_parse("SANDBOX_TEMPLATE_MAX_FILES"),
+            template_max_depth: env_parse("SANDBOX_TEMPLATE_MAX_DEPTH"),
+            template_strict: env_flag("SANDBOX_TEMPLATE_STRICT", false),
+            teardown_command: std::env::var("SANDBOX_TEARDOWN_COMMAND").ok(),
+            networked: env_flag("SANDBOX_NETWORKED", true),
+            dns: env_list("SANDBOX_DNS"),
+            add_hosts: env_list("SANDBOX_ADD_HOSTS"),
+            // Read from `SANDBOX_IMAGE_MANIFEST` at startup, where a bad file can be reported
+            image_manifest: vec![],
+            // Comma-separated; `SANDBOX_ALLOWED_IMAGES_FILE` adds more at startup
+            allowed_images: std::env::var("SANDBOX_ALLOWED_IMAGES")
+                .is_ok()
+                .then(|| env_list("SANDBOX_ALLOWED_IMAGES")),
+            validate_results: env_flag("SANDBOX_VALIDATE_RESULTS", true),
+            home: std::env::var("SANDBOX_HOME").ok(),
+            input_store: std::env::var("SANDBOX_INPUT_STORE")
+                .ok()
+                .map(|root| Arc::new(LocalStore::new(root)) as Arc<dyn ObjectStore>),
+            resource_summary: env_flag("SANDBOX_RESOURCE_SUMMARY", false),
+            executor_stderr: env_flag("SANDBOX_EXECUTOR_STDERR", false),
+            labels: env_list("SANDBOX_LABELS"),
+            output_counts: env_flag("SANDBOX_OUTPUT_COUNTS", false),
+            response_summary: env_flag("SANDBOX_RESPONSE_SUMMARY", false),
+            // An empty value keeps docker's default driver
+            log_driver: match std::env::var("SANDBOX_LOG_DRIVER") {
+                Ok(driver) => Some(driver).filter(|driver| !driver.is_empty()),
+                Err(_) => Some("none".to_string()),
+            },
+            preflight_files: env_flag("SANDBOX_PREFLIGHT_FILES", false),
+            max_run_time: env_parse("SANDBOX_MAX_RUN_TIME"),
+            run_time_grace: env_parse("SANDBOX_RUN_TIME_GRACE").unwrap_or(10),
+            cgroup_parent: std::env::var("SANDBOX_CGROUP_PARENT").ok(),
+            sandbox_root: std::env::var("SANDBOX_ROOT")
+                .unwrap_or_else(|_| SANDBOX_FOLDER.to_string()),
+            registry_prefix: std::env::var("IMAGE_REGISTRY_PREFIX")
+                .ok()
+                .filter(|prefix| !prefix.is_empty()),
+            output_buffering: env_parse("SANDBOX_OUTPUT_BUFFERING").unwrap_or_default(),
+            container_state: env_flag("SANDBOX_CONTAINER_STATE", false),
+            workspace_limits: WorkspaceLimits {
+                max_bytes: env_parse("SANDBOX_WORKSPACE_MAX_BYTES").unwrap_or(64 * 1024 * 1024),
+                max_entries: env_parse("SANDBOX_WORKSPACE_MAX_ENTRIES").unwrap_or(10_000),
+            },
+            time_factor: env_parse("SANDBOX_TIME_FACTOR")
+                .filter(|factor: &f64| factor.is_finite() && *factor > 0.0),
+            container_memory_headroom: env_parse("SANDBOX_CONTAINER_MEMORY_HEADROOM"),
+            memlock: env_parse("SANDBOX_MEMLOCK"),
+            // Any value that isn't a number, e.g. `off`, disables the check
+            max_args: match std::env::var("SANDBOX_MAX_ARGS") {
+                Ok(count) => count.trim().parse().ok(),
+                Err(_) => Some(4096),
+            },
+            max_args_bytes: match std::env::var("SANDBOX_MAX_ARGS_BYTES") {
+                Ok(bytes) => bytes.trim().parse().ok(),
+                Err(_) => Some(1024 * 1024),
+            },
+            report_file_writes: env_flag("SANDBOX_REPORT_FILE_WRITES", false),
+            sorted_args: env_flag("SANDBOX_SORTED_ARGS", false),
+            always_judge: env_flag("SANDBOX_ALWAYS_JUDGE", false),
+            max_repeat: match std::env::var("SANDBOX_MAX_REPEAT") {
+                Ok(count) => count.trim().parse().ok(),
+                Err(_) => Some(100),
+            },
+            max_time_limit: env_parse("SANDBOX_MAX_TIME_LIMIT"),
+            max_memory_limit: env_parse("SANDBOX_MAX_MEMORY_LIMIT"),
+            effective_limits: env_flag("SANDBOX_EFFECTIVE_LIMITS", false),
+            command_reports: env_flag("SANDBOX_COMMAND_REPORTS", false),
+            container_runtime: std::env::var("CONTAINER_RUNTIME")
+                .ok()
+                .filter(|runtime| !runtime.trim().is_empty())
+                .unwrap_or_else(|| "docker".to_string()),
+        }
+    }
+
+    // Reference docker pulls and runs `image` by, with `registry_prefix` prepended unless
+    // the image already names a registry host
+    fn image_ref(&self, image: &str) -> String {
+        match &self.registry_prefix {
+            Some(prefix) if !has_registry_host(image) => {
+                format!("{}/{}", prefix.trim_end_matches('/'), image)
+            }
+            _ => image.to_string(),
+        }
+    }
+
+    // `cgroup_parent` for a submission's tenant
+    fn cgroup_parent(&self, form_data: &FormData) -> Option<String> {
+        let tenant = form_data
+            .tenant
+            .as_deref()
+            .map_or_else(|| "default".to_string(), sanitize_tenant);
+        self.cgroup_parent
+            .as_ref()
+            .map(|parent| parent.replace("{tenant}", &tenant))
+    }
+
+    // Image manifest entries matching `image`, most specific first
+    fn image_defaults(&self, image: &str) -> Vec<&ImageDefaults> {
+        self.image_manifest
+            .iter()
+            .filter(|entry| image_matches(&entry.image, image))
+            .collect()
+    }
+
+    // Capabilities granted to `image`, from every matching pattern
+    fn capabilities(&self, image: &str) -> Vec<&str> {
+        let mut capabilities: Vec<&str> = self
+            .image_capabilities
+            .iter()
+            .filter(|(pattern, _)| image_matches(pattern, image))
+            .flat_map(|(_, capabilities)| capabilities.iter().map(String::as_str))
+            .chain(
+                self.image_defaults(image)
+                    .into_iter()
+                    .flat_map(|entry| entry.capabilities.iter().map(String::as_str)),
+            )
+            .collect();
+        capabilities.sort_unstable();
+        capabilities.dedup();
+        capabilities
+    }
+
+    fn cpu_ulimit(&self, image: &str) -> bool {
+        self.image_defaults(image)
+            .iter()
+            .find_map(|entry| entry.cpu_ulimit)
+            .unwrap_or(self.cpu_ulimit)
+    }
+
+    fn memlock(&self, image: &str) -> Option<u64> {
+        self.image_defaults(image)
+            .iter()
+            .find_map(|entry| entry.memlock)
+            .or(self.memlock)
+    }
+
+    // `--ulimit memlock` for a submission: what it asks for, up to the image's ceiling,
+    // otherwise the ceiling itself
+    fn submission_memlock(&self, form_data: &FormData) -> Result<Option<u64>, String> {
+        let ceiling = self.memlock(form_data.image);
+        match (form_data.memlock, ceiling) {
+            (Some(requested), Some(ceiling)) if requested <= ceiling => Ok(Some(requested)),
+            (Some(requested), _) => Err(format!(
+                "memlock of {} bytes exceeds the {} bytes allowed for {}",
+                requested,
+                ceiling.unwrap_or(0),
+                form_data.image
+            )),
+            (None, ceiling) => Ok(ceiling),
+        }
+    }
+
+    fn seccomp_profile(&self, image: &str) -> Option<&str> {
+        self.image_defaults(image)
+            .iter()
+            .find_map(|entry| entry.seccomp_profile.as_deref())
+            .or(self.seccomp_profile.as_deref())
+    }
+
+    fn entrypoint(&self, image: &str) -> Option<&str> {
+        self.image_defaults(image)
+            .iter()
+            .find_map(|entry| entry.entrypoint.as_deref())
+            .or(self.entrypoint.as_deref())
+    }
+
+    fn home(&self, image: &str) -> Option<&str> {
+        self.image_defaults(image)
+            .iter()
+            .find_map(|entry| entry.home.as_deref())
+            .or(self.home.as_deref())
+    }
+
+    // Comparator for commands of `image` that don't choose one
+    fn comparator(&self, image: &str) -> Comparator {
+        self.image_defaults(image)
+            .iter()
+            .find_map(|entry| entry.comparator)
+            .unwrap_or_default()
+    }
+
+    // Containers are kept after they stop, rather than started with `--rm`, when docker's
+    // account of how they ended is needed
+    fn inspects_containers(&self) -> bool {
+        self.container_state || self.container_memory_headroom.is_some()
+    }
+
+    // Commands are run under `REPORT_SCRIPT`
+    fn reports_commands(&self) -> bool {
+        self.command_reports || self.resource_summary
+    }
+
+    // Share of `max_concurrent_runs` one container of `image` takes
+    fn run_weight(&self, image: &str) -> usize {
+        self.image_defaults(image)
+            .iter()
+            .find_map(|entry| entry.weight)
+            .unwrap_or(1)
+    }
+}
+
+// Hex SHA-256 of the executor binary, which has no `--version` to ask
+fn executor_version(executor: &Path) -> Option<String> {
+    let binary = fs::read(executor).ok()?;
+    let digest = digest::digest(&digest::SHA256, &binary);
+    Some(
+        digest
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    )
+}
+
+fn check_executor_version(expected: &str, actual: Option<&str>) -> Result<(), String> {
+    match actual {
+        Some(actual) if actual.eq_ignore_ascii_case(expected.trim()) => Ok(()),
+        Some(actual) => Err(format!(
+            "Sandbox executor has SHA-256 {}, expected {}",
+            actual, expected
+        )),
+        None => Err(format!(
+            "Sandbox executor can't be read, expected SHA-256 {}",
+            expected
+        )),
+    }
+}
+
+// Check at startup that the container runtime can be run, returning its version, e.g.
+// `Docker version 27.3.1, build ce12230`
+pub fn verify_runtime(config: &SandboxConfig) -> Result<String, String> {
+    let runtime = &config.container_runtime;
+    let output = Command::new(runtime)
+        .arg("--version")
+        .output()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => format!(
+                "Container runtime {} not found on PATH, set CONTAINER_RUNTIME to one that is",
+                runtime
+            ),
+            _ => format!("Failed to run container runtime {}: {}", runtime, e),
+        })?;
+    if !output.status.success() {
+        return Err(format!(
+            "Container runtime {} --version failed: {}",
+            runtime,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Handshake with the bundled executor at startup. Only fails when the version is pinned
+// in strict mode; otherwise a mismatch is reported and the runner carries on.
+pub fn verify_executor(config: &SandboxConfig) -> Result<(), String> {
+    let Some(expected) = &config.executor_version else {
+        return Ok(());
+    };
+    let actual = executor_version(&Path::new(&config.sandbox_root).join("sandbox"));
+    match check_executor_version(expected, actual.as_deref()) {
+        Err(e) if config.executor_version_strict => Err(e),
+        Err(e) => {
+            warn!("{}", e);
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+// Size of a sandbox template directory
+#[derive(Debug, Default, PartialEq)]
+struct TemplateStats {
+    bytes: u64,
+    files: u64,
+    // Nesting of the deepest file, 1 for files directly in the template
+    depth: usize,
+}
+
+fn template_stats(path: &Path) -> std::io::Result<TemplateStats> {
+    let mut stats = TemplateStats::default();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let nested = template_stats(&entry.path())?;
+            stats.bytes += nested.bytes;
+            stats.files += nested.files;
+            stats.depth = stats.depth.max(nested.depth + 1);
+        } else {
+            stats.bytes += metadata.len();
+            stats.files += 1;
+            stats.depth = stats.depth.max(1);
+        }
+    }
+    Ok(stats)
+}
+
+fn check_template(config: &SandboxConfig, stats: &TemplateStats) -> Result<(), String> {
+    let mut exceeded = vec![];
+    if let Some(max) = config.template_max_bytes.filter(|max| stats.bytes > *max) {
+        exceeded.push(format!("{} bytes (max {})", stats.bytes, max));
+    }
+    if let Some(max) = config.template_max_files.filter(|max| stats.files > *max) {
+        exceeded.push(format!("{} files (max {})", stats.files, max));
+    }
+    if let Some(max) = config.template_max_depth.filter(|max| stats.depth > *max) {
+        exceeded.push(format!("depth {} (max {})", stats.depth, max));
+    }
+    if exceeded.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "Sandbox template too large: {}",
+        exceeded.join(", ")
+    ))
+}
+
+// Check the sandbox template against the configured ceilings at startup. Like the
+// executor handshake, only strict mode refuses to start.
+pub fn verify_template(config: &SandboxConfig) -> Result<(), String> {
+    if config.template_max_bytes.is_none()
+        && config.template_max_files.is_none()
+        && config.template_max_depth.is_none()
+    {
+        return Ok(());
+    }
+    let stats = template_stats(Path::new(&config.sandbox_root))
+        .map_err(|e| format!("Failed to inspect sandbox template: {}", e))?;
+    match check_template(config, &stats) {
+        Err(e) if config.template_strict => Err(e),
+        Err(e) => {
+            warn!("{}", e);
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+// Exact image match, or a prefix match when the pattern ends with `*`
+fn image_matches(pattern: &str, image: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => image.starts_with(prefix),
+        None => pattern == image,
+    }
+}
+
+// Whether submissions may run `image`
+fn image_allowed(config: &SandboxConfig, image: &str) -> bool {
+    config
+        .allowed_images
+        .as_ref()
+        .is_none_or(|allowed| allowed.iter().any(|pattern| image_matches(pattern, image)))
+}
+
+// Parses `gcc:*=SYS_PTRACE;openjdk:21=SYS_PTRACE,IPC_LOCK`, dropping invalid capability names
+fn parse_image_capabilities(value: &str) -> Vec<(String, Vec<String>)> {
+    value
+        .split(';')
+        .filter_map(|entry| entry.split_once('='))
+        .map(|(pattern, capabilities)| {
+            let capabilities = capabilities
+                .split(',')
+                .map(|capability| capability.trim().trim_start_matches("CAP_"))
+                .filter(|capability| {
+                    !capability.is_empty()
+                        && capability
+                            .chars()
+                            .all(|c| c.is_ascii_uppercase() || c == '_')
+                })
+                .map(str::to_string)
+                .collect();
+            (pattern.trim().to_string(), capabilities)
+        })
+        .collect()
+}
+
+fn env_flag(key: &str, default: bool) -> bool {
+    match std::env::var(key) {
+        Ok(value) => matches!(value.trim(), "1" | "true" | "yes" | "on"),
+        Err(_) => default,
+    }
+}
+
+fn env_parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+}
+
+// Comma-separated list, empty when unset
+fn env_list(key: &str) -> Vec<String> {
+    std::env::var(key)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// When to pull the submission's image before running it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum PullPolicy {
+    Always,
+    #[default]
+    IfNotPresent,
+    Never,
+}
+
+impl std::str::FromStr for PullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Always" => Ok(PullPolicy::Always),
+            "IfNotPresent" => Ok(PullPolicy::IfNotPresent),
+            "Never" => Ok(PullPolicy::Never),
+            _ => Err(format!("Unknown pull policy: {:?}", s)),
+        }
+    }
+}
+
+impl PullPolicy {
+    // Whether to pull, given whether the image is already present locally
+    fn should_pull(self, image: &str, present: bool) -> Result<bool, String> {
+        match (self, present) {
+            (PullPolicy::Always, _) => Ok(true),
+            (PullPolicy::IfNotPresent, present) => Ok(!present),
+            (PullPolicy::Never, true) => Ok(false),
+            (PullPolicy::Never, false) => Err(format!(
+                "Image {} is not present and pull policy is Never",
+                image
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
 pub struct FormData<'a> {
     pub commands: Vec<CMD>,
     pub image: &'a str,
     pub submit_id: String,
+    // Convert CRLF/CR to LF in every input and in the captured output
+    #[serde(default)]
+    pub normalize_line_endings: bool,
+    // Overrides the deployment's `--platform` for this submission
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    // Overrides the deployment's pull policy for this submission
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pull_policy: Option<PullPolicy>,
+    // Contents of a `.env` file, written to the sandbox and loaded into the container environment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<String>,
+    // Run commands sharing a `parallel_group` concurrently, each in its own container
+    #[serde(default)]
+    pub parallel: bool,
+    // Tenant or source of the submission, used to group its temp directory for cleanup and quotas
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    // Publish each command's result as soon as it finishes, ahead of the full response
+    #[serde(default)]
+    pub incremental: bool,
+    // When the server enqueued the submission, in epoch milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submitted_at: Option<u64>,
+    // Capabilities the worker must have, e.g. `gpu` or `high-memory`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+    // Encoding of the response; the submission itself may be YAML or JSON either way
+    #[serde(default)]
+    pub format: WireFormat,
+    // Project files unpacked into the working directory before the first command, as a
+    // base64 tar archive (gzipped or not) or the key of one in the input store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workspace_ref: Option<String>,
+    // Bytes the commands may lock into memory, within what the deployment allows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memlock: Option<u64>,
+}
+
+// The submission's workspace archive, if it has one
+fn load_workspace(config: &SandboxConfig, form_data: &FormData) -> Result<Option<Vec<u8>>, String> {
+    match (&form_data.workspace, &form_data.workspace_ref) {
+        (Some(_), Some(_)) => Err("Both workspace and workspace_ref given".to_string()),
+        (Some(encoded), None) => BASE64_STANDARD
+            .decode(encoded.trim())
+            .map(Some)
+            .map_err(|e| format!("Invalid workspace: {}", e)),
+        (None, Some(key)) => {
+            let store = config
+                .input_store
+                .as_deref()
+                .ok_or("workspace_ref given but no input store is configured")?;
+            store.fetch(key).map(Some)
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+// Encoding of messages on the broker
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Yaml,
+    Json,
+}
+
+// Submissions are handled as YAML throughout. JSON mostly is YAML already, but not
+// entirely (e.g. the `\/` escape), so a JSON submission is converted first.
+pub fn decode_message(message: String) -> Result<String, String> {
+    match serde_json::from_str::<serde_yaml::Value>(&message) {
+        Ok(submission) => {
+            serde_yaml::to_string(&submission).map_err(|e| format!("Invalid submission: {}", e))
+        }
+        Err(_) => Ok(message),
+    }
+}
+
+// Fill limits a submission leaves out from the image manifest. Returns the rewritten
+// submission, or `message` itself when no manifest entry applies.
+pub fn apply_image_defaults(config: &SandboxConfig, message: String) -> Result<String, String> {
+    if config.image_manifest.is_empty() {
+        return Ok(message);
+    }
+    let mut submission: serde_yaml::Value =
+        serde_yaml::from_str(&message).map_err(|e| format!("Invalid submission: {}", e))?;
+    let image = submission["image"].as_str().unwrap_or_default();
+    let entries = config.image_defaults(image);
+    if entries.iter().all(|entry| entry.limits.is_empty()) {
+        return Ok(message);
+    }
+    images::merge_limits(&mut submission, &entries);
+    serde_yaml::to_string(&submission).map_err(|e| format!("Invalid submission: {}", e))
+}
+
+// Parse a submission, rejecting unknown keys in strict mode instead of silently ignoring them.
+// Limits above the deployment's ceilings are lowered to them.
+pub fn parse_form_data<'a>(
+    config: &SandboxConfig,
+    message: &'a str,
+) -> Result<FormData<'a>, String> {
+    let mut form_data = read_form_data(config, message)?;
+    for cmd in &mut form_data.commands {
+        cap_limits(config, &mut cmd.config);
+    }
+    Ok(form_data)
+}
+
+// Parse a submission with its limits as sent, for validating it
+pub fn read_form_data<'a>(
+    config: &SandboxConfig,
+    message: &'a str,
+) -> Result<FormData<'a>, String> {
+    if config.strict_yaml {
+        let value: serde_yaml::Value =
+            serde_yaml::from_str(message).map_err(|e| format!("Invalid submission: {}", e))?;
+        let unknown = strict::unknown_fields(&value);
+        if !unknown.is_empty() {
+            return Err(format!(
+                "Unknown fields in submission: {}",
+                unknown.join(", ")
+            ));
+        }
+    }
+    serde_yaml::from_str(message).map_err(|e| format!("Invalid submission: {}", e))
+}
+
+fn cap_limits(config: &SandboxConfig, limits: &mut Config) {
+    if let Some(max) = config.max_time_limit {
+        limits.time_limit = limits.time_limit.min(max);
+        for limit in [&mut limits.wall_time_limit, &mut limits.cpu_time_limit]
+            .into_iter()
+            .flatten()
+        {
+            *limit = (*limit).min(max);
+        }
+    }
+    if let Some(max) = config.max_memory_limit
+        && (limits.memory_limit == 0 || limits.memory_limit > max)
+    {
+        limits.memory_limit = max;
+    }
+}
+
+// Reject commands whose args exceed `max_args` or `max_args_bytes`
+pub fn check_args(config: &SandboxConfig, commands: &[CMD]) -> Result<(), String> {
+    for (index, cmd) in commands.iter().enumerate() {
+        if let Some(max) = config.max_args
+            && cmd.args.len() > max
+        {
+            return Err(format!(
+                "Command {} ({}) has {} args, at most {} are allowed",
+                index,
+                cmd.command,
+                cmd.args.len(),
+                max
+            ));
+        }
+        let bytes: usize = cmd.args.iter().map(String::len).sum();
+        if let Some(max) = config.max_args_bytes
+            && bytes > max
+        {
+            return Err(format!(
+                "Command {} ({}) has {} bytes of args, at most {} are allowed",
+                index, cmd.command, bytes, max
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Reject commands repeated zero times or more than `max_repeat` times
+pub fn check_repeats(config: &SandboxConfig, commands: &[CMD]) -> Result<(), String> {
+    for (index, cmd) in commands.iter().enumerate() {
+        let Some(repeat) = cmd.repeat else {
+            continue;
+        };
+        if repeat == 0 {
+            return Err(format!(
+                "Command {} ({}) is repeated 0 times",
+                index, cmd.command
+            ));
+        }
+        if let Some(max) = config.max_repeat
+            && repeat > max
+        {
+            return Err(format!(
+                "Command {} ({}) is repeated {} times, at most {} are allowed",
+                index, cmd.command, repeat, max
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Everything wrong with a submission that shows without running it, for checking one before
+// it is queued: missing commands or image, empty args, an image off the allowlist, limits of
+// 0 or beyond the ceilings, data files leaving the working directory, and whatever would
+// fail `check_args`, `check_repeats` or `check_conditions`
+pub fn validate_form_data(
+    config: &SandboxConfig,
+    form_data: &FormData,
+) -> Result<(), ValidationError> {
+    let mut problems = vec![];
+    if form_data.commands.is_empty() {
+        problems.push("No commands".to_string());
+    }
+    if form_data.image.trim().is_empty() {
+        problems.push("No image".to_string());
+    } else if !image_allowed(config, form_data.image) {
+        problems.push(SandboxError::ImageNotAllowed(form_data.image.to_string()).to_string());
+    }
+    for (index, cmd) in form_data.commands.iter().enumerate() {
+        if cmd.command.trim().is_empty() {
+            problems.push(format!("Command {} is empty", index));
+        }
+        let mut command_problems = vec![];
+        if let Some(arg) = cmd.args.iter().position(String::is_empty) {
+            command_problems.push(format!("arg {} is empty", arg));
+        }
+        command_problems.extend(limit_problems(config, &cmd.config));
+        for file in &cmd.files {
+            if let Err(e) = workspace::check_path(Path::new(&file.path))
+                .and_then(|()| file.contents().map(drop))
+            {
+                command_problems.push(e);
+            }
+        }
+        problems.extend(
+            command_problems
+                .into_iter()
+                .map(|problem| format!("Command {} ({}): {}", index, cmd.command, problem)),
+        );
+    }
+    let checks = [
+        check_args(config, &form_data.commands),
+        check_repeats(config, &form_data.commands),
+        check_conditions(&form_data.commands),
+    ];
+    problems.extend(checks.into_iter().filter_map(Result::err));
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError(problems))
+    }
+}
+
+// Limits of 0 where that would stop the command right away, and limits the deployment's
+// ceilings would lower
+fn limit_problems(config: &SandboxConfig, limits: &Config) -> Vec<String> {
+    let mut problems = vec![];
+    if limits.time_limit == 0 {
+        problems.push("time_limit is 0".to_string());
+    }
+    let times = [
+        ("time_limit", Some(limits.time_limit)),
+        ("wall_time_limit", limits.wall_time_limit),
+        ("cpu_time_limit", limits.cpu_time_limit),
+    ];
+    if let Some(max) = config.max_time_limit {
+        for (name, limit) in times {
+            if let Some(limit) = limit.filter(|limit| *limit > max) {
+                problems.push(format!("{} is {}, at most {} is allowed", name, limit, max));
+            }
+        }
+    }
+    if let Some(max) = config.max_memory_limit {
+        match limits.memory_limit {
+            0 => problems.push(format!(
+                "memory_limit is unlimited, at most {} is allowed",
+                max
+            )),
+            limit if limit > max => problems.push(format!(
+                "memory_limit is {}, at most {} is allowed",
+                limit, max
+            )),
+            _ => {}
+        }
+    }
+    problems
+}
+
+// Reject conditions on commands that don't come earlier
+pub fn check_conditions(commands: &[CMD]) -> Result<(), String> {
+    for (index, cmd) in commands.iter().enumerate() {
+        if let Some(run_if) = &cmd.run_if
+            && run_if.depends_on >= index
+        {
+            return Err(format!(
+                "Command {} ({}) depends on command {}, which doesn't run before it",
+                index, cmd.command, run_if.depends_on
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Whether this worker has every label the submission requires
+pub fn admit(config: &SandboxConfig, form_data: &FormData) -> Result<(), String> {
+    let missing: Vec<&str> = form_data
+        .labels
+        .iter()
+        .filter(|label| !config.labels.contains(label))
+        .map(String::as_str)
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Missing worker labels: {}", missing.join(", ")))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ResponseData {
     pub sandbox_results: Vec<SandboxResult>,
     pub submit_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<PipelineManifest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<PhaseMemory>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+    // Set on incremental updates to the index of the single command in `sandbox_results`.
+    // The full response, without it, marks the end of the submission.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update: Option<usize>,
+    // Time between the submission being queued and its execution starting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_wait_ms: Option<u64>,
+    // Whether the submission waited for others to finish, for a job slot or under
+    // `max_concurrent_runs`
+    #[serde(default)]
+    pub hit_concurrency_limit: bool,
+    // See `summarize`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    // Why the submission wasn't run, e.g. the host being under pressure; the client may
+    // submit it again later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejected: Option<String>,
+    // Summed time the submission's `docker run`s took as measured by the runner, container
+    // start and stop included, against the programs' own `time`. Containers running side by
+    // side each count. See `SandboxRun`.
+    #[serde(default)]
+    pub wall_time_ms: u64,
+    // The submission's choice of encoding for this response
+    #[serde(skip)]
+    pub format: WireFormat,
+}
+
+// Milliseconds from when a submission was queued until `started_at`. The server's
+// `submitted_at` covers time spent on the broker; without it the runner's own
+// `received_at` is used.
+pub fn queue_wait_ms(form_data: &FormData, received_at: u64, started_at: u64) -> u64 {
+    started_at.saturating_sub(form_data.submitted_at.unwrap_or(received_at))
+}
+
+impl ResponseData {
+    pub fn new(
+        config: &SandboxConfig,
+        form_data: &FormData,
+        sandbox_results: Vec<SandboxResult>,
+    ) -> Self {
+        ResponseData {
+            memory: Some(PhaseMemory::new(&sandbox_results)),
+            warnings: collect_warnings(config, form_data, &sandbox_results),
+            submit_id: form_data.submit_id.clone(),
+            manifest: Some(PipelineManifest::new(config, form_data)),
+            update: None,
+            queue_wait_ms: None,
+            hit_concurrency_limit: false,
+            summary: config.response_summary.then(|| summarize(&sandbox_results)),
+            rejected: None,
+            wall_time_ms: 0,
+            format: form_data.format,
+            sandbox_results,
+        }
+    }
+
+    pub fn update(form_data: &FormData, command: usize, result: SandboxResult) -> Self {
+        ResponseData {
+            sandbox_results: vec![result],
+            submit_id: form_data.submit_id.clone(),
+            manifest: None,
+            memory: None,
+            warnings: vec![],
+            update: Some(command),
+            queue_wait_ms: None,
+            hit_concurrency_limit: false,
+            summary: None,
+            rejected: None,
+            wall_time_ms: 0,
+            format: form_data.format,
+        }
+    }
+
+    // Response for a submission that wasn't run, for `reason`
+    pub fn rejected(form_data: &FormData, reason: String) -> Self {
+        ResponseData {
+            sandbox_results: vec![],
+            submit_id: form_data.submit_id.clone(),
+            manifest: None,
+            memory: None,
+            warnings: vec![],
+            update: None,
+            queue_wait_ms: None,
+            hit_concurrency_limit: false,
+            summary: None,
+            rejected: Some(reason),
+            wall_time_ms: 0,
+            format: form_data.format,
+        }
+    }
+
+    // The response as sent back, in the submission's format
+    pub fn encode(&self) -> String {
+        match self.format {
+            WireFormat::Yaml => serde_yaml::to_string(self).unwrap_or_default(),
+            WireFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+        }
+    }
+}
+
+// Non-fatal issues with a submission, surfaced to users next to the results
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum Warning {
+    // The command's output was cut off at its `output_limit`
+    OutputTruncated { command: usize },
+    // The command exited before reading all of its input
+    InputNotConsumed { command: usize },
+    // The image ran under emulation for a platform other than the host's
+    PlatformEmulated { platform: String },
+}
+
+// `docker` name of the host architecture, e.g. `amd64` on x86_64
+fn host_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    }
+}
+
+// The single place warnings are gathered from a finished submission
+fn collect_warnings(
+    config: &SandboxConfig,
+    form_data: &FormData,
+    results: &[SandboxResult],
+) -> Vec<Warning> {
+    let mut warnings = vec![];
+    if let Some(platform) = form_data.platform.as_ref().or(config.platform.as_ref())
+        && platform.split('/').nth(1) != Some(host_arch())
+    {
+        warnings.push(Warning::PlatformEmulated {
+            platform: platform.clone(),
+        });
+    }
+    for (command, result) in results.iter().enumerate() {
+        if result.limit_hit == Some(LimitKind::Output) {
+            warnings.push(Warning::OutputTruncated { command });
+        }
+        if result.input_fully_consumed == Some(false) {
+            warnings.push(Warning::InputNotConsumed { command });
+        }
+    }
+    warnings
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+// Peak memory in KB per phase. Compilers often need far more than the program they build,
+// so only `run` is what a memory verdict on the submission should be based on.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PhaseMemory {
+    pub build: Option<u64>,
+    pub run: Option<u64>,
+}
+
+impl PhaseMemory {
+    fn new(results: &[SandboxResult]) -> Self {
+        let peak = |phase| {
+            results
+                .iter()
+                .filter(|result| result.phase == phase)
+                .map(|result| result.memory)
+                .max()
+        };
+        PhaseMemory {
+            build: peak(Phase::Build),
+            run: peak(Phase::Run),
+        }
+    }
+}
+
+// One-line summary of a submission for logs and simple integrations:
+//
+//   <status> [<accepted>/<judged> cases, ]<time>s, <memory>MB
+//
+// `status` is that of the first command that ran and didn't succeed (CE for a failed
+// build, RE, TLE, MLE, OLE or SE for other errors), otherwise WA when any judged command
+// was wrong and AC when none was. Cases are left out when nothing was judged against an expected
+// output. `time` is the summed time of all commands and `memory` the peak of any, rounded
+// up to whole megabytes.
+pub fn summarize(results: &[SandboxResult]) -> String {
+    let failed = results
+        .iter()
+        .find(|result| !matches!(result.state, ExitState::Success | ExitState::Skipped));
+    let judged: Vec<Verdict> = results
+        .iter()
+        .filter_map(|result| result.verdict)
+        .filter(|verdict| *verdict != Verdict::NoExpectedOutput)
+        .collect();
+    let accepted = judged.iter().filter(|v| **v == Verdict::Accepted).count();
+    let status = match failed {
+        Some(result) if result.phase == Phase::Build => "CE",
+        Some(result) => match (&result.state, result.limit_hit) {
+            (_, Some(LimitKind::Output)) => "OLE",
+            (ExitState::TimeLimitExceeded | ExitState::TotalTimeLimitExceeded, _) => "TLE",
+            (ExitState::MemoryLimitExceeded, _) => "MLE",
+            (ExitState::RuntimeError, _) => "RE",
+            _ => "SE",
+        },
+        None if accepted < judged.len() => "WA",
+        None => "AC",
+    };
+    let cases = if judged.is_empty() {
+        String::new()
+    } else {
+        format!(" {}/{} cases,", accepted, judged.len())
+    };
+    let time: u64 = results.iter().map(|result| result.time).sum();
+    let memory = results
+        .iter()
+        .map(|result| result.memory)
+        .max()
+        .unwrap_or(0);
+    format!("{}{} {}s, {}MB", status, cases, time, memory.div_ceil(1024))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
     pub time_limit: u64,
     pub time_reserved: u64,
@@ -30,366 +1275,5996 @@ pub struct Config {
     pub large_stack: bool,
     pub output_limit: u64,
     pub process_limit: u64,
+    // Independent ceilings on elapsed and CPU time, in the unit of `time_limit`. A command
+    // that mostly waits is stopped by the wall limit without tripping the CPU limit. A command
+    // with either runs in a container of its own: the wall limit is the watchdog's timeout,
+    // `run_time_grace` included, and the CPU limit the container's CPU ulimit, which the
+    // executor's own `time_limit` may undercut.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wall_time_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_time_limit: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            time_limit: 1,
+            time_reserved: 1,
+            memory_limit: 256000,
+            memory_reserved: 6144000,
+            large_stack: false,
+            output_limit: 0,
+            process_limit: 0,
+            wall_time_limit: None,
+            cpu_time_limit: None,
+        }
+    }
+}
+
+// How a command's stdout is buffered. Whatever is still buffered when the program is
+// killed, e.g. for exceeding its time limit, never reaches the result.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Buffering {
+    // Whatever the program does on its own, usually fully buffered into a pipe
+    #[default]
+    Program,
+    Line,
+    Unbuffered,
+}
+
+impl std::str::FromStr for Buffering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Program" => Ok(Buffering::Program),
+            "Line" => Ok(Buffering::Line),
+            "Unbuffered" => Ok(Buffering::Unbuffered),
+            _ => Err(format!("Unknown buffering: {:?}", s)),
+        }
+    }
 }
 
-// impl Config {
-//     fn new(image: String, stdin: String) -> Self {
-//         Config {
-//             time_limit: 1,
-//             time_reserved: 1,
-//             memory_limit: 256000,
-//             memory_reserved: 6144000,
-//             large_stack: false,
-//             output_limit: 0,
-//             process_limit: 0,
-//         }
-//     }
-// }
+// Role of a command within a submission, so results can be grouped without relying on position
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Phase {
+    Build,
+    #[default]
+    Run,
+    Check,
+}
+
+// Programs that only build, treated as `Phase::Build` when a command doesn't declare its phase
+const BUILD_COMMANDS: &[&str] = &[
+    "gcc", "g++", "cc", "c++", "clang", "clang++", "javac", "kotlinc", "rustc", "tsc", "cmake",
+];
+
+// Tools that build or run depending on their first argument, with the arguments that build,
+// e.g. `cargo build` but not `cargo run`. `make` builds when given no target.
+const BUILD_SUBCOMMANDS: &[(&str, &[&str])] = &[
+    ("cargo", &["build", "check"]),
+    ("go", &["build", "vet"]),
+    ("dotnet", &["build", "publish"]),
+    ("make", &["all", "build"]),
+];
 
 // Command to be executed
-#[derive(Serialize, Deserialize, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct CMD {
     pub command: String,
     pub args: Vec<String>,
     pub input: String,
+    // Key of an object in the input store fed to stdin instead of `input`, for inputs too
+    // large to send through the broker
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_ref: Option<String>,
     pub config: Config,
+    // Guessed from the program and its first argument when not given, which only goes so far:
+    // a `bash -c` that compiles and then runs counts as running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<Phase>,
+    // Accepted answers for stdout; the command is judged only when this is non-empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expected_outputs: Vec<String>,
+    // Falls back to the image's comparator, then `Exact`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comparator: Option<Comparator>,
+    // Consecutive commands with the same group are independent and may run concurrently
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallel_group: Option<String>,
+    // Stdin ends once `input` is read, unless asked to stay open. Left open, a program waiting
+    // for more input is only stopped by the container's timeout. See `KEEP_STDIN_OPEN_SCRIPT`.
+    #[serde(default)]
+    pub keep_stdin_open: bool,
+    // Run in a container of its own once the commands before it are done, whatever their
+    // outcome, e.g. to clean up after them
+    #[serde(default)]
+    pub teardown: bool,
+    // Leave stdout and stderr out of the result once judged, e.g. when only counts are needed
+    #[serde(default)]
+    pub discard_output: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffering: Option<Buffering>,
+    // Send stderr into stdout, interleaved as written, for clients that read one stream
+    #[serde(default)]
+    pub stderr_to_stdout: bool,
+    // Data files written to the working directory before any command runs, e.g. a
+    // problem's `input.txt`; `input` is still what the command reads on stdin
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileEntry>,
+    // Run the command this many times for benchmarking, reporting timing statistics over
+    // the runs instead of a single measurement
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<u32>,
+    // Stop the program once a line of its stdout contains this string, counting the command
+    // as successful, for interactive problems that signal they are done. Lines are only seen
+    // as the program flushes them, see `buffering`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_on_output: Option<String>,
+    // Only run once an earlier command ended a certain way, e.g. tests only after the build
+    // succeeded and a report only after it failed. Otherwise this command and the ones
+    // following it in its container are skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_if: Option<RunIf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RunIf {
+    // Index of the earlier command this one depends on
+    pub depends_on: usize,
+    #[serde(default)]
+    pub outcome: Outcome,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Outcome {
+    #[default]
+    Success,
+    // Ran, but didn't succeed
+    Failure,
+}
+
+impl RunIf {
+    // Whether the condition holds given the results of the commands run so far
+    fn met(&self, results: &[SandboxResult]) -> bool {
+        let Some(result) = results.get(self.depends_on) else {
+            return false;
+        };
+        match self.outcome {
+            Outcome::Success => result.state == ExitState::Success,
+            Outcome::Failure => !matches!(result.state, ExitState::Success | ExitState::Skipped),
+        }
+    }
+}
+
+// Relays the program's stdout line by line, and on the first line containing the sentinel
+// (`$0`) stops the program and exits successfully. The program runs in the background to
+// be stoppable, with stdin handed over explicitly as `sh` would give it /dev/null, and is
+// killed along with the shell should the executor stop it.
+const STOP_ON_OUTPUT_SCRIPT: &str = r#"fifo="${TMPDIR:-/tmp}/stop-on-output-$$"
+trap 'kill -KILL "$pid" 2>/dev/null; rm -f "$fifo"; exit 143' TERM
+mkfifo "$fifo" || exit 125
+exec 3<&0
+"$@" <&3 3<&- >"$fifo" &
+pid=$!
+exec 3<&-
+while IFS= read -r line || [ -n "$line" ]; do
+    printf '%s\n' "$line"
+    case $line in *"$0"*) kill "$pid" 2>/dev/null; rm -f "$fifo"; exit 0;; esac
+done <"$fifo"
+rm -f "$fifo"
+wait "$pid""#;
+
+// Runs the program with its stdin fed through a fifo whose write end the program holds too, so
+// it never reads EOF after its input. `cat` copies the input over in the background, with
+// stdin handed over explicitly like in `STOP_ON_OUTPUT_SCRIPT`. It only holds the write end,
+// so it dies of a broken pipe rather than outliving the program with input left to copy.
+const KEEP_STDIN_OPEN_SCRIPT: &str = r#"fifo="${TMPDIR:-/tmp}/keep-stdin-open-$$"
+mkfifo "$fifo" || exit 125
+exec 3<&0 5<>"$fifo" 4>"$fifo"
+rm -f "$fifo"
+cat <&3 >&4 3<&- 4>&- 5<&- &
+exec "$0" "$@" <&5 3<&- 4>&- 5<&-"#;
+
+// Runs the program, then appends a line to stderr starting with the marker (`$0`) giving its
+// exit status, when it started and finished and whether it left input unread, and ends the way
+// the program did. The program runs in the background, like in `STOP_ON_OUTPUT_SCRIPT`, so the
+// shell doesn't print how it died. Its stdin is the executor's input file, so reading on from
+// there finds what it left. Times are read from `/proc/uptime`, which any image has unlike a
+// `date` with milliseconds, and a status only counts as a signal if `kill -l` names one.
+// `counters`, run before and after the program, leaves what it added to the shell's children's
+// page faults and CPU ticks in `/proc/$$/stat`, with nothing else forked in between. Where the
+// runner created `resources.yaml`, it also appends what it observed of the run, `$1`, there.
+const REPORT_SCRIPT: &str = r#"trap 'kill -KILL "$pid" 2>/dev/null; exit 143' TERM
+run=$1
+shift
+minflt=0 majflt=0 utime=0 stime=0
+counters() { read -r stat <"/proc/$$/stat"; set -- ${stat##*) }; minflt=$(($9 - minflt)) majflt=$((${11} - majflt)) utime=$((${14} - utime)) stime=$((${15} - stime)); }
+counters
+pids=
+if [ -r /sys/fs/cgroup/pids.peak ]; then read -r pids </sys/fs/cgroup/pids.current; fi
+exec 3<&0
+read -r started _ </proc/uptime
+"$@" <&3 3<&- &
+pid=$!
+wait "$pid" 2>/dev/null
+status=$?
+counters
+read -r finished _ </proc/uptime
+if [ -n "$pids" ] && read -r peak </sys/fs/cgroup/pids.peak; then pids=$((peak - pids)); fi
+signal=
+if [ "$status" -gt 128 ] && kill -l "$status" >/dev/null 2>&1; then signal=$((status - 128)); fi
+unread=$(dd bs=1 count=1 <&3 2>/dev/null | wc -c)
+if [ -w resources.yaml ]; then
+    output=
+    if [ -f "/proc/$$/fd/1" ] && [ -f "/proc/$$/fd/2" ]; then output=$(($(wc -c <"/proc/$$/fd/1") + $(wc -c <"/proc/$$/fd/2"))); fi
+    wall=$((((${finished%.*} - ${started%.*}) * 100 + 1${finished#*.} - 1${started#*.}) * 10))
+    printf '%s {run: %s, wall_time: %s, cpu_time: %s, output_bytes: %s, peak_processes: %s}\n' - "$run" "$wall" "$(((utime + stime) * 10))" "$output" "$pids" >>resources.yaml
+fi
+printf '\n%s status=%s signal=%s started=%s finished=%s unread=%s minflt=%s majflt=%s utime=%s stime=%s\n' "$0" "$status" "$signal" "$started" "$finished" "$unread" "$minflt" "$majflt" "$utime" "$stime" >&2
+if [ -n "$signal" ]; then trap - TERM; kill -s "$signal" "$$"; fi
+exit "$status""#;
+
+const REPORT_MARKER: &str = "@sandbox-report";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    // Relative to the working directory, which it must not leave
+    pub path: String,
+    pub content: String,
+    #[serde(default)]
+    pub encoding: FileEncoding,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FileEncoding {
+    #[default]
+    Raw,
+    Base64,
+}
+
+impl FileEntry {
+    fn contents(&self) -> Result<Vec<u8>, String> {
+        match self.encoding {
+            FileEncoding::Raw => Ok(self.content.clone().into_bytes()),
+            FileEncoding::Base64 => BASE64_STANDARD
+                .decode(self.content.trim())
+                .map_err(|e| format!("Invalid file {:?}: {}", self.path, e)),
+        }
+    }
+}
+
+// The commands as the executor runs them, each repeated command once per run
+fn expand_runs(commands: &[CMD]) -> Vec<CMD> {
+    commands
+        .iter()
+        .flat_map(|cmd| std::iter::repeat_n(cmd, cmd.runs()))
+        .cloned()
+        .collect()
+}
+
+// Fold the results of each repeated command's runs into one: the first run that failed,
+// failing that the last, with `time` the median and `memory` the peak of the runs
+fn collapse_runs(commands: &[CMD], results: Vec<SandboxResult>) -> Vec<SandboxResult> {
+    if commands.iter().all(|cmd| cmd.repeat.is_none()) {
+        return results;
+    }
+    let mut results = results.into_iter();
+    commands
+        .iter()
+        .map(|cmd| {
+            let runs: Vec<SandboxResult> = results.by_ref().take(cmd.runs()).collect();
+            let Some(last) = runs.last() else {
+                return skipped_result();
+            };
+            let mut result = runs
+                .iter()
+                .find(|run| run.state != ExitState::Success)
+                .unwrap_or(last)
+                .clone();
+            if cmd.repeat.is_some() {
+                let times = runs
+                    .iter()
+                    .filter(|run| run.state == ExitState::Success)
+                    .map(|run| run.time);
+                result.timing = TimingStats::of(times.collect());
+                if let Some(timing) = &result.timing {
+                    result.time = timing.median;
+                    result.memory = runs.iter().map(|run| run.memory).max().unwrap_or(0);
+                }
+            }
+            result
+        })
+        .collect()
+}
+
+// Write every command's `files` into `dest`
+fn write_files(dest: &Path, commands: &[CMD]) -> Result<(), String> {
+    for file in commands.iter().flat_map(|cmd| &cmd.files) {
+        workspace::write_file(dest, &file.path, &file.contents()?)?;
+    }
+    Ok(())
+}
+
+impl CMD {
+    // How many times the executor runs the command
+    fn runs(&self) -> usize {
+        self.repeat.map_or(1, |repeat| repeat.max(1) as usize)
+    }
+
+    // The command as handed to the executor, run through `stdbuf` unless it keeps the
+    // program's own buffering, which only affects programs using C stdio. A command merging
+    // its streams is run by a shell redirecting stderr before it `exec`s the program, and
+    // one stopping on output by a shell watching what it prints. With `command_reports`,
+    // all of that runs under `REPORT_SCRIPT`, told it's the container's `run`th.
+    fn for_executor(&self, config: &SandboxConfig, run: usize) -> CMD {
+        let mut cmd = self.clone();
+        cmd.repeat = None;
+        let mode = match self.buffering.unwrap_or(config.output_buffering) {
+            Buffering::Program => None,
+            Buffering::Line => Some("-oL"),
+            Buffering::Unbuffered => Some("-o0"),
+        };
+        if let Some(mode) = mode {
+            cmd.args = [mode.to_string(), cmd.command]
+                .into_iter()
+                .chain(cmd.args)
+                .collect();
+            cmd.command = "stdbuf".to_string();
+        }
+        if self.stderr_to_stdout {
+            let script = ["-c".to_string(), "exec \"$0\" \"$@\" 2>&1".to_string()];
+            cmd.args = script
+                .into_iter()
+                .chain([cmd.command])
+                .chain(cmd.args)
+                .collect();
+            cmd.command = "sh".to_string();
+        }
+        if let Some(sentinel) = &self.stop_on_output {
+            let script = ["-c".to_string(), STOP_ON_OUTPUT_SCRIPT.to_string()];
+            cmd.args = script
+                .into_iter()
+                .chain([sentinel.clone(), cmd.command])
+                .chain(cmd.args)
+                .collect();
+            cmd.command = "sh".to_string();
+        }
+        if self.keep_stdin_open {
+            let script = ["-c".to_string(), KEEP_STDIN_OPEN_SCRIPT.to_string()];
+            cmd.args = script
+                .into_iter()
+                .chain([cmd.command])
+                .chain(cmd.args)
+                .collect();
+            cmd.command = "sh".to_string();
+        }
+        if config.reports_commands() {
+            let script = ["-c".to_string(), REPORT_SCRIPT.to_string()];
+            cmd.args = script
+                .into_iter()
+                .chain([REPORT_MARKER.to_string(), run.to_string(), cmd.command])
+                .chain(cmd.args)
+                .collect();
+            cmd.command = "sh".to_string();
+        }
+        CMD {
+            buffering: None,
+            stderr_to_stdout: false,
+            stop_on_output: None,
+            keep_stdin_open: false,
+            ..cmd
+        }
+    }
+
+    // Declared phase, falling back to a guess based on the executable name
+    pub fn phase(&self) -> Phase {
+        self.phase.unwrap_or_else(|| {
+            let name = self.command.rsplit('/').next().unwrap_or_default();
+            let first = self.args.iter().find(|arg| !arg.starts_with('-'));
+            let builds = BUILD_SUBCOMMANDS.iter().any(|(tool, building)| {
+                *tool == name
+                    && first.map_or(name == "make", |first| building.contains(&first.as_str()))
+            });
+            if builds || BUILD_COMMANDS.contains(&name) {
+                Phase::Build
+            } else {
+                Phase::Run
+            }
+        })
+    }
 }
 
 // Enum representing the exit state of the sandboxed process
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 enum ExitState {
     Success,
     RuntimeError,
     TimeLimitExceeded,
     MemoryLimitExceeded,
+    #[default]
     OtherError,
+    // The submission ran out of its total time budget at or before this command
+    TotalTimeLimitExceeded,
+    // Not run, as its `run_if` condition or that of a command before it wasn't met
+    Skipped,
 }
 
-// Structure to hold the result of the sandbox execution
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SandboxResult {
-    state: ExitState,
-    stdout: String,
-    stderr: String,
-    time: u64,   // Execution time in seconds
-    memory: u64, // Memory usage in KB
+// Which resource limit stopped a process
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LimitKind {
+    Time,
+    Memory,
+    Output,
+    Process,
 }
 
-pub fn sandbox_service(
-    commands: Vec<CMD>,
-    image: &str,
-) -> Result<Vec<SandboxResult>, Box<dyn std::error::Error>> {
-    if !Path::new(SANDBOX_FOLDER).exists() {
-        panic!("No sandbox found");
-    }
-    let tmp_folder = Uuid::new_v4().to_string();
-    if !Path::new(&tmp_folder).exists() {
-        fs::create_dir(&tmp_folder).unwrap();
-    }
-    let perm = fs::Permissions::from_mode(0o777);
-    fs::set_permissions(&tmp_folder, perm.clone())?;
-    copy_items(
-        &[format!("{}/sandbox", SANDBOX_FOLDER)],
-        &tmp_folder,
-        &dir::CopyOptions::new(),
-    )
-    .unwrap();
+// Signals raised by the kernel when RLIMIT_CPU and RLIMIT_FSIZE are exceeded
+const SIGXCPU: i32 = 24;
+const SIGXFSZ: i32 = 25;
+const SIGKILL: i32 = 9;
 
-    fs::write(
-        format!("{}/commands.yaml", &tmp_folder),
-        serde_yaml::to_string(&commands).unwrap(),
-    )
-    .unwrap();
+// Times of the successful runs of a repeated command, percentiles by nearest rank
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TimingStats {
+    pub runs: u32,
+    pub min: u64,
+    pub median: u64,
+    pub p95: u64,
+    pub max: u64,
+}
 
-    let mut command = Command::new("docker");
-    command.arg("run").arg("--rm");
-    command.arg("-v").arg(format!("./{}:/sandbox", tmp_folder));
-    command.arg("-w").arg(format!("/{}", SANDBOX_FOLDER));
-    command.arg(image).arg("./sandbox");
-    let _ = command.output();
-    let results = fs::read_to_string(format!("{}/results.yaml", tmp_folder)).unwrap();
-    let _ = fs::remove_dir_all(tmp_folder);
-    Ok(serde_yaml::from_str(&results).unwrap())
+impl TimingStats {
+    fn of(mut times: Vec<u64>) -> Option<Self> {
+        times.sort_unstable();
+        let percentile = |percent: usize| times[(times.len() * percent).div_ceil(100).max(1) - 1];
+        Some(TimingStats {
+            runs: times.len() as u32,
+            min: *times.first()?,
+            median: percentile(50),
+            p95: percentile(95),
+            max: *times.last()?,
+        })
+    }
 }
 
-#[cfg(test)]
-mod service_test {
+// `getrusage` counters of a command and the processes it waited for, as `REPORT_SCRIPT` reads
+// them off its shell. Linux shows a process's children's page faults and CPU times, but not
+// their context switches.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Rusage {
+    major_page_faults: u64,
+    minor_page_faults: u64,
+    // CPU time in user and kernel mode, in milliseconds
+    user_time: u64,
+    system_time: u64,
+}
 
-    use super::*;
+// Linux's `/proc` gives CPU times in ticks of `USER_HZ`, 100 per second
+const CLOCK_TICK_MS: u64 = 10;
 
-    #[test]
-    fn gcc_version() {
-        let commands = vec![CMD {
-            command: "gcc".to_string(),
-            args: vec!["--version".to_string()],
-            input: "".to_string(),
-            config: Config {
-                time_limit: 1,
-                time_reserved: 1,
-                memory_limit: 256000,
-                memory_reserved: 4096000,
-                large_stack: false,
-                output_limit: 0,
-                process_limit: 0,
-            },
-        }];
-        let results = sandbox_service(commands, "gcc:14.2");
-        assert!(results.is_ok());
-        assert_eq!(
-            results.unwrap()[0].stdout,
-            "gcc (GCC) 14.2.0\nCopyright (C) 2024 Free Software Foundation, Inc.\nThis is free software; see the source for copying conditions.  There is NO\nwarranty; not even for MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.\n\n"
+// Failures the server should treat as system errors rather than a verdict on the submission
+#[derive(Debug)]
+pub enum SandboxError {
+    // There is no `sandbox` folder with the executor in the working directory
+    MissingSandboxFolder(String),
+    // The submission can't run as given, e.g. a malformed env file or platform
+    InvalidSubmission(String),
+    // The host has too little free memory for the submission right now
+    InsufficientMemory(String),
+    // The image isn't present and couldn't be pulled
+    ImageUnavailable(String),
+    // The image isn't on the allowlist
+    ImageNotAllowed(String),
+    // Creating the temp directory or writing the executor's inputs failed
+    TempSetup(io::Error),
+    // Copying the executor into the temp directory failed
+    CopyFailed(fs_extra::error::Error),
+    // The docker CLI couldn't be started
+    DockerSpawn(io::Error),
+    // The docker daemon couldn't be reached, e.g. while it restarts
+    DockerUnavailable(String),
+    // The executor exited without writing its results
+    ResultsMissing,
+    // `docker run` failed before the executor wrote any results, with docker's exit
+    // code and what it printed, e.g. 125 when the container couldn't be created
+    DockerExited(Option<i32>, String),
+    // The executor's results aren't valid YAML for `SandboxResult`s
+    ResultsParse(serde_yaml::Error),
+    // The executor's results parsed but are inconsistent with the commands
+    InvalidResults(String),
+    // Compressing large outputs failed
+    Compression(io::Error),
+}
+
+impl SandboxError {
+    // Whether running the submission again later may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            SandboxError::DockerSpawn(_)
+                | SandboxError::DockerUnavailable(_)
+                | SandboxError::InsufficientMemory(_)
         )
     }
+}
 
-    #[test]
-    fn c_a_add_b() {
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SandboxError::MissingSandboxFolder(root) => write!(f, "No sandbox found in {}", root),
+            SandboxError::InvalidSubmission(reason) => write!(f, "Invalid submission: {}", reason),
+            SandboxError::InsufficientMemory(reason) | SandboxError::ImageUnavailable(reason) => {
+                write!(f, "{}", reason)
+            }
+            SandboxError::TempSetup(e) => write!(f, "Failed to set up sandbox: {}", e),
+            SandboxError::CopyFailed(e) => write!(f, "Failed to copy executor: {}", e),
+            SandboxError::DockerSpawn(e) => write!(f, "Failed to run docker: {}", e),
+            SandboxError::ImageNotAllowed(image) => write!(f, "Image {} is not allowed", image),
+            SandboxError::DockerUnavailable(reason) => {
+                write!(f, "Docker daemon unavailable: {}", reason)
+            }
+            SandboxError::ResultsMissing => write!(f, "Executor wrote no results"),
+            SandboxError::DockerExited(code, stderr) => {
+                match code {
+                    Some(code) => write!(f, "docker run exited with {}", code)?,
+                    None => write!(f, "docker run was killed by a signal")?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr)?;
+                }
+                Ok(())
+            }
+            SandboxError::ResultsParse(e) => write!(f, "Unparsable executor results: {}", e),
+            SandboxError::InvalidResults(reason) => {
+                write!(f, "Invalid executor results: {}", reason)
+            }
+            SandboxError::Compression(e) => write!(f, "Failed to compress output: {}", e),
+        }
+    }
+}
+
+// Every problem `validate_form_data` found with a submission
+#[derive(Debug, PartialEq)]
+pub struct ValidationError(pub Vec<String>);
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid submission: {}", self.0.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl std::error::Error for SandboxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SandboxError::TempSetup(e)
+            | SandboxError::DockerSpawn(e)
+            | SandboxError::Compression(e) => Some(e),
+            SandboxError::CopyFailed(e) => Some(e),
+            SandboxError::ResultsParse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// What the docker CLI prints when it can't reach the daemon or loses it mid-run
+const DAEMON_UNAVAILABLE: &[&str] = &[
+    "Cannot connect to the Docker daemon",
+    "Is the docker daemon running",
+    "error during connect",
+    "error waiting for container",
+];
+
+// Tell a `docker run` that failed for lack of a daemon from one whose program failed
+fn docker_unavailable(output: &io::Result<Output>) -> Option<SandboxError> {
+    match output {
+        Err(e) => Some(SandboxError::DockerSpawn(io::Error::new(
+            e.kind(),
+            e.to_string(),
+        ))),
+        Ok(output) if !output.status.success() => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            DAEMON_UNAVAILABLE
+                .iter()
+                .any(|message| stderr.contains(message))
+                .then(|| SandboxError::DockerUnavailable(stderr.trim().to_string()))
+        }
+        Ok(_) => None,
+    }
+}
+
+// Parse the executor's results for `expected` commands. With `validate`, results that parse
+// but contradict themselves or the commands are rejected too.
+fn parse_results(
+    output: &str,
+    expected: usize,
+    validate: bool,
+) -> Result<Vec<SandboxResult>, SandboxError> {
+    if output.trim().is_empty() {
+        return Err(SandboxError::ResultsMissing);
+    }
+    let results: Vec<SandboxResult> =
+        serde_yaml::from_str(output).map_err(SandboxError::ResultsParse)?;
+    if !validate {
+        return Ok(results);
+    }
+    // Fewer results are expected once a command failed, see `fill_skipped`
+    let failed = results
+        .iter()
+        .any(|result| result.state != ExitState::Success);
+    if results.len() > expected || (results.len() < expected && !failed) {
+        return Err(SandboxError::InvalidResults(format!(
+            "{} results for {} commands",
+            results.len(),
+            expected
+        )));
+    }
+    if let Some(index) = results
+        .iter()
+        .position(|result| result.state == ExitState::Success && result.signal.is_some())
+    {
+        return Err(SandboxError::InvalidResults(format!(
+            "command {} succeeded but was killed by a signal",
+            index
+        )));
+    }
+    Ok(results)
+}
+
+// Attach to each result the limits its command requested next to the usage in the summary
+// its container wrote, the most of a repeated command's runs. `pids.peak` counts from when
+// the container started, so only the first command's peak is its own.
+fn attach_resources(commands: &[CMD], summary: &str, results: &mut [SandboxResult]) {
+    let usage = resources::parse_summary(summary).unwrap_or_else(|e| {
+        warn!(error = %e, "ignoring resource summary");
+        vec![]
+    });
+    let mut first_run = 0;
+    for (index, (cmd, result)) in commands.iter().zip(results.iter_mut()).enumerate() {
+        let runs = first_run..first_run + cmd.runs();
+        first_run = runs.end;
+        let mut observed = usage
+            .iter()
+            .filter(|usage| runs.contains(&usage.run))
+            .copied()
+            .reduce(ObservedUsage::max);
+        if index > 0
+            && let Some(observed) = &mut observed
+        {
+            observed.peak_processes = None;
+        }
+        result.resources = Some(ResourceSummary::observed(
+            &cmd.config,
+            result.time,
+            result.memory,
+            observed,
+        ));
+    }
+}
+
+// When the machine booted, in epoch milliseconds. Containers share the runner's kernel, so
+// this turns the uptimes `REPORT_SCRIPT` reads into times.
+fn boot_millis() -> Option<u64> {
+    let uptime = fs::read_to_string("/proc/uptime").ok()?;
+    events::now_millis().checked_sub(uptime_millis(uptime.split_whitespace().next()?)?)
+}
+
+// `/proc/uptime`'s seconds, e.g. `351.27`, in milliseconds
+fn uptime_millis(uptime: &str) -> Option<u64> {
+    let seconds: f64 = uptime.parse().ok()?;
+    Some((seconds * 1000.0).round() as u64)
+}
+
+// Cut the line `REPORT_SCRIPT` appended to stderr, filling in what it reports. A program
+// killed along with the shell leaves none. A status of 128 plus a signal is all a shell
+// gets for a program the signal killed, so a program exiting with one still reads as killed.
+fn take_report(boot: Option<u64>, result: &mut SandboxResult) {
+    let Some(at) = result.stderr.rfind(&format!("\n{} ", REPORT_MARKER)) else {
+        return;
+    };
+    let report = result.stderr.split_off(at);
+    let mut counters = [None; 4];
+    for field in report.split_whitespace().skip(1) {
+        match field.split_once('=') {
+            Some(("status", status)) => result.exit_code = status.parse().ok(),
+            Some(("signal", signal)) => result.signal = signal.parse().ok(),
+            // Left to the container's bounds when the runner can't tell when it booted
+            Some(("started", at)) => {
+                result.started_at = boot.zip(uptime_millis(at)).map(|(boot, at)| boot + at)
+            }
+            Some(("finished", at)) => {
+                result.finished_at = boot.zip(uptime_millis(at)).map(|(boot, at)| boot + at)
+            }
+            Some(("unread", unread)) => result.input_fully_consumed = Some(unread == "0"),
+            Some(("minflt", count)) => counters[0] = count.parse::<u64>().ok(),
+            Some(("majflt", count)) => counters[1] = count.parse().ok(),
+            Some(("utime", ticks)) => counters[2] = ticks.parse().ok(),
+            Some(("stime", ticks)) => counters[3] = ticks.parse().ok(),
+            _ => {}
+        }
+    }
+    if let [Some(minor), Some(major), Some(user), Some(system)] = counters {
+        result.rusage = Some(Rusage {
+            major_page_faults: major,
+            minor_page_faults: minor,
+            user_time: user * CLOCK_TICK_MS,
+            system_time: system * CLOCK_TICK_MS,
+        });
+    }
+}
+
+// Include the executor's stderr in the reason results were rejected, when it is returned
+fn with_executor_stderr(config: &SandboxConfig, error: SandboxError, stderr: &str) -> SandboxError {
+    match error {
+        SandboxError::InvalidResults(reason) if config.executor_stderr && !stderr.is_empty() => {
+            SandboxError::InvalidResults(format!("{}; executor stderr: {}", reason, stderr.trim()))
+        }
+        error => error,
+    }
+}
+
+// Structure to hold the result of the sandbox execution
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SandboxResult {
+    state: ExitState,
+    stdout: String,
+    stderr: String,
+    time: u64,   // Execution time in seconds
+    memory: u64, // Memory usage in KB
+    #[serde(default)]
+    phase: Phase,
+    // Whether the program read all of its stdin before exiting, with command reports. Input
+    // it buffered, e.g. through C stdio, counts as read whether or not it was used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input_fully_consumed: Option<bool>,
+    // When set, `stdout` and `stderr` hold base64-encoded gzip data
+    #[serde(default)]
+    compressed: bool,
+    // The signal that killed the process, if any, as reported with `command_reports`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signal: Option<i32>,
+    // Exit status of the program as reported with `command_reports`. Without a report it is
+    // inferred: 0 for a success, 128 plus the signal for a process killed by one, and unknown
+    // for any other failure.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    // Denied syscalls, any of which seccomp may have blocked, when the process died of SIGSYS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    possibly_blocked_syscalls: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verdict: Option<Verdict>,
+    // Reported by the executor, otherwise inferred from the exit state and signal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limit_hit: Option<LimitKind>,
+    // Reported with `command_reports`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rusage: Option<Rusage>,
+    // When the command started and finished, in epoch milliseconds, as reported with
+    // `command_reports`. Otherwise they are when the container it ran in started and exited,
+    // shared by the commands run together.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    started_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    finished_at: Option<u64>,
+    // Requested limits against observed usage, when resource summaries are enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resources: Option<ResourceSummary>,
+    // The executor's own stderr for the container that ran this command, set on the first
+    // command of each container when executor stderr is returned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    executor_stderr: Option<String>,
+    // Size of what the command wrote, reported by the executor or counted from the
+    // captured output when output counts are enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stdout_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stderr_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stdout_lines: Option<u64>,
+    // How docker says the container running this command ended, e.g. `OOMKilled` or
+    // `Exited (0)`, when container states are returned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    container_state: Option<String>,
+    // `time` as measured on this host, when `time` is normalized by the host's time factor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw_time: Option<u64>,
+    // Files created or modified in the working directory while the commands of a container
+    // ran, set on the last command of each container when file writes are reported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files_written: Option<Vec<String>>,
+    // Statistics over the runs of a command with `repeat`, whose `time` is then the median
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timing: Option<TimingStats>,
+    // The limits the command ran with, when effective limits are returned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    effective_limits: Option<Config>,
+}
+
+// A submission's results along with how long its `docker run`s took in total as measured by
+// the runner, in milliseconds
+pub struct SandboxRun {
+    pub results: Vec<SandboxResult>,
+    pub wall_time_ms: u64,
+}
+
+fn detect_limit_hit(result: &SandboxResult) -> Option<LimitKind> {
+    if result.limit_hit.is_some() {
+        return result.limit_hit;
+    }
+    match (&result.state, result.signal) {
+        (ExitState::TimeLimitExceeded | ExitState::TotalTimeLimitExceeded, _) => {
+            Some(LimitKind::Time)
+        }
+        (ExitState::MemoryLimitExceeded, _) => Some(LimitKind::Memory),
+        (_, Some(SIGXCPU)) => Some(LimitKind::Time),
+        (_, Some(SIGXFSZ)) => Some(LimitKind::Output),
+        _ => None,
+    }
+}
+
+// Judge successful commands against their expected outputs. Those without any are left
+// unjudged, unless they name a comparator or `always_judge` is set, which asks for a
+// verdict for every command.
+fn judge_results(
+    commands: &[CMD],
+    results: &mut [SandboxResult],
+    default: Comparator,
+    always_judge: bool,
+) {
+    for (result, cmd) in results.iter_mut().zip(commands) {
+        if result.state == ExitState::Success {
+            let comparator = cmd.comparator.unwrap_or(default);
+            result.verdict =
+                verdict::judge(comparator, &result.stdout, &cmd.expected_outputs)
+                    .or((always_judge || cmd.comparator.is_some())
+                        .then_some(Verdict::NoExpectedOutput));
+        }
+    }
+}
+
+fn gzip_base64(text: &str) -> std::io::Result<String> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(text.as_bytes())?;
+    Ok(BASE64_STANDARD.encode(encoder.finish()?))
+}
+
+// Compress both streams of any result whose stdout or stderr exceeds `threshold` bytes
+fn compress_large_outputs(threshold: usize, results: &mut [SandboxResult]) -> io::Result<()> {
+    for result in results {
+        if result.stdout.len() > threshold || result.stderr.len() > threshold {
+            result.stdout = gzip_base64(&result.stdout)?;
+            result.stderr = gzip_base64(&result.stderr)?;
+            result.compressed = true;
+        }
+    }
+    Ok(())
+}
+
+fn teardown_command(script: &str) -> CMD {
+    CMD {
+        command: "sh".to_string(),
+        args: vec!["-c".to_string(), script.to_string()],
+        teardown: true,
+        ..Default::default()
+    }
+}
+
+// Drop the teardown command and its result, logging what it printed
+fn take_teardown(commands: &mut Vec<CMD>, results: &mut Vec<SandboxResult>, user_commands: usize) {
+    commands.truncate(user_commands);
+    for teardown in results.drain(user_commands.min(results.len())..) {
+        info!(
+            state = ?teardown.state,
+            stdout = %teardown.stdout,
+            stderr = %teardown.stderr,
+            "teardown finished"
+        );
+    }
+}
+
+// Fill in the fields that depend on the command that produced each result
+fn annotate_results(config: &SandboxConfig, commands: &[CMD], results: &mut [SandboxResult]) {
+    for (result, cmd) in results.iter_mut().zip(commands) {
+        result.phase = cmd.phase();
+        // Input kept open is all copied into the fifo, read or not
+        if cmd.input.is_empty() || cmd.keep_stdin_open {
+            result.input_fully_consumed = None;
+        }
+        result.possibly_blocked_syscalls =
+            seccomp::possibly_blocked_syscalls(result.signal, &config.denied_syscalls);
+        result.limit_hit = detect_limit_hit(result);
+        if result.exit_code.is_none() {
+            result.exit_code = match (&result.state, result.signal) {
+                (_, Some(signal)) => Some(128 + signal),
+                (ExitState::Success, None) => Some(0),
+                _ => None,
+            };
+        }
+        if config.output_counts {
+            count_output(result);
+        }
+        truncate_output(cmd.config.output_limit, result);
+        if config.effective_limits {
+            result.effective_limits = Some(cmd.config.clone());
+        }
+    }
+}
+
+const TRUNCATED_MARKER: &str = "\n[output truncated]";
+
+// Scale times to the reference machine, keeping what was measured in `raw_time`
+fn normalize_times(factor: f64, results: &mut [SandboxResult]) {
+    for result in results {
+        let raw = *result.raw_time.get_or_insert(result.time);
+        result.time = (raw as f64 / factor).round() as u64;
+    }
+}
+
+// Cut stdout and stderr down to `limit` bytes each, in case the executor returned more
+// than the command was allowed to write. A limit of 0 means unlimited.
+fn truncate_output(limit: u64, result: &mut SandboxResult) {
+    let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+    if limit == 0 {
+        return;
+    }
+    let mut truncated = false;
+    for output in [&mut result.stdout, &mut result.stderr] {
+        if output.len() > limit {
+            let mut end = limit;
+            while !output.is_char_boundary(end) {
+                end -= 1;
+            }
+            output.truncate(end);
+            output.push_str(TRUNCATED_MARKER);
+            truncated = true;
+        }
+    }
+    if truncated {
+        result.limit_hit = Some(LimitKind::Output);
+        if result.state == ExitState::Success {
+            result.state = ExitState::OtherError;
+        }
+    }
+}
+
+// Fill in whichever output counts the executor didn't report
+fn count_output(result: &mut SandboxResult) {
+    let stdout = &result.stdout;
+    result.stdout_bytes.get_or_insert(stdout.len() as u64);
+    result
+        .stderr_bytes
+        .get_or_insert(result.stderr.len() as u64);
+    // A final line without a newline still counts
+    let lines =
+        stdout.matches('\n').count() + usize::from(!stdout.is_empty() && !stdout.ends_with('\n'));
+    result.stdout_lines.get_or_insert(lines as u64);
+}
+
+fn discard_outputs(commands: &[CMD], results: &mut [SandboxResult]) {
+    for (result, cmd) in results.iter_mut().zip(commands) {
+        if cmd.discard_output {
+            result.stdout.clear();
+            result.stderr.clear();
+        }
+    }
+}
+
+// Once the summed time passes `limit`, mark the offending command and every later one
+// as `TotalTimeLimitExceeded`, discarding the output of the commands after it. The stage
+// loop already stopped starting commands by then, see `budget_exceeded_result`. Applying
+// it again to more results leaves those it already marked as they were.
+fn apply_total_time_limit(limit: u64, results: &mut [SandboxResult]) {
+    let mut total = 0;
+    let mut exceeded = false;
+    for result in results {
+        total += result.time;
+        if exceeded {
+            result.stdout.clear();
+            result.stderr.clear();
+            result.time = 0;
+            result.memory = 0;
+        }
+        if exceeded || total > limit {
+            exceeded = true;
+            result.state = ExitState::TotalTimeLimitExceeded;
+            result.limit_hit = Some(LimitKind::Time);
+        }
+    }
+}
+
+// CPU seconds allowed to any process in the container. The in-sandbox timer should
+// fire first, so this only acts as a backstop for CPU-bound runaways, except for a
+// `cpu_time_limit`, which nothing else enforces.
+fn cpu_ulimit_seconds(commands: &[CMD]) -> u64 {
+    commands
+        .iter()
+        .map(|cmd| {
+            let limit = cmd.config.cpu_time_limit.unwrap_or(cmd.config.time_limit);
+            limit + cmd.config.time_reserved
+        })
+        .max()
+        .unwrap_or(0)
+        .max(1)
+}
+
+// `--memory` in KB for a container running `commands`
+fn container_memory(config: &SandboxConfig, commands: &[CMD]) -> Option<u64> {
+    let headroom = config.container_memory_headroom?;
+    let largest = commands.iter().map(|cmd| cmd.config.memory_limit).max()?;
+    (largest > 0).then_some(largest + headroom)
+}
+
+// Tasks a container needs beyond its commands' own, for `docker-init`, the executor and
+// its threads
+const PIDS_HEADROOM: u64 = 32;
+
+// `--pids-limit` for a container running `commands`. A `process_limit` of 0 means no
+// limit, so a single unlimited command leaves the whole container unlimited.
+fn container_pids(commands: &[CMD]) -> Option<u64> {
+    let limits: Vec<u64> = commands
+        .iter()
+        .map(|cmd| cmd.config.process_limit)
+        .collect();
+    if limits.contains(&0) {
+        return None;
+    }
+    limits.into_iter().max().map(|limit| limit + PIDS_HEADROOM)
+}
+
+// Mark the command the kernel OOM-killed inside its container: the one that died of
+// SIGKILL, failing that the last one that didn't succeed
+fn mark_oom_killed(results: &mut [SandboxResult]) {
+    let killed = results
+        .iter()
+        .position(|result| result.signal == Some(SIGKILL));
+    let killed = killed.or_else(|| {
+        results
+            .iter()
+            .rposition(|result| result.state != ExitState::Success)
+    });
+    if let Some(result) = killed.map(|index| &mut results[index]) {
+        result.state = ExitState::MemoryLimitExceeded;
+        result.limit_hit = Some(LimitKind::Memory);
+    }
+}
+
+// Accepts `os/arch` or `os/arch/variant`, e.g. `linux/amd64` or `linux/arm/v7`
+fn validate_platform(platform: &str) -> Result<(), String> {
+    let parts: Vec<&str> = platform.split('/').collect();
+    let valid = (2..=3).contains(&parts.len())
+        && parts.iter().all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid platform: {:?}", platform))
+    }
+}
+
+// `$HOME` must be a plain relative path so it stays inside the sandbox folder
+fn validate_home(home: &str) -> Result<(), String> {
+    let valid = !home.is_empty()
+        && !home.starts_with('/')
+        && home
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..");
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid home directory: {:?}", home))
+    }
+}
+
+// A cgroup path, absolute or relative to docker's own, or a systemd slice name
+fn validate_cgroup_parent(parent: &str) -> Result<(), String> {
+    let path = parent.strip_prefix('/').unwrap_or(parent);
+    let valid = path.split('/').all(|part| {
+        !part.is_empty()
+            && part != "."
+            && part != ".."
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid cgroup parent: {:?}", parent))
+    }
+}
+
+// Counting semaphore shared across submissions
+struct Limiter {
+    active: Mutex<usize>,
+    released: Condvar,
+}
+
+// Image pulls, so cold images don't trip registry rate limits
+static PULLS: Limiter = Limiter::new();
+// Weight of running submissions, so heavy images can't crowd the host
+static RUNS: Limiter = Limiter::new();
+// Containers with bind mounts, so the storage backing them isn't overwhelmed
+static MOUNTS: Limiter = Limiter::new();
+
+impl Limiter {
+    const fn new() -> Self {
+        Limiter {
+            active: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    // Blocks until fewer than `limit` units are in use
+    fn acquire(&self, limit: usize) -> Permit<'_> {
+        self.acquire_weighted(1, limit)
+    }
+
+    // Blocks until `weight` more units fit under `limit`. Weights above the limit are
+    // capped to it, so a heavy submission runs alone rather than never.
+    fn acquire_weighted(&self, weight: usize, limit: usize) -> Permit<'_> {
+        let limit = limit.max(1);
+        let weight = weight.clamp(1, limit);
+        let mut active = self.active.lock().unwrap();
+        let mut waited = false;
+        while *active + weight > limit {
+            waited = true;
+            active = self.released.wait(active).unwrap();
+        }
+        *active += weight;
+        Permit {
+            limiter: self,
+            weight,
+            waited,
+        }
+    }
+}
+
+struct Permit<'a> {
+    limiter: &'a Limiter,
+    weight: usize,
+    // Whether the limit was reached and the holder had to wait for others to finish
+    waited: bool,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.limiter.active.lock().unwrap() -= self.weight;
+        self.limiter.released.notify_all();
+    }
+}
+
+// Admission under `max_concurrent_runs`, held for as long as a submission runs
+pub struct RunPermit {
+    _permit: Option<Permit<'static>>,
+    pub waited: bool,
+}
+
+pub fn acquire_run(config: &SandboxConfig, form_data: &FormData) -> RunPermit {
+    acquire_run_from(&RUNS, config, form_data)
+}
+
+fn acquire_run_from(
+    limiter: &'static Limiter,
+    config: &SandboxConfig,
+    form_data: &FormData,
+) -> RunPermit {
+    // Concurrent lanes each weigh as much as a whole submission
+    let lanes = execution_stages(&form_data.commands, form_data.parallel, false)
+        .iter()
+        .map(Vec::len)
+        .max()
+        .unwrap_or(1);
+    let weight = config.run_weight(form_data.image) * lanes;
+    let permit = config
+        .max_concurrent_runs
+        .map(|limit| limiter.acquire_weighted(weight, limit));
+    RunPermit {
+        waited: permit.as_ref().is_some_and(|permit| permit.waited),
+        _permit: permit,
+    }
+}
+
+// Held while a container with the working directory mounted runs
+fn acquire_mount<'a>(limiter: &'a Limiter, config: &SandboxConfig) -> Option<Permit<'a>> {
+    config
+        .max_concurrent_mounts
+        .map(|limit| limiter.acquire(limit))
+}
+
+// Like docker, the first path component names a registry when it looks like a host
+fn has_registry_host(image: &str) -> bool {
+    image.split_once('/').is_some_and(|(first, _)| {
+        first == "localhost" || first.contains('.') || first.contains(':')
+    })
+}
+
+fn image_present(config: &SandboxConfig, image: &str) -> bool {
+    Command::new(&config.container_runtime)
+        .args(["image", "inspect", image])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+// Apply the submission's pull policy before the image is run
+fn ensure_image(config: &SandboxConfig, form_data: &FormData) -> Result<(), String> {
+    let policy = form_data.pull_policy.unwrap_or(config.pull_policy);
+    let image = config.image_ref(form_data.image);
+    if !policy.should_pull(&image, image_present(config, &image))? {
+        return Ok(());
+    }
+    let _permit = config
+        .max_concurrent_pulls
+        .map(|limit| PULLS.acquire(limit));
+    let mut pull = Command::new(&config.container_runtime);
+    pull.arg("pull");
+    if let Some(platform) = form_data.platform.as_ref().or(config.platform.as_ref()) {
+        pull.arg("--platform").arg(platform);
+    }
+    let output = pull
+        .arg(&image)
+        .output()
+        .map_err(|e| format!("Failed to run docker pull: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to pull image {}: {}",
+            image,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+// Splits commands into stages that run one after another. The lanes of a stage run
+// concurrently, each in its own container; without `parallel` everything is one lane.
+// In `incremental` mode every sequential command gets a stage of its own, so its result
+// is available as soon as it finishes. A command with a `run_if` condition starts a stage,
+// so the result it depends on is known before its container is started. Teardown commands
+// get a stage of their own, so they run after everything before them however it ended, as
+// do commands with limits of their own container. See `runs_alone`.
+fn execution_stages(commands: &[CMD], parallel: bool, incremental: bool) -> Vec<Vec<Range<usize>>> {
+    let single = |i: usize| std::iter::once(i..i + 1).collect();
+    let conditional = has_conditions(commands);
+    if !parallel && !conditional && !commands.iter().any(runs_alone) {
+        if incremental {
+            return (0..commands.len()).map(single).collect();
+        }
+        return vec![std::iter::once(0..commands.len()).collect()];
+    }
+    let group_of = |cmd| parallel_group(cmd, parallel);
+    let starts_stage = |cmd: &CMD| runs_alone(cmd) || (conditional && cmd.run_if.is_some());
+    let mut stages = vec![];
+    let mut start = 0;
+    while start < commands.len() {
+        let group = group_of(&commands[start]);
+        let end = if runs_alone(&commands[start]) {
+            start + 1
+        } else {
+            commands[start + 1..]
+                .iter()
+                .position(|cmd| group_of(cmd) != group || starts_stage(cmd))
+                .map_or(commands.len(), |offset| start + 1 + offset)
+        };
+        match group {
+            Some(_) => stages.push((start..end).map(|i| i..i + 1).collect()),
+            None if incremental => stages.extend((start..end).map(single)),
+            None => stages.push(std::iter::once(start..end).collect()),
+        }
+        start = end;
+    }
+    stages
+}
+
+// The parallel group a command runs in, none without `parallel`
+fn parallel_group(cmd: &CMD, parallel: bool) -> Option<&str> {
+    cmd.parallel_group.as_deref().filter(|_| parallel)
+}
+
+fn has_conditions(commands: &[CMD]) -> bool {
+    commands.iter().any(|cmd| cmd.run_if.is_some())
+}
+
+// Teardown, and commands whose limits are enforced on their container: a wall limit by the
+// watchdog and a CPU limit by the CPU ulimit
+fn runs_alone(cmd: &CMD) -> bool {
+    cmd.teardown || cmd.config.wall_time_limit.is_some() || cmd.config.cpu_time_limit.is_some()
+}
+
+fn has_cpu_time_limit(commands: &[CMD]) -> bool {
+    commands
+        .iter()
+        .any(|cmd| cmd.config.cpu_time_limit.is_some())
+}
+
+// Result for a command skipped because a `run_if` condition wasn't met
+fn condition_skipped_result() -> SandboxResult {
+    SandboxResult {
+        state: ExitState::Skipped,
+        stderr: "Not run: condition not met".to_string(),
+        ..Default::default()
+    }
+}
+
+// Result for a command not started because the submission's time budget was spent
+fn budget_exceeded_result() -> SandboxResult {
+    SandboxResult {
+        state: ExitState::TotalTimeLimitExceeded,
+        limit_hit: Some(LimitKind::Time),
+        ..Default::default()
+    }
+}
+
+// Tenant names become a single path component of `[A-Za-z0-9_-]`, at most 64 characters
+fn sanitize_tenant(tenant: &str) -> String {
+    let sanitized: String = tenant
+        .chars()
+        .take(64)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized.is_empty() {
+        "_".to_string()
+    } else {
+        sanitized
+    }
+}
+
+// Fresh temp directory for a submission, relative to the working directory
+fn temp_folder(form_data: &FormData) -> String {
+    let id = Uuid::new_v4().to_string();
+    match &form_data.tenant {
+        Some(tenant) => format!("{}/{}/{}", TENANTS_FOLDER, sanitize_tenant(tenant), id),
+        None => id,
+    }
+}
+
+// Holds the commands and results files of a lane, named after its first command
+fn lane_folder(tmp_folder: &str, lane: Option<usize>) -> String {
+    match lane {
+        Some(lane) => format!("{}/lane-{}", tmp_folder, lane),
+        None => tmp_folder.to_string(),
+    }
+}
+
+// Seconds a container running `commands` may take before the watchdog kills it: their
+// summed wall time limits, or time limits where they have none, capped by `max_run_time`,
+// plus the grace for starting the container
+fn run_time_limit(config: &SandboxConfig, commands: &[CMD]) -> Duration {
+    let limits: u64 = commands
+        .iter()
+        .map(|cmd| {
+            let limit = match cmd.config.wall_time_limit {
+                Some(wall_time_limit) => wall_time_limit,
+                None => cmd.config.time_limit + cmd.config.time_reserved,
+            };
+            limit * cmd.runs() as u64
+        })
+        .sum();
+    let limit = config.max_run_time.map_or(limits, |max| limits.min(max));
+    Duration::from_secs(limit + config.run_time_grace)
+}
+
+// Like `Command::output`, but once `timeout` passes kills the container named `name` through
+// `runtime` and returns `None`. Only stderr is captured; the executor reports through
+// `results.yaml`.
+fn output_with_timeout(
+    runtime: &str,
+    command: &mut Command,
+    name: &str,
+    timeout: Duration,
+) -> io::Result<Option<Output>> {
+    let mut child = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut pipe = child.stderr.take();
+    let reader = thread::spawn(move || {
+        let mut stderr = vec![];
+        if let Some(pipe) = &mut pipe {
+            let _ = pipe.read_to_end(&mut stderr);
+        }
+        stderr
+    });
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            let stderr = reader.join().unwrap_or_default();
+            return Ok(Some(Output {
+                status,
+                stdout: vec![],
+                stderr,
+            }));
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    // Killing the CLI alone would leave the container running
+    let _ = Command::new(runtime).args(["kill", name]).output();
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = reader.join();
+    Ok(None)
+}
+
+const STATE_FORMAT: &str =
+    "{{.State.OOMKilled}}|{{.State.Dead}}|{{.State.ExitCode}}|{{.State.Error}}";
+
+// Description of a stopped container from `docker inspect --format STATE_FORMAT`
+fn describe_state(inspected: &str) -> Option<String> {
+    let mut fields = inspected.trim().splitn(4, '|');
+    let (oom_killed, dead, exit_code) = (fields.next()?, fields.next()?, fields.next()?);
+    let error = fields.next().unwrap_or_default();
+    Some(if oom_killed == "true" {
+        "OOMKilled".to_string()
+    } else if dead == "true" {
+        "Dead".to_string()
+    } else if !error.is_empty() {
+        format!("Error: {}", error)
+    } else {
+        format!("Exited ({})", exit_code.parse::<i64>().ok()?)
+    })
+}
+
+fn container_state(config: &SandboxConfig, name: &str) -> Option<String> {
+    let output = Command::new(&config.container_runtime)
+        .args(["inspect", "--format", STATE_FORMAT, name])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    describe_state(&String::from_utf8_lossy(&output.stdout))
+}
+
+// A lane's results, blaming `docker run` itself when it failed without any
+fn lane_results(
+    contents: Option<String>,
+    status: Option<ExitStatus>,
+    stderr: &str,
+) -> Result<Option<String>, SandboxError> {
+    match (
+        contents.filter(|contents| !contents.trim().is_empty()),
+        status,
+    ) {
+        (Some(contents), _) => Ok(Some(contents)),
+        (None, Some(status)) if !status.success() => Err(SandboxError::DockerExited(
+            status.code(),
+            stderr.trim().to_string(),
+        )),
+        (None, _) => Err(SandboxError::ResultsMissing),
+    }
+}
+
+// The executor stops at the first command that fails, only running teardown commands after
+// it. Commands it skipped get a placeholder, so there is a result per command.
+fn fill_skipped(commands: &[CMD], results: Vec<SandboxResult>) -> Vec<SandboxResult> {
+    if results.len() >= commands.len() {
+        return results;
+    }
+    let mut results = results.into_iter();
+    let mut failed = false;
+    commands
+        .iter()
+        .map(|cmd| {
+            let result = if failed && !cmd.teardown {
+                None
+            } else {
+                results.next()
+            };
+            let result = result.unwrap_or_else(skipped_result);
+            failed |= result.state != ExitState::Success;
+            result
+        })
+        .collect()
+}
+
+fn skipped_result() -> SandboxResult {
+    SandboxResult {
+        state: ExitState::OtherError,
+        stderr: "Not run: an earlier command failed".to_string(),
+        ..Default::default()
+    }
+}
+
+// Result for a command whose container the watchdog killed
+fn timed_out_result(timeout: Duration) -> SandboxResult {
+    SandboxResult {
+        state: ExitState::TimeLimitExceeded,
+        time: timeout.as_secs(),
+        limit_hit: Some(LimitKind::Time),
+        ..Default::default()
+    }
+}
+
+// Name of the container running `lane` of the submission in `tmp_folder`, unique per run
+// since it ends with the temp folder's uuid
+fn container_name(tmp_folder: &str, lane: Option<usize>) -> String {
+    let id = tmp_folder.rsplit('/').next().unwrap_or_default();
+    match lane {
+        Some(lane) => format!("sandbox-{}-lane-{}", id, lane),
+        None => format!("sandbox-{}", id),
+    }
+}
+
+// Removes a run's containers and temp folder when dropped, so they don't outlive an early
+// return or a panic
+struct RunGuard {
+    runtime: String,
+    tmp_folder: String,
+    containers: Vec<String>,
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        // `--rm` has usually removed them already, in which case this fails harmlessly
+        for name in &self.containers {
+            let _ = Command::new(&self.runtime)
+                .args(["rm", "-f", name])
+                .output();
+        }
+        let _ = fs::remove_dir_all(&self.tmp_folder);
+    }
+}
+
+// `docker run` for a container running `commands`, whose limits it is sized for
+fn docker_command(
+    config: &SandboxConfig,
+    form_data: &FormData,
+    commands: &[CMD],
+    tmp_folder: &str,
+    lane: Option<usize>,
+) -> Result<Command, String> {
+    let mut command = Command::new(&config.container_runtime);
+    command.arg("run");
+    // Kept containers are removed by `RunGuard` once inspected
+    if !config.inspects_containers() {
+        command.arg("--rm");
+    }
+    command.arg("--name").arg(container_name(tmp_folder, lane));
+    if config.init {
+        command.arg("--init");
+    }
+    if let Some(driver) = &config.log_driver {
+        command.arg("--log-driver").arg(driver);
+    }
+    if let Some(memory) = container_memory(config, commands) {
+        // Without swap, so the limit can't be dodged by paging out
+        command
+            .arg("--memory")
+            .arg(format!("{}k", memory))
+            .arg("--memory-swap")
+            .arg(format!("{}k", memory));
+    }
+    if let Some(pids) = container_pids(commands) {
+        command.arg("--pids-limit").arg(pids.to_string());
+    }
+    if config.cpu_ulimit(form_data.image) || has_cpu_time_limit(commands) {
+        // Soft limit raises SIGXCPU, the hard limit one second later raises SIGKILL
+        let seconds = cpu_ulimit_seconds(commands);
+        command
+            .arg("--ulimit")
+            .arg(format!("cpu={}:{}", seconds, seconds + 1));
+    }
+    if let Some(bytes) = config.submission_memlock(form_data)? {
+        command
+            .arg("--ulimit")
+            .arg(format!("memlock={}:{}", bytes, bytes));
+    }
+    if let Some(platform) = form_data.platform.as_ref().or(config.platform.as_ref()) {
+        validate_platform(platform)?;
+        command.arg("--platform").arg(platform);
+    }
+    if let Some(parent) = config.cgroup_parent(form_data) {
+        validate_cgroup_parent(&parent)?;
+        command.arg("--cgroup-parent").arg(parent);
+    }
+    if config.drop_capabilities {
+        command.arg("--cap-drop").arg("ALL");
+    }
+    for capability in config.capabilities(form_data.image) {
+        command.arg("--cap-add").arg(capability);
+    }
+    if config.networked {
+        for server in &config.dns {
+            command.arg("--dns").arg(server);
+        }
+        let mut hosts: Vec<&String> = config.add_hosts.iter().collect();
+        if config.sorted_args {
+            hosts.sort();
+        }
+        for host in hosts {
+            command.arg("--add-host").arg(host);
+        }
+    } else {
+        command.arg("--network").arg("none");
+    }
+    let mut env = vec![];
+    if let Some(home) = config.home(form_data.image) {
+        validate_home(home)?;
+        env.push(format!("HOME=/{}/{}", SANDBOX_FOLDER, home));
+    }
+    if config.sorted_args {
+        env.sort();
+    }
+    for var in env {
+        command.arg("-e").arg(var);
+    }
+    if form_data.env_file.is_some() {
+        command
+            .arg("--env-file")
+            .arg(format!("./{}/.env", tmp_folder));
+    }
+    if let Some(profile) = config.seccomp_profile(form_data.image) {
+        command
+            .arg("--security-opt")
+            .arg(format!("seccomp={}", profile));
+    }
+    command.arg("-v").arg(format!("./{}:/sandbox", tmp_folder));
+    if lane.is_some() {
+        // Lanes share the working directory but not the executor's input and output files
+        let summary = config.resource_summary.then_some("resources.yaml");
+        for file in ["commands.yaml", "results.yaml"].into_iter().chain(summary) {
+            command.arg("-v").arg(format!(
+                "./{}/{}:/sandbox/{}",
+                lane_folder(tmp_folder, lane),
+                file,
+                file
+            ));
+        }
+    }
+    command.arg("-w").arg(format!("/{}", SANDBOX_FOLDER));
+    if let Some(entrypoint) = config.entrypoint(form_data.image) {
+        command.arg("--entrypoint").arg(entrypoint);
+    }
+    command
+        .arg(config.image_ref(form_data.image))
+        .arg("./sandbox");
+    Ok(command)
+}
+
+// `MemAvailable` from /proc/meminfo, in KB
+pub fn available_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+// `docker run` flags a submission is started with, leaving out mounts and per-run paths
+pub fn docker_flags(config: &SandboxConfig, form_data: &FormData) -> Result<Vec<String>, String> {
+    let command = docker_command(config, form_data, &form_data.commands, "", None)?;
+    let mut args = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .skip(1);
+    let mut flags = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" | "-v" | "-w" => {
+                args.next();
+            }
+            "--env-file" => {
+                args.next();
+                flags.push(arg);
+            }
+            _ if arg == config.image_ref(form_data.image) => break,
+            _ => flags.push(arg),
+        }
+    }
+    Ok(flags)
+}
+
+// Refuse a submission whose reserved memory doesn't fit in what the host has available
+fn check_free_memory(
+    config: &SandboxConfig,
+    form_data: &FormData,
+    available: u64,
+) -> Result<(), String> {
+    let Some(headroom) = config.min_free_memory else {
+        return Ok(());
+    };
+    // Concurrent lanes each hold their reservation at the same time
+    let reserved = execution_stages(&form_data.commands, form_data.parallel, false)
+        .iter()
+        .map(|stage| {
+            stage
+                .iter()
+                .filter_map(|lane| {
+                    form_data.commands[lane.clone()]
+                        .iter()
+                        .map(|cmd| cmd.config.memory_reserved)
+                        .max()
+                })
+                .sum::<u64>()
+        })
+        .max()
+        .unwrap_or(0);
+    if available < reserved + headroom {
+        return Err(format!(
+            "Insufficient free memory: {} KB available, {} KB required",
+            available,
+            reserved + headroom
+        ));
+    }
+    Ok(())
+}
+
+fn valid_env_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Parses `KEY=VALUE` lines, skipping blanks and `#` comments and accepting an `export `
+// prefix and quoted values. Fails on the first line with a missing `=` or an invalid key.
+fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("Invalid env file line {}: missing '='", number + 1));
+        };
+        let key = key.trim();
+        if !valid_env_key(key) {
+            return Err(format!(
+                "Invalid env file key {:?} on line {}",
+                key,
+                number + 1
+            ));
+        }
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')]
+            .iter()
+            .find_map(|(open, close)| value.strip_prefix(*open)?.strip_suffix(*close))
+            .unwrap_or(value);
+        vars.push((key.to_string(), value.to_string()));
+    }
+    Ok(vars)
+}
+
+// The `.env` file passed to docker for parsed `vars`
+fn render_env_file(config: &SandboxConfig, vars: &[(String, String)]) -> String {
+    let mut vars: Vec<&(String, String)> = vars.iter().collect();
+    if config.sorted_args {
+        // Stable, so the last of several values for a key still wins
+        vars.sort_by_key(|(key, _)| key);
+    }
+    vars.iter()
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect()
+}
+
+fn normalize_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+// Judge a stage's results and shape their output the way they're returned
+fn finish_results(
+    config: &SandboxConfig,
+    form_data: &FormData,
+    commands: &[CMD],
+    results: &mut [SandboxResult],
+) -> Result<(), SandboxError> {
+    if form_data.normalize_line_endings {
+        for result in results.iter_mut() {
+            result.stdout = normalize_line_endings(&result.stdout);
+            result.stderr = normalize_line_endings(&result.stderr);
+        }
+    }
+    judge_results(
+        commands,
+        results,
+        config.comparator(form_data.image),
+        config.always_judge,
+    );
+    discard_outputs(commands, results);
+    if let Some(threshold) = config.compress_threshold {
+        compress_large_outputs(threshold, results).map_err(SandboxError::Compression)?;
+    }
+    Ok(())
+}
+
+// What `sandbox_service_with_progress` reports as a submission runs
+pub enum Progress<'a> {
+    // Commands of the phase are starting. A container running commands of several phases
+    // is only seen starting the first one's, as the runner only hears back once it exits.
+    Phase(Phase),
+    // A command's result, by index
+    Result(usize, &'a SandboxResult),
+}
+
+// The results of `sandbox_service_with_progress` alone
+#[cfg(test)]
+pub fn sandbox_service(
+    config: &SandboxConfig,
+    form_data: &FormData,
+) -> Result<Vec<SandboxResult>, SandboxError> {
+    sandbox_service_with_progress(config, form_data, &mut |_| {}).map(|run| run.results)
+}
+
+// Run a submission's commands and return a result per command, in order: commands skipped
+// because an earlier one failed get an `OtherError` placeholder, while infrastructure
+// failures (e.g. a container dying before writing its results) are errors. Blocks until every
+// container has exited, so async callers run it on tokio's blocking pool (`spawn_blocking`),
+// one job per submission; how many run at once is bounded by `max_concurrent_runs`.
+// `on_progress` hears of each stage starting in a phase other than the last one's, and gets the
+// index and result of each command as soon as its stage finishes. See `Progress`.
+pub fn sandbox_service_with_progress(
+    config: &SandboxConfig,
+    form_data: &FormData,
+    on_progress: &mut dyn FnMut(Progress),
+) -> Result<SandboxRun, SandboxError> {
+    if !image_allowed(config, form_data.image) {
+        return Err(SandboxError::ImageNotAllowed(form_data.image.to_string()));
+    }
+    check_args(config, &form_data.commands).map_err(SandboxError::InvalidSubmission)?;
+    check_repeats(config, &form_data.commands).map_err(SandboxError::InvalidSubmission)?;
+    check_conditions(&form_data.commands).map_err(SandboxError::InvalidSubmission)?;
+    let mut commands = form_data.commands.clone();
+    if config.preflight_files {
+        let missing = storage::missing_inputs(config.input_store.as_deref(), &commands);
+        if !missing.is_empty() {
+            return Err(SandboxError::InvalidSubmission(format!(
+                "Missing files: {}",
+                missing.join(", ")
+            )));
+        }
+    }
+    storage::resolve_input_refs(config.input_store.as_deref(), &mut commands)
+        .map_err(SandboxError::InvalidSubmission)?;
+    let workspace = load_workspace(config, form_data).map_err(SandboxError::InvalidSubmission)?;
+    if form_data.normalize_line_endings {
+        for cmd in &mut commands {
+            cmd.input = normalize_line_endings(&cmd.input);
+            for expected in &mut cmd.expected_outputs {
+                *expected = normalize_line_endings(expected);
+            }
+        }
+    }
+    let user_commands = commands.len();
+    if let Some(script) = &config.teardown_command {
+        commands.push(teardown_command(script));
+    }
+    if !Path::new(&config.sandbox_root).exists() {
+        return Err(SandboxError::MissingSandboxFolder(
+            config.sandbox_root.clone(),
+        ));
+    }
+    let env_vars = form_data
+        .env_file
+        .as_deref()
+        .map(parse_env_file)
+        .transpose()
+        .map_err(SandboxError::InvalidSubmission)?;
+    if config.min_free_memory.is_some()
+        && let Some(available) = available_memory()
+    {
+        check_free_memory(config, form_data, available)
+            .map_err(SandboxError::InsufficientMemory)?;
+    }
+    let tmp_folder = temp_folder(form_data);
+    // Every container but a lone one gets a folder of its own
+    // A time budget is checked between stages, so every command gets one as when incremental
+    let one_by_one = form_data.incremental || config.total_time_limit.is_some();
+    let staged = form_data.parallel
+        || one_by_one
+        || has_conditions(&commands)
+        || commands.iter().any(runs_alone);
+    let lane_of = |range: &Range<usize>| staged.then_some(range.start);
+    let mut stages = execution_stages(&commands, form_data.parallel, one_by_one)
+        .into_iter()
+        .map(|stage| {
+            stage
+                .into_iter()
+                .map(|range| {
+                    let command = docker_command(
+                        config,
+                        form_data,
+                        &commands[range.clone()],
+                        &tmp_folder,
+                        lane_of(&range),
+                    )?;
+                    Ok((range, command))
+                })
+                .collect::<Result<Vec<_>, String>>()
+        })
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(SandboxError::InvalidSubmission)?;
+    ensure_image(config, form_data).map_err(SandboxError::ImageUnavailable)?;
+    let guard = RunGuard {
+        runtime: config.container_runtime.clone(),
+        tmp_folder: tmp_folder.clone(),
+        containers: stages
+            .iter()
+            .flatten()
+            .map(|(range, _)| container_name(&tmp_folder, lane_of(range)))
+            .collect(),
+    };
+    let setup = || -> Result<(), SandboxError> {
+        let perm = fs::Permissions::from_mode(0o777);
+        let write_commands = |path: String, commands: &[CMD]| {
+            let commands: Vec<CMD> = expand_runs(commands)
+                .iter()
+                .enumerate()
+                .map(|(run, cmd)| cmd.for_executor(config, run))
+                .collect();
+            let yaml = serde_yaml::to_string(&commands).map_err(io::Error::other)?;
+            fs::write(path, yaml)
+        };
+        fs::create_dir_all(&tmp_folder).map_err(SandboxError::TempSetup)?;
+        fs::set_permissions(&tmp_folder, perm.clone()).map_err(SandboxError::TempSetup)?;
+        if let Some(archive) = &workspace {
+            workspace::extract(archive, Path::new(&tmp_folder), config.workspace_limits)
+                .map_err(SandboxError::InvalidSubmission)?;
+        }
+        write_files(Path::new(&tmp_folder), &commands).map_err(SandboxError::InvalidSubmission)?;
+        copy_items(
+            &[format!("{}/sandbox", config.sandbox_root)],
+            &tmp_folder,
+            &dir::CopyOptions::new(),
+        )
+        .map_err(SandboxError::CopyFailed)?;
+        write_commands(format!("{}/commands.yaml", &tmp_folder), &commands)
+            .map_err(SandboxError::TempSetup)?;
+        if let Some(home) = config.home(form_data.image) {
+            let home = format!("{}/{}", tmp_folder, home);
+            fs::create_dir_all(&home).map_err(SandboxError::TempSetup)?;
+            fs::set_permissions(&home, perm.clone()).map_err(SandboxError::TempSetup)?;
+        }
+        if let Some(env_vars) = &env_vars {
+            fs::write(
+                format!("{}/.env", &tmp_folder),
+                render_env_file(config, env_vars),
+            )
+            .map_err(SandboxError::TempSetup)?;
+        }
+        for (range, _) in stages.iter().flatten() {
+            if let Some(lane) = lane_of(range) {
+                let folder = lane_folder(&tmp_folder, Some(lane));
+                fs::create_dir(&folder).map_err(SandboxError::TempSetup)?;
+                write_commands(
+                    format!("{}/commands.yaml", folder),
+                    &commands[range.clone()],
+                )
+                .map_err(SandboxError::TempSetup)?;
+                let path = format!("{}/results.yaml", folder);
+                fs::write(&path, "").map_err(SandboxError::TempSetup)?;
+                fs::set_permissions(&path, perm.clone()).map_err(SandboxError::TempSetup)?;
+            }
+            if config.resource_summary {
+                let path = format!(
+                    "{}/resources.yaml",
+                    lane_folder(&tmp_folder, lane_of(range))
+                );
+                fs::write(&path, "").map_err(SandboxError::TempSetup)?;
+                fs::set_permissions(&path, perm.clone()).map_err(SandboxError::TempSetup)?;
+            }
+        }
+        Ok(())
+    };
+    setup()?;
+
+    let mut results: Vec<SandboxResult> = vec![];
+    let mut wall_time = Duration::ZERO;
+    let mut phase = None;
+    for stage in &mut stages {
+        // `None` in place of the results when the container was killed for running too long,
+        // and when `docker run` started, in epoch milliseconds, and how long it took
+        type LaneOutput = (
+            Result<Option<String>, SandboxError>,
+            String,
+            Option<String>,
+            u64,
+            Duration,
+        );
+        let before = config
+            .report_file_writes
+            .then(|| workspace::snapshot(Path::new(&tmp_folder)));
+        let spent: u64 = results.iter().map(|result| result.time).sum();
+        let over_budget = config.total_time_limit.is_some_and(|limit| spent > limit);
+        // Lanes starting with a command whose condition isn't met are never started, nor
+        // are any but teardown once the time budget is spent
+        let starts: Vec<bool> = stage
+            .iter()
+            .map(|(range, _)| {
+                let first = &commands[range.start];
+                (!over_budget || first.teardown)
+                    && first
+                        .run_if
+                        .as_ref()
+                        .is_none_or(|run_if| run_if.met(&results))
+            })
+            .collect();
+        // The stage's phase is that of the first user command starting in it
+        let stage_phase = stage
+            .iter()
+            .zip(&starts)
+            .find(|((range, _), starts)| **starts && !commands[range.start].teardown)
+            .map(|((range, _), _)| commands[range.start].phase());
+        if let Some(stage_phase) = stage_phase
+            && phase != Some(stage_phase)
+        {
+            phase = Some(stage_phase);
+            on_progress(Progress::Phase(stage_phase));
+        }
+        let outputs: Vec<Option<LaneOutput>> = thread::scope(|scope| {
+            let handles: Vec<_> = stage
+                .iter_mut()
+                .zip(&starts)
+                .map(|((range, command), starts)| {
+                    if !starts {
+                        return None;
+                    }
+                    let folder = lane_folder(&tmp_folder, lane_of(range));
+                    let name = container_name(&tmp_folder, lane_of(range));
+                    let timeout = run_time_limit(config, &commands[range.clone()]);
+                    let lane_commands = &commands[range.clone()];
+                    // Threads don't inherit the submission's span
+                    let span = Span::current();
+                    Some(scope.spawn(move || {
+                        let _entered = span.enter();
+                        let mount = acquire_mount(&MOUNTS, config);
+                        for cmd in lane_commands {
+                            info!(container = %name, command = %cmd.command, "launching command");
+                        }
+                        let launched_at = events::now_millis();
+                        let started = Instant::now();
+                        let output = output_with_timeout(&config.container_runtime, command, &name, timeout);
+                        let elapsed = started.elapsed();
+                        telemetry::docker_run_finished(elapsed);
+                        let state = config
+                            .inspects_containers()
+                            .then(|| container_state(config, &name))
+                            .flatten();
+                        drop(mount);
+                        match &output {
+                            Ok(Some(output)) => {
+                                info!(container = %name, code = ?output.status.code(), "docker exited")
+                            }
+                            Ok(None) => warn!(container = %name, "container killed after timeout"),
+                            Err(e) => warn!(container = %name, error = %e, "running docker failed"),
+                        }
+                        let output = match output {
+                            Ok(Some(output)) => Ok(output),
+                            Ok(None) => {
+                                return (Ok(None), String::new(), state, launched_at, elapsed);
+                            }
+                            Err(e) => Err(e),
+                        };
+                        if let Some(e) = docker_unavailable(&output) {
+                            return (Err(e), String::new(), state, launched_at, elapsed);
+                        }
+                        let status = output.as_ref().ok().map(|output| output.status);
+                        // Without `-d`, the container's stderr is the executor's own
+                        let stderr = output
+                            .map(|output| String::from_utf8_lossy(&output.stderr).into_owned())
+                            .unwrap_or_default();
+                        let contents = fs::read_to_string(format!("{}/results.yaml", folder)).ok();
+                        let results = lane_results(contents, status, &stderr);
+                        (results, stderr, state, launched_at, elapsed)
+                    }))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.map(|h| h.join().unwrap()))
+                .collect()
+        });
+        let finished = results.len();
+        for ((range, _), output) in stage.iter().zip(outputs) {
+            let Some((output, stderr, state, launched_at, elapsed)) = output else {
+                let skipped = if over_budget {
+                    budget_exceeded_result()
+                } else {
+                    condition_skipped_result()
+                };
+                results.extend(vec![skipped; range.len()]);
+                continue;
+            };
+            wall_time += elapsed;
+            let timeout = run_time_limit(config, &commands[range.clone()]);
+            let oom_killed = state.as_deref() == Some("OOMKilled");
+            let parsed = match output {
+                // The executor was killed along with the program
+                Err(SandboxError::ResultsMissing | SandboxError::DockerExited(..))
+                    if oom_killed =>
+                {
+                    Ok(vec![SandboxResult::default(); range.len()])
+                }
+                output => output.and_then(|output| match output {
+                    Some(output) => {
+                        let lane = &commands[range.clone()];
+                        let runs = expand_runs(lane);
+                        parse_results(&output, runs.len(), config.validate_results).map(
+                            |mut parsed| {
+                                if config.reports_commands() {
+                                    let boot = boot_millis();
+                                    for result in &mut parsed {
+                                        take_report(boot, result);
+                                    }
+                                }
+                                collapse_runs(lane, fill_skipped(&runs, parsed))
+                            },
+                        )
+                    }
+                    None => Ok(vec![timed_out_result(timeout); range.len()]),
+                }),
+            };
+            let parsed = parsed
+                .map(|mut parsed| {
+                    if oom_killed {
+                        mark_oom_killed(&mut parsed);
+                    }
+                    for result in &mut parsed {
+                        result.started_at.get_or_insert(launched_at);
+                        result
+                            .finished_at
+                            .get_or_insert(launched_at + elapsed.as_millis() as u64);
+                    }
+                    if config.resource_summary {
+                        let folder = lane_folder(&tmp_folder, lane_of(range));
+                        let summary = fs::read_to_string(format!("{}/resources.yaml", folder))
+                            .unwrap_or_default();
+                        attach_resources(&commands[range.clone()], &summary, &mut parsed);
+                    }
+                    if config.executor_stderr
+                        && let Some(first) = parsed.first_mut()
+                    {
+                        first.executor_stderr = Some(stderr.clone());
+                    }
+                    if config.container_state {
+                        for result in &mut parsed {
+                            result.container_state.clone_from(&state);
+                        }
+                    }
+                    if let Some(factor) = config.time_factor {
+                        normalize_times(factor, &mut parsed);
+                    }
+                    parsed
+                })
+                .map_err(|e| with_executor_stderr(config, e, &stderr));
+            results.extend(parsed?);
+        }
+        let end = results.len().min(commands.len());
+        if let Some(before) = &before
+            && end > finished
+        {
+            let after = workspace::snapshot(Path::new(&tmp_folder));
+            results[end - 1].files_written = Some(workspace::changed_files(before, &after));
+        }
+        annotate_results(
+            config,
+            &commands[finished..end],
+            &mut results[finished..end],
+        );
+        // Updates get each result as it's returned, the teardown's aside
+        let user_end = end.min(user_commands);
+        if let Some(limit) = config.total_time_limit {
+            apply_total_time_limit(limit, &mut results[..user_end]);
+        }
+        if finished < user_end {
+            finish_results(
+                config,
+                form_data,
+                &commands[finished..user_end],
+                &mut results[finished..user_end],
+            )?;
+        }
+        for (index, result) in results
+            .iter()
+            .enumerate()
+            .take(user_commands)
+            .skip(finished)
+        {
+            on_progress(Progress::Result(index, result));
+        }
+    }
+    drop(guard);
+    take_teardown(&mut commands, &mut results, user_commands);
+    for result in &results {
+        telemetry::result_recorded(format!("{:?}", result.state));
+    }
+    Ok(SandboxRun {
+        results,
+        wall_time_ms: wall_time.as_millis() as u64,
+    })
+}
+
+#[cfg(test)]
+mod service_test {
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn docker_args(config: &SandboxConfig, form_data: &FormData) -> Vec<String> {
+        docker_command(config, form_data, &form_data.commands, "tmp", None)
+            .unwrap()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    // `command` with the limits most tests run under
+    fn cmd(command: &str, args: &[&str]) -> CMD {
+        CMD {
+            command: command.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            config: Config {
+                memory_reserved: 4096000,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // Results as the executor reports them in `yaml`, annotated for `commands`
+    fn annotated(config: &SandboxConfig, commands: &[CMD], yaml: &str) -> Vec<SandboxResult> {
+        let mut results: Vec<SandboxResult> = serde_yaml::from_str(yaml).unwrap();
+        annotate_results(config, commands, &mut results);
+        results
+    }
+
+    #[test]
+    fn gcc_version() {
+        let commands = vec![CMD {
+            command: "gcc".to_string(),
+            args: vec!["--version".to_string()],
+            input: "".to_string(),
+            config: Config {
+                time_limit: 1,
+                time_reserved: 1,
+                memory_limit: 256000,
+                memory_reserved: 4096000,
+                large_stack: false,
+                output_limit: 0,
+                process_limit: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        assert_eq!(
+            results.unwrap()[0].stdout,
+            "gcc (GCC) 14.2.0\nCopyright (C) 2024 Free Software Foundation, Inc.\nThis is free software; see the source for copying conditions.  There is NO\nwarranty; not even for MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.\n\n"
+        )
+    }
+
+    #[test]
+    fn buffered_output_survives_kill() {
+        let program = CMD {
+            command: "bash".to_string(),
+            args: vec![
+                "-c".to_string(),
+                r#"echo '#include <stdio.h>
+int main() {
+    printf("partial\n");
+    for (;;) {}
+}' > main.c && gcc main.c -o main"#
+                    .to_string(),
+            ],
+            ..Default::default()
+        };
+        let run = cmd("./main", &[]);
+        let stdout = |buffering: Buffering| {
+            let results = sandbox_service(
+                &SandboxConfig {
+                    output_buffering: buffering,
+                    ..Default::default()
+                },
+                &FormData {
+                    commands: vec![program.clone(), run.clone()],
+                    image: "gcc:14.2",
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(results[1].state, ExitState::TimeLimitExceeded);
+            results[1].stdout.clone()
+        };
+        assert_eq!(stdout(Buffering::Program), "");
+        assert_eq!(stdout(Buffering::Line), "partial\n");
+        assert_eq!(stdout(Buffering::Unbuffered), "partial\n");
+    }
+
+    #[test]
+    fn buffering_wraps_command() {
+        let cmd = CMD {
+            command: "./main".to_string(),
+            args: vec!["--fast".to_string()],
+            ..Default::default()
+        };
+        let config = SandboxConfig::default();
+        assert_eq!(cmd.for_executor(&config, 0).command, "./main");
+        let line = SandboxConfig {
+            output_buffering: Buffering::Line,
+            ..Default::default()
+        };
+        let wrapped = cmd.for_executor(&line, 0);
+        assert_eq!(wrapped.command, "stdbuf");
+        assert_eq!(wrapped.args, ["-oL", "./main", "--fast"]);
+        let unbuffered = CMD {
+            buffering: Some(Buffering::Unbuffered),
+            ..cmd.clone()
+        };
+        assert_eq!(unbuffered.for_executor(&config, 0).args[0], "-o0");
+        let program = CMD {
+            buffering: Some(Buffering::Program),
+            ..cmd
+        };
+        assert_eq!(program.for_executor(&line, 0).command, "./main");
+        assert_eq!("Line".parse(), Ok(Buffering::Line));
+        assert!("line".parse::<Buffering>().is_err());
+    }
+
+    #[test]
+    fn stderr_merged_per_command() {
+        let cmd = |stderr_to_stdout| CMD {
+            command: "bash".to_string(),
+            args: vec!["-c".to_string(), "echo out; echo err >&2".to_string()],
+            stderr_to_stdout,
+            ..Default::default()
+        };
+        let merged = cmd(true).for_executor(&SandboxConfig::default(), 0);
+        assert_eq!(merged.command, "sh");
+        assert_eq!(
+            merged.args,
+            [
+                "-c",
+                "exec \"$0\" \"$@\" 2>&1",
+                "bash",
+                "-c",
+                "echo out; echo err >&2"
+            ]
+        );
+        let line = SandboxConfig {
+            output_buffering: Buffering::Line,
+            ..Default::default()
+        };
+        assert_eq!(
+            cmd(true).for_executor(&line, 0).args[2..4],
+            ["stdbuf", "-oL"]
+        );
+        assert_eq!(
+            cmd(false)
+                .for_executor(&SandboxConfig::default(), 0)
+                .command,
+            "bash"
+        );
+
+        let form_data = FormData {
+            commands: vec![cmd(true), cmd(false)],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let results = sandbox_service(&SandboxConfig::default(), &form_data).unwrap();
+        assert_eq!(results[0].stdout, "out\nerr\n");
+        assert_eq!(results[0].stderr, "");
+        assert_eq!(results[1].stdout, "out\n");
+        assert_eq!(results[1].stderr, "err\n");
+    }
+
+    #[test]
+    fn stopped_on_output_match() {
+        let cmd = CMD {
+            command: "bash".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "read name; echo \"hello $name\"; echo DONE; sleep 30; echo late".to_string(),
+            ],
+            input: "runner\n".to_string(),
+            stop_on_output: Some("DONE".to_string()),
+            ..Default::default()
+        };
+        let wrapped = cmd.for_executor(&SandboxConfig::default(), 0);
+        assert_eq!(wrapped.command, "sh");
+        assert_eq!(wrapped.stop_on_output, None);
+        assert_eq!(wrapped.args[2..4], ["DONE", "bash"]);
+
+        // The wrapper is plain `sh`, so it behaves the same on the host
+        let start = Instant::now();
+        let mut child = Command::new(&wrapped.command)
+            .args(&wrapped.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(cmd.input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "hello runner\nDONE\n"
+        );
+        // Without a match the program's own exit status is kept
+        let failing = CMD {
+            args: vec!["-c".to_string(), "echo nothing; exit 3".to_string()],
+            ..cmd.clone()
+        }
+        .for_executor(&SandboxConfig::default(), 0);
+        let status = Command::new(&failing.command)
+            .args(&failing.args)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        let form_data = FormData {
+            commands: vec![cmd],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let results = sandbox_service(&SandboxConfig::default(), &form_data).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(20));
+        assert_eq!(results[0].state, ExitState::Success);
+        assert_eq!(results[0].stdout, "hello runner\nDONE\n");
+    }
+
+    #[test]
+    fn c_a_add_b() {
+        let commands = vec![
+            CMD {
+                command: "bash".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    r#"echo '#include <stdio.h>
+int main() {
+    int a, b;
+    scanf("%d %d", &a, &b);
+    printf("%d + %d = %d\n", a, b, a + b);
+}' > main.c"#
+                        .to_string(),
+                ],
+                input: "".to_string(),
+                config: Config {
+                    time_limit: 1,
+                    time_reserved: 1,
+                    memory_limit: 256000,
+                    memory_reserved: 4096000,
+                    large_stack: false,
+                    output_limit: 0,
+                    process_limit: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            CMD {
+                command: "gcc".to_string(),
+                args: vec!["main.c".to_string(), "-o".to_string(), "main".to_string()],
+                input: "".to_string(),
+                config: Config {
+                    time_limit: 1,
+                    time_reserved: 1,
+                    memory_limit: 256000,
+                    memory_reserved: 4096000,
+                    large_stack: false,
+                    output_limit: 0,
+                    process_limit: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            CMD {
+                command: "./main".to_string(),
+                args: vec![],
+                input: "1 2".to_string(),
+                config: Config {
+                    time_limit: 1,
+                    time_reserved: 1,
+                    memory_limit: 256000,
+                    memory_reserved: 4096000,
+                    large_stack: false,
+                    output_limit: 0,
+                    process_limit: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        assert_eq!(results.unwrap()[2].stdout, "1 + 2 = 3\n")
+    }
+
+    #[test]
+    fn cpp_a_add_b() {
+        let commands = vec![
+            CMD {
+                command: "bash".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    r#"echo '#include <iostream>
+using namespace std;
+int main() {
+    int a, b;
+    cin >> a >> b;
+    cout << a << " + " << b << " = " << a + b << endl;
+}' > main.cpp"#
+                        .to_string(),
+                ],
+                input: "".to_string(),
+                config: Config {
+                    time_limit: 1,
+                    time_reserved: 1,
+                    memory_limit: 256000,
+                    memory_reserved: 4096000,
+                    large_stack: false,
+                    output_limit: 0,
+                    process_limit: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            CMD {
+                command: "g++".to_string(),
+                args: vec!["main.cpp".to_string(), "-o".to_string(), "main".to_string()],
+                input: "".to_string(),
+                config: Config {
+                    time_limit: 1,
+                    time_reserved: 1,
+                    memory_limit: 256000,
+                    memory_reserved: 4096000,
+                    large_stack: false,
+                    output_limit: 0,
+                    process_limit: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            CMD {
+                command: "./main".to_string(),
+                args: vec![],
+                input: "1 2".to_string(),
+                config: Config {
+                    time_limit: 1,
+                    time_reserved: 1,
+                    memory_limit: 256000,
+                    memory_reserved: 4096000,
+                    large_stack: false,
+                    output_limit: 0,
+                    process_limit: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        assert_eq!(results.unwrap()[2].stdout, "1 + 2 = 3\n")
+    }
+
+    #[test]
+    fn java_version() {
+        let commands = vec![CMD {
+            command: "java".to_string(),
+            args: vec!["--version".to_string()],
+            input: "".to_string(),
+            config: Config {
+                time_limit: 1,
+                time_reserved: 1,
+                memory_limit: 256000,
+                memory_reserved: 6144000,
+                large_stack: false,
+                output_limit: 0,
+                process_limit: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "openjdk:21",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        assert_eq!(
+            results.unwrap()[0].stdout,
+            "openjdk 21 2023-09-19\nOpenJDK Runtime Environment (build 21+35-2513)\nOpenJDK 64-Bit Server VM (build 21+35-2513, mixed mode, sharing)\n"
+        )
+    }
+
+    #[test]
+    fn java_a_add_b() {
+        let commands = vec![
+            CMD {
+                command: "bash".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    r#"echo 'import java.util.Scanner;
+public class Main {
+    public static void main(String[] args) {
+        Scanner sc = new Scanner(System.in);
+        int a = sc.nextInt();
+        int b = sc.nextInt();
+        System.out.println(a + " + " + b + " = " + (a + b));
+    }
+}' > Main.java"#
+                        .to_string(),
+                ],
+                input: "".to_string(),
+                config: Config {
+                    time_limit: 1,
+                    time_reserved: 1,
+                    memory_limit: 256000,
+                    memory_reserved: 6144000,
+                    large_stack: false,
+                    output_limit: 0,
+                    process_limit: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            CMD {
+                command: "javac".to_string(),
+                args: vec!["Main.java".to_string()],
+                input: "".to_string(),
+                config: Config {
+                    time_limit: 1,
+                    time_reserved: 1,
+                    memory_limit: 256000,
+                    memory_reserved: 6144000,
+                    large_stack: false,
+                    output_limit: 0,
+                    process_limit: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            CMD {
+                command: "java".to_string(),
+                args: vec!["Main.java".to_string()],
+                input: "1 2".to_string(),
+                config: Config {
+                    time_limit: 1,
+                    time_reserved: 1,
+                    memory_limit: 256000,
+                    memory_reserved: 6144000,
+                    large_stack: false,
+                    output_limit: 0,
+                    process_limit: 0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "openjdk:21",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        assert_eq!(results.unwrap()[2].stdout, "1 + 2 = 3\n")
+    }
+
+    #[test]
+    fn robust0() {
+        let commands = vec![CMD {
+            command: "reboot".to_string(),
+            args: vec![],
+            input: "".to_string(),
+            config: Config {
+                time_limit: 1,
+                time_reserved: 1,
+                memory_limit: 256000,
+                memory_reserved: 6144000,
+                large_stack: false,
+                output_limit: 0,
+                process_limit: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        assert_eq!(
+            format!("{:?}", results.unwrap()),
+            r#"[SandboxResult { state: OtherError, stdout: "", stderr: "Error occurred", time: 0, memory: 0, phase: Run, input_fully_consumed: None, compressed: false, signal: None, exit_code: None, possibly_blocked_syscalls: None, verdict: None, limit_hit: None, rusage: None, started_at: None, finished_at: None, resources: None, executor_stderr: None, stdout_bytes: None, stderr_bytes: None, stdout_lines: None, container_state: None, raw_time: None, files_written: None, timing: None, effective_limits: None }]"#
+        );
+    }
+
+    #[test]
+    fn robust1() {
+        let commands = vec![CMD {
+            command: "rm".to_string(),
+            args: vec!["-rf".to_string(), "/*".to_string()],
+            input: "".to_string(),
+            config: Config {
+                time_limit: 1,
+                time_reserved: 1,
+                memory_limit: 256000,
+                memory_reserved: 6144000,
+                large_stack: false,
+                output_limit: 0,
+                process_limit: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+    }
+
+    #[test]
+    fn cpu_ulimit_flag() {
+        let commands = vec![CMD {
+            config: Config {
+                time_limit: 2,
+                memory_reserved: 4096000,
+                ..Default::default()
+            },
+            ..cmd("./main", &[])
+        }];
+        let form_data = FormData {
+            commands,
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let config = SandboxConfig {
+            cpu_ulimit: true,
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["--ulimit", "cpu=3:4"]));
+
+        let args = docker_args(&SandboxConfig::default(), &form_data);
+        assert!(!args.contains(&"--ulimit".to_string()));
+
+        // A CPU time limit is enforced through the ulimit alone, so it turns it on
+        let mut form_data = form_data;
+        form_data.commands[0].config.cpu_time_limit = Some(1);
+        let args = docker_args(&SandboxConfig::default(), &form_data);
+        assert!(args.windows(2).any(|w| w == ["--ulimit", "cpu=2:3"]));
+    }
+
+    #[test]
+    fn pids_limit_flag() {
+        let cmd = |process_limit| CMD {
+            command: "./main".to_string(),
+            config: Config {
+                process_limit,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut form_data = FormData {
+            commands: vec![cmd(16), cmd(4)],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let args = docker_args(&SandboxConfig::default(), &form_data);
+        let expected = (16 + PIDS_HEADROOM).to_string();
+        assert!(args.windows(2).any(|w| w == ["--pids-limit", &expected]));
+
+        form_data.commands.push(cmd(0));
+        let args = docker_args(&SandboxConfig::default(), &form_data);
+        assert!(!args.contains(&"--pids-limit".to_string()));
+    }
+
+    #[test]
+    fn fork_bomb_contained() {
+        let commands = vec![CMD {
+            command: "bash".to_string(),
+            args: vec!["-c".to_string(), ":(){ :|:& };:".to_string()],
+            config: Config {
+                process_limit: 16,
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let form_data = FormData {
+            commands,
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        let results = sandbox_service(&SandboxConfig::default(), &form_data).unwrap();
+        assert_ne!(results[0].state, ExitState::Success);
+        assert!(started.elapsed() < std::time::Duration::from_secs(30));
+    }
+
+    #[test]
+    fn memlock_ulimit_flag() {
+        let mut form_data = FormData {
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let memlock = |config: &SandboxConfig, form_data: &FormData| {
+            docker_command(config, form_data, &form_data.commands, "tmp", None).map(|command| {
+                command
+                    .get_args()
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect::<Vec<String>>()
+                    .windows(2)
+                    .find(|w| w[0] == "--ulimit" && w[1].starts_with("memlock="))
+                    .map(|w| w[1].clone())
+            })
+        };
+        assert_eq!(memlock(&SandboxConfig::default(), &form_data), Ok(None));
+
+        let config = SandboxConfig {
+            memlock: Some(65536),
+            image_manifest: images::parse_image_manifest(
+                "- image: \"gcc:*\"\n  memlock: 8388608\n",
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(
+            memlock(&config, &form_data),
+            Ok(Some("memlock=8388608:8388608".to_string()))
+        );
+        form_data.memlock = Some(1048576);
+        assert_eq!(
+            memlock(&config, &form_data),
+            Ok(Some("memlock=1048576:1048576".to_string()))
+        );
+        form_data.image = "python:3.12";
+        assert!(memlock(&config, &form_data).is_err());
+        assert!(memlock(&SandboxConfig::default(), &form_data).is_err());
+    }
+
+    #[test]
+    fn cpu_ulimit_kills_busy_loop() {
+        // The ulimit, at 2 seconds, fires before the executor's own limit of 4
+        let commands = vec![CMD {
+            config: Config {
+                time_limit: 3,
+                cpu_time_limit: Some(1),
+                memory_reserved: 4096000,
+                ..Default::default()
+            },
+            ..cmd("bash", &["-c", "while :; do :; done"])
+        }];
+        let config = SandboxConfig {
+            cpu_ulimit: true,
+            command_reports: true,
+            ..Default::default()
+        };
+        let results = sandbox_service(
+            &config,
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        let result = results.unwrap().remove(0);
+        assert_eq!(result.state, ExitState::RuntimeError);
+        assert!(
+            matches!(result.signal, Some(SIGXCPU | SIGKILL)),
+            "{:?}",
+            result
+        );
+        if result.signal == Some(SIGXCPU) {
+            assert_eq!(result.limit_hit, Some(LimitKind::Time));
+        }
+    }
+
+    #[test]
+    fn compile_and_run_phases() {
+        let commands = vec![
+            CMD {
+                command: "gcc".to_string(),
+                args: vec!["main.c".to_string(), "-o".to_string(), "main".to_string()],
+                ..Default::default()
+            },
+            CMD {
+                command: "./main".to_string(),
+                input: "1 2".to_string(),
+                ..Default::default()
+            },
+            CMD {
+                command: "bash".to_string(),
+                args: vec!["-c".to_string(), "diff out.txt ans.txt".to_string()],
+                phase: Some(Phase::Check),
+                ..Default::default()
+            },
+        ];
+        let results = annotated(
+            &SandboxConfig::default(),
+            &commands,
+            r#"
+- state: Success
+  stdout: ''
+  stderr: ''
+  time: 120
+  memory: 20480
+- state: Success
+  stdout: "1 + 2 = 3\n"
+  stderr: ''
+  time: 1
+  memory: 1024
+- state: Success
+  stdout: ''
+  stderr: ''
+  time: 1
+  memory: 1024
+"#,
+        );
+        let phases: Vec<_> = results.iter().map(|result| result.phase).collect();
+        assert_eq!(phases, [Phase::Build, Phase::Run, Phase::Check]);
+
+        // Tools that can also run are only guessed to build when asked to
+        let guessed = |command: &str, args: &[&str]| cmd(command, args).phase();
+        assert_eq!(guessed("cargo", &["build", "--release"]), Phase::Build);
+        assert_eq!(guessed("cargo", &["run"]), Phase::Run);
+        assert_eq!(guessed("go", &["run", "main.go"]), Phase::Run);
+        assert_eq!(guessed("dotnet", &["run"]), Phase::Run);
+        assert_eq!(guessed("make", &["-j4"]), Phase::Build);
+        assert_eq!(guessed("make", &["run"]), Phase::Run);
+        assert_eq!(
+            guessed("bash", &["-c", "javac Main.java && java Main"]),
+            Phase::Run
+        );
+        // A declared phase always wins
+        let declared = CMD {
+            phase: Some(Phase::Build),
+            ..cmd("bash", &["-c", "javac Main.java && java Main"])
+        };
+        assert_eq!(declared.phase(), Phase::Build);
+    }
+
+    #[test]
+    fn crlf_normalization() {
+        let commands = vec![CMD {
+            input: "1 2\r\n3 4\r\n".to_string(),
+            ..cmd("cat", &[])
+        }];
+        let expected = "1 2\n3 4\n";
+        let mut form_data = FormData {
+            commands,
+            image: "gcc:14.2",
+            normalize_line_endings: true,
+            ..Default::default()
+        };
+        let results = sandbox_service(&SandboxConfig::default(), &form_data);
+        assert_eq!(results.unwrap()[0].stdout, expected);
+
+        form_data.normalize_line_endings = false;
+        let results = sandbox_service(&SandboxConfig::default(), &form_data);
+        assert_ne!(results.unwrap()[0].stdout, expected);
+    }
+
+    #[test]
+    fn normalize_crlf_and_cr() {
+        assert_eq!(normalize_line_endings("a\r\nb\rc\n"), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn platform_flag() {
+        let mut form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let args = docker_args(&SandboxConfig::default(), &form_data);
+        assert!(!args.contains(&"--platform".to_string()));
+
+        let config = SandboxConfig {
+            platform: Some("linux/amd64".to_string()),
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["--platform", "linux/amd64"]));
+
+        form_data.platform = Some("linux/arm64/v8".to_string());
+        let args = docker_args(&config, &form_data);
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["--platform", "linux/arm64/v8"])
+        );
+
+        form_data.platform = Some("linux/amd64 --privileged".to_string());
+        assert!(docker_command(&config, &form_data, &form_data.commands, "tmp", None).is_err());
+    }
+
+    #[test]
+    fn low_free_memory_rejected() {
+        let form_data = FormData {
+            commands: vec![CMD {
+                config: Config {
+                    memory_reserved: 4096000,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let config = SandboxConfig {
+            min_free_memory: Some(512000),
+            ..Default::default()
+        };
+        assert!(check_free_memory(&config, &form_data, 1024000).is_err());
+        assert!(check_free_memory(&config, &form_data, 8192000).is_ok());
+        assert!(check_free_memory(&SandboxConfig::default(), &form_data, 1024000).is_ok());
+    }
+
+    #[test]
+    fn partially_read_input() {
+        let commands = vec![CMD {
+            input: "1 2 3 4 5 6 7 8 9 10\n".repeat(10000),
+            ..cmd("head", &["-c", "1"])
+        }];
+        let config = SandboxConfig {
+            command_reports: true,
+            ..Default::default()
+        };
+        let results = sandbox_service(
+            &config,
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        assert_eq!(results.unwrap()[0].input_fully_consumed, Some(false));
+    }
+
+    #[test]
+    fn report_cut_from_stderr() {
+        let config = SandboxConfig {
+            command_reports: true,
+            ..Default::default()
+        };
+        let wrapped = cmd("./main", &["--fast"]).for_executor(&config, 3);
+        assert_eq!(wrapped.command, "sh");
+        assert_eq!(wrapped.args[2..], [REPORT_MARKER, "3", "./main", "--fast"]);
+
+        let mut result = SandboxResult {
+            stderr: format!("warning\n\n{} status=0 unread=1\n", REPORT_MARKER),
+            ..Default::default()
+        };
+        take_report(None, &mut result);
+        assert_eq!(result.stderr, "warning\n");
+        assert_eq!(result.input_fully_consumed, Some(false));
+        assert_eq!(result.signal, None);
+        let mut killed_by_sigsys = SandboxResult {
+            stderr: format!(
+                "\n{} status=159 signal=31 started=120.50 finished=120.75 unread=0\n",
+                REPORT_MARKER
+            ),
+            ..Default::default()
+        };
+        take_report(Some(1_700_000_000_000), &mut killed_by_sigsys);
+        assert_eq!(killed_by_sigsys.rusage, None);
+        assert_eq!(killed_by_sigsys.signal, Some(seccomp::SIGSYS));
+        assert_eq!(killed_by_sigsys.started_at, Some(1_700_000_120_500));
+        assert_eq!(killed_by_sigsys.finished_at, Some(1_700_000_120_750));
+        // No signal has a number above 64, so `kill -l` names none for a status of 200
+        let mut exited = SandboxResult {
+            stderr: format!("\n{} status=200 signal= unread=0\n", REPORT_MARKER),
+            ..Default::default()
+        };
+        take_report(None, &mut exited);
+        assert_eq!((exited.exit_code, exited.signal), (Some(200), None));
+        let mut counted = SandboxResult {
+            stderr: format!(
+                "\n{} status=0 signal= minflt=120 majflt=1 utime=95 stime=3\n",
+                REPORT_MARKER
+            ),
+            ..Default::default()
+        };
+        take_report(None, &mut counted);
+        let rusage = counted.rusage.unwrap();
+        assert_eq!(
+            (rusage.minor_page_faults, rusage.major_page_faults),
+            (120, 1)
+        );
+        assert_eq!((rusage.user_time, rusage.system_time), (950, 30));
+        // Without a report, e.g. when the shell was killed too, stderr is left alone
+        let mut killed = SandboxResult {
+            stderr: "Killed\n".to_string(),
+            ..Default::default()
+        };
+        take_report(None, &mut killed);
+        assert_eq!(killed.stderr, "Killed\n");
+        assert_eq!(killed.input_fully_consumed, None);
+    }
+
+    #[test]
+    fn input_consumption_only_reported_with_input() {
+        let commands = vec![
+            CMD {
+                command: "./main".to_string(),
+                ..Default::default()
+            },
+            CMD {
+                command: "./main".to_string(),
+                input: "1 2".to_string(),
+                ..Default::default()
+            },
+        ];
+        let results = annotated(
+            &SandboxConfig::default(),
+            &commands,
+            r#"
+- state: Success
+  stdout: ''
+  stderr: ''
+  time: 1
+  memory: 1024
+  input_fully_consumed: true
+- state: Success
+  stdout: ''
+  stderr: ''
+  time: 1
+  memory: 1024
+  input_fully_consumed: false
+"#,
+        );
+        assert_eq!(results[0].input_fully_consumed, None);
+        assert_eq!(results[1].input_fully_consumed, Some(false));
+    }
+
+    #[test]
+    fn pull_policies() {
+        assert_eq!(PullPolicy::Always.should_pull("gcc:14.2", true), Ok(true));
+        assert_eq!(PullPolicy::Always.should_pull("gcc:14.2", false), Ok(true));
+        assert_eq!(
+            PullPolicy::IfNotPresent.should_pull("gcc:14.2", true),
+            Ok(false)
+        );
+        assert_eq!(
+            PullPolicy::IfNotPresent.should_pull("gcc:14.2", false),
+            Ok(true)
+        );
+        assert_eq!(PullPolicy::Never.should_pull("gcc:14.2", true), Ok(false));
+        assert!(PullPolicy::Never.should_pull("gcc:14.2", false).is_err());
+    }
+
+    #[cfg(feature = "docker-tests")]
+    #[test]
+    fn pull_policy_never_absent_image() {
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "supercode/not-a-real-image:0",
+            pull_policy: Some(PullPolicy::Never),
+            ..Default::default()
+        };
+        let error = ensure_image(&SandboxConfig::default(), &form_data).unwrap_err();
+        assert!(error.contains("pull policy is Never"));
+    }
+
+    #[cfg(feature = "docker-tests")]
+    #[test]
+    fn pull_policy_present_image() {
+        let mut form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "hello-world:latest",
+            pull_policy: Some(PullPolicy::Always),
+            ..Default::default()
+        };
+        assert!(ensure_image(&SandboxConfig::default(), &form_data).is_ok());
+        assert!(image_present(
+            &SandboxConfig::default(),
+            "hello-world:latest"
+        ));
+        form_data.pull_policy = Some(PullPolicy::Never);
+        assert!(ensure_image(&SandboxConfig::default(), &form_data).is_ok());
+        form_data.pull_policy = Some(PullPolicy::IfNotPresent);
+        assert!(ensure_image(&SandboxConfig::default(), &form_data).is_ok());
+    }
+
+    #[cfg(feature = "docker-tests")]
+    #[test]
+    fn pull_policy_if_not_present_absent_image() {
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "supercode/not-a-real-image:0",
+            pull_policy: Some(PullPolicy::IfNotPresent),
+            ..Default::default()
+        };
+        let error = ensure_image(&SandboxConfig::default(), &form_data).unwrap_err();
+        assert!(error.contains("Failed to pull image"));
+    }
+
+    #[test]
+    fn total_time_limit_skips_remaining_commands() {
+        let mut results: Vec<SandboxResult> = serde_yaml::from_str(
+            &"- state: Success\n  stdout: ok\n  stderr: ''\n  time: 400\n  memory: 1024\n"
+                .repeat(5),
+        )
+        .unwrap();
+        apply_total_time_limit(1000, &mut results);
+        let states: Vec<_> = results.iter().map(|result| &result.state).collect();
+        assert_eq!(
+            states,
+            [
+                &ExitState::Success,
+                &ExitState::Success,
+                &ExitState::TotalTimeLimitExceeded,
+                &ExitState::TotalTimeLimitExceeded,
+                &ExitState::TotalTimeLimitExceeded
+            ]
+        );
+        assert_eq!(results[2].stdout, "ok");
+        assert_eq!(results[3].stdout, "");
+        assert_eq!(results[4].time, 0);
+    }
+
+    #[test]
+    fn large_output_compressed() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let stdout = "1 + 2 = 3\n".repeat(1000);
+        let mut results: Vec<SandboxResult> = serde_yaml::from_str(&format!(
+            "- state: Success\n  stdout: {:?}\n  stderr: ''\n  time: 1\n  memory: 1024\n\
+             - state: Success\n  stdout: small\n  stderr: ''\n  time: 1\n  memory: 1024\n",
+            stdout
+        ))
+        .unwrap();
+        compress_large_outputs(1024, &mut results).unwrap();
+        assert!(results[0].compressed);
+        assert!(results[0].stdout.len() < stdout.len());
+        assert!(!results[1].compressed);
+        assert_eq!(results[1].stdout, "small");
+
+        let mut decoded = String::new();
+        GzDecoder::new(&BASE64_STANDARD.decode(&results[0].stdout).unwrap()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, stdout);
+    }
+
+    #[test]
+    fn sigsys_annotated_with_possibly_blocked_syscalls() {
+        let commands = vec![CMD {
+            command: "./main".to_string(),
+            ..Default::default()
+        }];
+        let config = SandboxConfig {
+            seccomp_profile: Some("/etc/supercode/seccomp.json".to_string()),
+            denied_syscalls: vec!["socket".to_string()],
+            ..Default::default()
+        };
+        let results = annotated(
+            &config,
+            &commands,
+            r#"
+- state: RuntimeError
+  stdout: ''
+  stderr: ''
+  time: 1
+  memory: 1024
+  signal: 31
+"#,
+        );
+        assert_eq!(
+            results[0].possibly_blocked_syscalls.as_deref(),
+            Some(&["socket".to_string()][..])
+        );
+
+        let form_data = FormData {
+            commands,
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["--security-opt", "seccomp=/etc/supercode/seccomp.json"])
+        );
+    }
+
+    #[test]
+    fn image_capability_grants() {
+        let config = SandboxConfig {
+            drop_capabilities: true,
+            image_capabilities: parse_image_capabilities(
+                "gcc:*=CAP_SYS_PTRACE; openjdk:21=IPC_LOCK,bad-cap;gcc:14.2=SYS_PTRACE,IPC_LOCK",
+            ),
+            ..Default::default()
+        };
+        let mut form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["--cap-drop", "ALL"]));
+        let added: Vec<_> = args
+            .windows(2)
+            .filter(|w| w[0] == "--cap-add")
+            .map(|w| w[1].as_str())
+            .collect();
+        assert_eq!(added, ["IPC_LOCK", "SYS_PTRACE"]);
+
+        form_data.image = "python:3.12";
+        let args = docker_args(&config, &form_data);
+        assert!(!args.contains(&"--cap-add".to_string()));
+    }
+
+    #[test]
+    fn comparator_chosen_by_image() {
+        let config = SandboxConfig {
+            image_manifest: images::parse_image_manifest(
+                "- image: \"python:*\"\n  comparator: Float\n",
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let judged = |image: &str, comparator: Option<Comparator>| {
+            let commands = [CMD {
+                expected_outputs: vec!["0.3\n".to_string()],
+                comparator,
+                ..Default::default()
+            }];
+            let mut results: Vec<SandboxResult> = serde_yaml::from_str(
+                "- {state: Success, stdout: \"0.30000000000000004\\n\", stderr: '', time: 0, memory: 1024}",
+            )
+            .unwrap();
+            judge_results(&commands, &mut results, config.comparator(image), false);
+            results[0].verdict
+        };
+        assert_eq!(judged("python:3.12", None), Some(Verdict::Accepted));
+        assert_eq!(judged("gcc:14.2", None), Some(Verdict::WrongAnswer));
+        assert_eq!(
+            judged("python:3.12", Some(Comparator::Exact)),
+            Some(Verdict::WrongAnswer)
+        );
+        assert_eq!(
+            judged("gcc:14.2", Some(Comparator::Float)),
+            Some(Verdict::Accepted)
+        );
+    }
+
+    #[test]
+    fn run_only_without_expected_output() {
+        let commands = [
+            CMD {
+                command: "./main".to_string(),
+                ..Default::default()
+            },
+            CMD {
+                command: "./main".to_string(),
+                expected_outputs: vec!["3\n".to_string()],
+                ..Default::default()
+            },
+        ];
+        let parsed = || {
+            parse_results(
+                "- {state: Success, stdout: \"3\\n\", stderr: '', time: 0, memory: 1024}\n\
+                 - {state: Success, stdout: \"3\\n\", stderr: '', time: 0, memory: 1024}\n",
+                2,
+                true,
+            )
+            .unwrap()
+        };
+        let mut results = parsed();
+        judge_results(&commands, &mut results, Comparator::Exact, true);
+        assert_eq!(results[0].verdict, Some(Verdict::NoExpectedOutput));
+        assert_eq!(results[1].verdict, Some(Verdict::Accepted));
+        // Not counted as a judged case
+        assert_eq!(summarize(&results), "AC 1/1 cases, 0s, 1MB");
+
+        let mut results = parsed();
+        judge_results(&commands, &mut results, Comparator::Exact, false);
+        assert_eq!(results[0].verdict, None);
+        let asked = [CMD {
+            comparator: Some(Comparator::Tokens),
+            ..Default::default()
+        }];
+        judge_results(&asked, &mut results, Comparator::Exact, false);
+        assert_eq!(results[0].verdict, Some(Verdict::NoExpectedOutput));
+    }
+
+    #[test]
+    fn second_accepted_answer_matches() {
+        let commands = vec![CMD {
+            command: "./main".to_string(),
+            expected_outputs: vec!["1 + 2 = 3\n".to_string(), "3\n".to_string()],
+            comparator: Some(Comparator::IgnoreTrailingWhitespace),
+            ..Default::default()
+        }];
+        let mut results: Vec<SandboxResult> = serde_yaml::from_str(
+            "- state: Success\n  stdout: \"3 \\n\"\n  stderr: ''\n  time: 1\n  memory: 1024\n",
+        )
+        .unwrap();
+        judge_results(&commands, &mut results, Comparator::Exact, false);
+        assert_eq!(results[0].verdict, Some(Verdict::Accepted));
+
+        results[0].stdout = "4\n".to_string();
+        judge_results(&commands, &mut results, Comparator::Exact, false);
+        assert_eq!(results[0].verdict, Some(Verdict::WrongAnswer));
+    }
+
+    #[test]
+    fn output_counts() {
+        let config = SandboxConfig {
+            output_counts: true,
+            ..Default::default()
+        };
+        let commands = vec![
+            CMD::default(),
+            CMD {
+                discard_output: true,
+                ..Default::default()
+            },
+        ];
+        let mut results = parse_results(
+            "- {state: Success, stdout: \"1\\n22\\n333\", stderr: \"warn\\n\", time: 0, memory: 1024}\n\
+             - {state: Success, stdout: \"a\\nb\\n\", stderr: '', time: 0, memory: 1024}\n",
+            2,
+            true,
+        )
+        .unwrap();
+        annotate_results(&config, &commands, &mut results);
+        discard_outputs(&commands, &mut results);
+        assert_eq!(results[0].stdout_bytes, Some(8));
+        assert_eq!(results[0].stderr_bytes, Some(5));
+        assert_eq!(results[0].stdout_lines, Some(3));
+        assert_eq!(results[0].stdout, "1\n22\n333");
+        // Counts survive discarding the output
+        assert_eq!(results[1].stdout, "");
+        assert_eq!(results[1].stdout_bytes, Some(4));
+        assert_eq!(results[1].stderr_bytes, Some(0));
+        assert_eq!(results[1].stdout_lines, Some(2));
+
+        // Counts from the executor are kept, and none are added when disabled
+        let output =
+            "- {state: Success, stdout: '', stderr: '', time: 0, memory: 1024, stdout_bytes: 10}\n";
+        let mut results = parse_results(output, 1, true).unwrap();
+        annotate_results(&config, &commands[..1], &mut results);
+        assert_eq!(results[0].stdout_bytes, Some(10));
+        assert_eq!(results[0].stdout_lines, Some(0));
+        let mut results = parse_results(output, 1, true).unwrap();
+        annotate_results(&SandboxConfig::default(), &commands[..1], &mut results);
+        assert_eq!(results[0].stdout_lines, None);
+    }
+
+    #[test]
+    fn one_line_summary() {
+        let commands = vec![
+            CMD {
+                command: "gcc".to_string(),
+                ..Default::default()
+            },
+            CMD {
+                expected_outputs: vec!["3\n".to_string()],
+                ..Default::default()
+            },
+            CMD {
+                expected_outputs: vec!["7\n".to_string()],
+                ..Default::default()
+            },
+            CMD {
+                expected_outputs: vec!["11\n".to_string()],
+                ..Default::default()
+            },
+        ];
+        let output = |third: &str| {
+            format!(
+                "- {{state: Success, stdout: '', stderr: '', time: 1, memory: 40000}}\n\
+                 - {{state: Success, stdout: \"3\\n\", stderr: '', time: 0, memory: 1024}}\n\
+                 - {{state: Success, stdout: \"7\\n\", stderr: '', time: 1, memory: 3900}}\n\
+                 - {}\n",
+                third
+            )
+        };
+        let summary = |third: &str| {
+            let mut results = parse_results(&output(third), 4, true).unwrap();
+            annotate_results(&SandboxConfig::default(), &commands, &mut results);
+            judge_results(&commands, &mut results, Comparator::Exact, false);
+            summarize(&results)
+        };
+        let correct = "{state: Success, stdout: \"11\\n\", stderr: '', time: 0, memory: 1024}";
+        let wrong = "{state: Success, stdout: \"10\\n\", stderr: '', time: 0, memory: 1024}";
+        let slow = "{state: TimeLimitExceeded, stdout: '', stderr: '', time: 2, memory: 1024}";
+        assert_eq!(summary(correct), "AC 3/3 cases, 2s, 40MB");
+        assert_eq!(summary(wrong), "WA 2/3 cases, 2s, 40MB");
+        assert_eq!(summary(slow), "TLE 2/2 cases, 4s, 40MB");
+
+        let mut build_failed = parse_results(
+            "- {state: RuntimeError, stdout: '', stderr: 'error', time: 0, memory: 2048}\n",
+            1,
+            true,
+        )
+        .unwrap();
+        annotate_results(&SandboxConfig::default(), &commands[..1], &mut build_failed);
+        assert_eq!(summarize(&build_failed), "CE 0s, 2MB");
+
+        let config = SandboxConfig {
+            response_summary: true,
+            ..Default::default()
+        };
+        let form_data = FormData::default();
+        let response = ResponseData::new(&config, &form_data, build_failed.clone());
+        assert_eq!(response.summary.as_deref(), Some("CE 0s, 2MB"));
+        let response = ResponseData::new(&SandboxConfig::default(), &form_data, build_failed);
+        assert_eq!(response.summary, None);
+    }
+
+    #[test]
+    fn log_driver_default_none() {
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let args = docker_args(&SandboxConfig::default(), &form_data);
+        assert!(args.windows(2).any(|w| w == ["--log-driver", "none"]));
+        let config = SandboxConfig {
+            log_driver: Some("local".to_string()),
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["--log-driver", "local"]));
+        let config = SandboxConfig {
+            log_driver: None,
+            ..Default::default()
+        };
+        assert!(!docker_args(&config, &form_data).contains(&"--log-driver".to_string()));
+    }
+
+    #[test]
+    fn cgroup_parent_per_tenant() {
+        let mut form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        assert!(
+            !docker_args(&SandboxConfig::default(), &form_data)
+                .contains(&"--cgroup-parent".to_string())
+        );
+        let config = SandboxConfig {
+            cgroup_parent: Some("supercode-{tenant}.slice".to_string()),
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["--cgroup-parent", "supercode-default.slice"])
+        );
+        form_data.tenant = Some("acme corp".to_string());
+        let args = docker_args(&config, &form_data);
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["--cgroup-parent", "supercode-acme_corp.slice"])
+        );
+
+        assert!(validate_cgroup_parent("/docker/sandbox").is_ok());
+        for invalid in ["", "/", "../host", "docker//sandbox", "slice;rm"] {
+            assert!(validate_cgroup_parent(invalid).is_err(), "{:?}", invalid);
+        }
+        let config = SandboxConfig {
+            cgroup_parent: Some("../{tenant}".to_string()),
+            ..Default::default()
+        };
+        assert!(docker_flags(&config, &form_data).is_err());
+    }
+
+    #[test]
+    fn registry_prefix_and_root() {
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        assert!(
+            docker_args(&SandboxConfig::default(), &form_data).contains(&"gcc:14.2".to_string())
+        );
+        let config = SandboxConfig {
+            registry_prefix: Some("registry.example.com/mirror/".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            docker_args(&config, &form_data)
+                .contains(&"registry.example.com/mirror/gcc:14.2".to_string())
+        );
+        assert_eq!(
+            docker_flags(&config, &form_data).unwrap(),
+            docker_flags(&SandboxConfig::default(), &form_data).unwrap()
+        );
+        for image in ["ghcr.io/acme/gcc:14", "localhost/gcc", "localhost:5000/gcc"] {
+            assert_eq!(config.image_ref(image), image);
+        }
+        assert_eq!(
+            config.image_ref("library/gcc"),
+            "registry.example.com/mirror/library/gcc"
+        );
+
+        let config = SandboxConfig {
+            sandbox_root: "/nonexistent/sandbox".to_string(),
+            ..Default::default()
+        };
+        let error = sandbox_service(&config, &form_data).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No sandbox found in /nonexistent/sandbox"
+        );
+    }
+
+    #[test]
+    fn times_normalized_by_host_factor() {
+        let mut results = parse_results(
+            r#"
+- {state: Success, stdout: '', stderr: '', time: 3, memory: 1024}
+- {state: Success, stdout: '', stderr: '', time: 0, memory: 1024}
+- {state: TimeLimitExceeded, stdout: '', stderr: '', time: 10, memory: 1024}
+"#,
+            3,
+            true,
+        )
+        .unwrap();
+        normalize_times(2.0, &mut results);
+        let times: Vec<(u64, Option<u64>)> = results.iter().map(|r| (r.time, r.raw_time)).collect();
+        assert_eq!(times, [(2, Some(3)), (0, Some(0)), (5, Some(10))]);
+        // Always scaled from the raw time, never twice
+        normalize_times(0.5, &mut results);
+        let times: Vec<(u64, Option<u64>)> = results.iter().map(|r| (r.time, r.raw_time)).collect();
+        assert_eq!(times, [(6, Some(3)), (0, Some(0)), (20, Some(10))]);
+        let serialized = serde_yaml::to_string(&results[0]).unwrap();
+        assert!(serialized.contains("time: 6") && serialized.contains("raw_time: 3"));
+    }
+
+    #[test]
+    fn exit_codes() {
+        let commands = vec![CMD::default(); 4];
+        let mut results = parse_results(
+            r#"
+- {state: Success, stdout: '', stderr: '', time: 0, memory: 1024}
+- {state: RuntimeError, stdout: '', stderr: '', time: 0, memory: 1024, signal: 11}
+- {state: RuntimeError, stdout: '', stderr: "\n@sandbox-report status=3 unread=0\n", time: 0, memory: 1024}
+- {state: RuntimeError, stdout: '', stderr: '', time: 0, memory: 1024}
+"#,
+            4,
+            true,
+        )
+        .unwrap();
+        for result in &mut results {
+            take_report(None, result);
+        }
+        annotate_results(&SandboxConfig::default(), &commands, &mut results);
+        let codes: Vec<Option<i32>> = results.iter().map(|result| result.exit_code).collect();
+        assert_eq!(codes, [Some(0), Some(139), Some(3), None]);
+
+        use std::os::unix::process::ExitStatusExt;
+        let failed = Some(ExitStatus::from_raw(125 << 8));
+        let error = lane_results(None, failed, "docker: invalid reference format\n").unwrap_err();
+        assert!(matches!(error, SandboxError::DockerExited(Some(125), _)));
+        assert_eq!(
+            error.to_string(),
+            "docker run exited with 125: docker: invalid reference format"
+        );
+        let exited = Some(ExitStatus::from_raw(0));
+        assert!(matches!(
+            lane_results(Some(String::new()), exited, ""),
+            Err(SandboxError::ResultsMissing)
+        ));
+        // Results win over docker's status, which is the executor's own
+        let results = lane_results(Some("[]".to_string()), failed, "");
+        assert_eq!(results.unwrap().as_deref(), Some("[]"));
+    }
+
+    #[test]
+    fn file_writes_reported() {
         let commands = vec![
             CMD {
                 command: "bash".to_string(),
-                args: vec![
-                    "-c".to_string(),
-                    r#"echo '#include <stdio.h>
-int main() {
-    int a, b;
-    scanf("%d %d", &a, &b);
-    printf("%d + %d = %d\n", a, b, a + b);
-}' > main.c"#
-                        .to_string(),
-                ],
-                input: "".to_string(),
-                config: Config {
-                    time_limit: 1,
-                    time_reserved: 1,
-                    memory_limit: 256000,
-                    memory_reserved: 4096000,
-                    large_stack: false,
-                    output_limit: 0,
-                    process_limit: 0,
+                args: vec!["-c".to_string(), "echo 42 > cache.txt".to_string()],
+                ..Default::default()
+            },
+            CMD {
+                command: "cat".to_string(),
+                args: vec!["cache.txt".to_string()],
+                ..Default::default()
+            },
+        ];
+        let form_data = FormData {
+            commands,
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let config = SandboxConfig {
+            report_file_writes: true,
+            ..Default::default()
+        };
+        let results = sandbox_service(&config, &form_data).unwrap();
+        assert_eq!(results[0].files_written, None);
+        assert_eq!(
+            results[1].files_written,
+            Some(vec!["cache.txt".to_string()])
+        );
+        let results = sandbox_service(&SandboxConfig::default(), &form_data).unwrap();
+        assert_eq!(results[1].files_written, None);
+    }
+
+    #[test]
+    fn container_state_described() {
+        assert_eq!(
+            describe_state("true|false|137|\n").as_deref(),
+            Some("OOMKilled")
+        );
+        assert_eq!(describe_state("false|true|137|").as_deref(), Some("Dead"));
+        assert_eq!(
+            describe_state("false|false|127|exec: \"./sandbox\": permission denied").as_deref(),
+            Some("Error: exec: \"./sandbox\": permission denied")
+        );
+        assert_eq!(
+            describe_state("false|false|0|\n").as_deref(),
+            Some("Exited (0)")
+        );
+        assert_eq!(describe_state(""), None);
+
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        assert!(docker_args(&SandboxConfig::default(), &form_data).contains(&"--rm".to_string()));
+        let config = SandboxConfig {
+            container_state: true,
+            ..Default::default()
+        };
+        assert!(!docker_args(&config, &form_data).contains(&"--rm".to_string()));
+    }
+
+    #[test]
+    fn container_memory_limited() {
+        let form_data = FormData {
+            commands: vec![
+                CMD {
+                    config: Config {
+                        memory_limit: 128000,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                CMD {
+                    config: Config {
+                        memory_limit: 512000,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            ],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let config = SandboxConfig {
+            container_memory_headroom: Some(65536),
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["--memory", "577536k"]));
+        assert!(args.windows(2).any(|w| w == ["--memory-swap", "577536k"]));
+        assert!(!args.contains(&"--rm".to_string()));
+        let default_args = docker_args(&SandboxConfig::default(), &form_data);
+        assert!(!default_args.contains(&"--memory".to_string()));
+        assert!(default_args.contains(&"--rm".to_string()));
+
+        let mut results = parse_results(
+            r#"
+- {state: Success, stdout: '', stderr: '', time: 0, memory: 1024}
+- {state: RuntimeError, stdout: '', stderr: '', time: 0, memory: 500000, signal: 9}
+- {state: OtherError, stdout: '', stderr: '', time: 0, memory: 0}
+"#,
+            3,
+            true,
+        )
+        .unwrap();
+        mark_oom_killed(&mut results);
+        let states: Vec<&ExitState> = results.iter().map(|result| &result.state).collect();
+        assert_eq!(
+            states,
+            [
+                &ExitState::Success,
+                &ExitState::MemoryLimitExceeded,
+                &ExitState::OtherError
+            ]
+        );
+        let mut lost = vec![SandboxResult::default(); 2];
+        mark_oom_killed(&mut lost);
+        assert_eq!(lost[1].state, ExitState::MemoryLimitExceeded);
+        assert_eq!(lost[1].limit_hit, Some(LimitKind::Memory));
+    }
+
+    #[test]
+    fn init_flag_default_on() {
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        assert!(docker_args(&SandboxConfig::default(), &form_data).contains(&"--init".to_string()));
+        let config = SandboxConfig {
+            init: false,
+            ..Default::default()
+        };
+        assert!(!docker_args(&config, &form_data).contains(&"--init".to_string()));
+    }
+
+    #[test]
+    fn entrypoint_override() {
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        assert!(
+            !docker_args(&SandboxConfig::default(), &form_data)
+                .contains(&"--entrypoint".to_string())
+        );
+        let config = SandboxConfig {
+            entrypoint: Some("".to_string()),
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["--entrypoint", ""]));
+        assert!(args.ends_with(&["gcc:14.2".to_string(), "./sandbox".to_string()]));
+    }
+
+    #[test]
+    fn dns_and_hosts_only_when_networked() {
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let config = SandboxConfig {
+            dns: vec!["10.0.0.53".to_string()],
+            add_hosts: vec!["judge.internal:10.0.0.2".to_string()],
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["--dns", "10.0.0.53"]));
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["--add-host", "judge.internal:10.0.0.2"])
+        );
+        assert!(!args.contains(&"--network".to_string()));
+
+        let config = SandboxConfig {
+            networked: false,
+            ..config
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["--network", "none"]));
+        assert!(!args.contains(&"--dns".to_string()));
+        assert!(!args.contains(&"--add-host".to_string()));
+    }
+
+    #[test]
+    fn hostile_entrypoint_bypassed() {
+        // An image whose entrypoint fails before the executor could ever run
+        let image = "supercode-hostile-entrypoint:latest";
+        let mut build = Command::new("docker")
+            .args(["build", "-t", image, "-"])
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        build
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"FROM gcc:14.2\nENTRYPOINT [\"false\"]\n")
+            .unwrap();
+        assert!(build.wait().unwrap().success());
+        let commands = vec![CMD {
+            command: "echo".to_string(),
+            args: vec!["ran".to_string()],
+            ..Default::default()
+        }];
+        let config = SandboxConfig {
+            entrypoint: Some("".to_string()),
+            ..Default::default()
+        };
+        let results = sandbox_service(
+            &config,
+            &FormData {
+                commands,
+                image,
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        assert_eq!(results.unwrap()[0].stdout, "ran\n");
+    }
+
+    #[test]
+    fn orphaned_child_reaped() {
+        let commands = vec![cmd(
+            "bash",
+            &[
+                "-c",
+                "(sleep 0.1 &); sleep 0.5; grep -l '^State:.*Z' /proc/[0-9]*/status | wc -l",
+            ],
+        )];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        let results = results.unwrap();
+        assert_eq!(results[0].state, ExitState::Success);
+        assert_eq!(results[0].stdout, "0\n");
+    }
+
+    #[test]
+    fn limit_hit_detection() {
+        let results = annotated(
+            &SandboxConfig::default(),
+            &vec![CMD::default(); 7],
+            r#"
+- {state: TimeLimitExceeded, stdout: '', stderr: '', time: 1000, memory: 1024}
+- {state: MemoryLimitExceeded, stdout: '', stderr: '', time: 1, memory: 256000}
+- {state: RuntimeError, stdout: '', stderr: '', time: 1, memory: 1024, signal: 24}
+- {state: RuntimeError, stdout: '', stderr: '', time: 1, memory: 1024, signal: 25}
+- {state: RuntimeError, stdout: '', stderr: '', time: 1, memory: 1024, limit_hit: Process}
+- {state: RuntimeError, stdout: '', stderr: '', time: 1, memory: 1024, signal: 11}
+- {state: Success, stdout: '', stderr: '', time: 1, memory: 1024}
+"#,
+        );
+        let limits: Vec<_> = results.iter().map(|result| result.limit_hit).collect();
+        assert_eq!(
+            limits,
+            [
+                Some(LimitKind::Time),
+                Some(LimitKind::Memory),
+                Some(LimitKind::Time),
+                Some(LimitKind::Output),
+                Some(LimitKind::Process),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn limit_hit_per_kind() {
+        let cases = [
+            ("while :; do :; done", Config::default(), LimitKind::Time),
+            (
+                "a=x; while :; do a=$a$a; done",
+                Config::default(),
+                LimitKind::Memory,
+            ),
+            (
+                "yes",
+                Config {
+                    output_limit: 1024,
+                    ..Default::default()
+                },
+                LimitKind::Output,
+            ),
+            (
+                ":(){ :|:& };:",
+                Config {
+                    process_limit: 16,
+                    ..Default::default()
+                },
+                LimitKind::Process,
+            ),
+        ];
+        for (script, config, limit) in cases {
+            let commands = vec![CMD {
+                command: "bash".to_string(),
+                args: vec!["-c".to_string(), script.to_string()],
+                config,
+                ..Default::default()
+            }];
+            let results = sandbox_service(
+                &SandboxConfig::default(),
+                &FormData {
+                    commands,
+                    image: "gcc:14.2",
+                    ..Default::default()
                 },
+            );
+            assert!(results.is_ok());
+            assert_eq!(results.unwrap()[0].limit_hit, Some(limit), "{}", script);
+        }
+    }
+
+    #[test]
+    fn executor_version_pin() {
+        let executor = Uuid::new_v4().to_string();
+        let missing = executor_version(Path::new(&executor));
+        fs::write(&executor, "abc").unwrap();
+        let digest = executor_version(Path::new(&executor));
+        let _ = fs::remove_file(&executor);
+        assert_eq!(missing, None);
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(digest.as_deref(), Some(abc));
+
+        assert!(check_executor_version(abc, digest.as_deref()).is_ok());
+        assert!(check_executor_version(&abc.to_uppercase(), digest.as_deref()).is_ok());
+        assert!(check_executor_version(&abc[1..], digest.as_deref()).is_err());
+        assert!(check_executor_version(abc, None).is_err());
+    }
+
+    #[test]
+    fn incompatible_executor_detected() {
+        // Any change to the bundled executor changes its digest
+        let config = SandboxConfig {
+            executor_version: Some("0".repeat(64)),
+            executor_version_strict: true,
+            ..Default::default()
+        };
+        assert!(verify_executor(&config).is_err());
+        let bundled = executor_version(&Path::new(&config.sandbox_root).join("sandbox"));
+        let compatible = SandboxConfig {
+            executor_version: bundled,
+            ..config.clone()
+        };
+        assert!(verify_executor(&compatible).is_ok());
+        let config = SandboxConfig {
+            executor_version_strict: false,
+            ..config
+        };
+        assert!(verify_executor(&config).is_ok());
+        assert!(verify_executor(&SandboxConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn env_file_parsing() {
+        let vars = parse_env_file(
+            "# comment\n\nGREETING=hello world\nexport NAME=\"Ada\"\n_EMPTY=\nQUOTED='a=b'\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            [
+                ("GREETING".to_string(), "hello world".to_string()),
+                ("NAME".to_string(), "Ada".to_string()),
+                ("_EMPTY".to_string(), "".to_string()),
+                ("QUOTED".to_string(), "a=b".to_string()),
+            ]
+        );
+        assert!(parse_env_file("1ABC=x").is_err());
+        assert!(parse_env_file("BAD-KEY=x").is_err());
+        assert!(parse_env_file("NO_VALUE").is_err());
+    }
+
+    #[test]
+    fn sorted_args_identical() {
+        let config = |add_hosts: &[&str]| SandboxConfig {
+            add_hosts: add_hosts.iter().map(|host| host.to_string()).collect(),
+            home: Some(".home".to_string()),
+            sorted_args: true,
+            ..Default::default()
+        };
+        let form_data = || FormData {
+            commands: vec![CMD {
+                command: "./main".to_string(),
+                ..Default::default()
+            }],
+            image: "gcc:14.2",
+            env_file: Some("B=2\nA=1\n".to_string()),
+            ..Default::default()
+        };
+        let first = config(&["db:10.0.0.2", "api:10.0.0.1"]);
+        let second = config(&["api:10.0.0.1", "db:10.0.0.2"]);
+        let args = docker_args(&first, &form_data());
+        assert_eq!(args, docker_args(&first, &form_data()));
+        assert_eq!(args, docker_args(&second, &form_data()));
+        let hosts: Vec<&String> = args
+            .windows(2)
+            .filter(|w| w[0] == "--add-host")
+            .map(|w| &w[1])
+            .collect();
+        assert_eq!(hosts, ["api:10.0.0.1", "db:10.0.0.2"]);
+
+        let vars = parse_env_file("B=2\nA=1\nB=3\n").unwrap();
+        assert_eq!(render_env_file(&first, &vars), "A=1\nB=2\nB=3\n");
+        let unsorted = SandboxConfig::default();
+        assert_eq!(render_env_file(&unsorted, &vars), "B=2\nA=1\nB=3\n");
+    }
+
+    #[test]
+    fn env_file_visible_to_program() {
+        let commands = vec![cmd("bash", &["-c", "echo \"$GREETING, $NAME\""])];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                env_file: Some("GREETING=hello\nNAME=\"world\"\n".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        assert_eq!(results.unwrap()[0].stdout, "hello, world\n");
+    }
+
+    #[test]
+    fn parallel_groups_split_into_lanes() {
+        let cmd = |group: Option<&str>| CMD {
+            parallel_group: group.map(str::to_string),
+            ..Default::default()
+        };
+        let commands = vec![
+            cmd(None),
+            cmd(Some("variants")),
+            cmd(Some("variants")),
+            cmd(None),
+            cmd(None),
+            cmd(Some("tests")),
+        ];
+        let bounds = |parallel, incremental| {
+            execution_stages(&commands, parallel, incremental)
+                .iter()
+                .map(|stage| stage.iter().map(|lane| (lane.start, lane.end)).collect())
+                .collect::<Vec<Vec<_>>>()
+        };
+        assert_eq!(bounds(false, false), [vec![(0, 6)]]);
+        assert_eq!(bounds(false, true).len(), 6);
+        assert_eq!(bounds(true, true)[2..4], [vec![(3, 4)], vec![(4, 5)]]);
+        assert_eq!(
+            bounds(true, false),
+            [
+                vec![(0, 1)],
+                vec![(1, 2), (2, 3)],
+                vec![(3, 5)],
+                vec![(5, 6)]
+            ]
+        );
+
+        let form_data = FormData {
+            commands,
+            image: "gcc:14.2",
+            parallel: true,
+            ..Default::default()
+        };
+        let config = SandboxConfig {
+            min_free_memory: Some(0),
+            ..Default::default()
+        };
+        // The two variants reserve memory at the same time
+        assert!(check_free_memory(&config, &form_data, 2 * 6144000).is_ok());
+        assert!(check_free_memory(&config, &form_data, 2 * 6144000 - 1).is_err());
+
+        let args: Vec<String> =
+            docker_command(&config, &form_data, &form_data.commands, "tmp", Some(1))
+                .unwrap()
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["-v", "./tmp/lane-1/results.yaml:/sandbox/results.yaml"])
+        );
+    }
+
+    #[test]
+    fn parallel_group_overlaps() {
+        // Each command prints when it started and when it finished, in nanoseconds
+        let cmd = || CMD {
+            config: Config {
+                time_limit: 3,
+                memory_reserved: 4096000,
+                ..Default::default()
+            },
+            parallel_group: Some("sleepers".to_string()),
+            ..cmd("bash", &["-c", "date +%s%N; sleep 1; date +%s%N"])
+        };
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands: vec![cmd(), cmd()],
+                image: "gcc:14.2",
+                parallel: true,
+                ..Default::default()
+            },
+        );
+        assert!(results.is_ok());
+        let spans: Vec<Vec<u128>> = results
+            .unwrap()
+            .iter()
+            .map(|result| {
+                result
+                    .stdout
+                    .lines()
+                    .map(|line| line.parse().unwrap())
+                    .collect()
+            })
+            .collect();
+        assert!(spans[0][0] < spans[1][1] && spans[1][0] < spans[0][1]);
+    }
+
+    #[test]
+    fn rusage_counters_for_busy_loop() {
+        let commands = vec![CMD {
+            command: "bash".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "end=$((SECONDS + 2)); while [ $SECONDS -lt $end ]; do :; done".to_string(),
+            ],
+            config: Config {
+                time_limit: 3,
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let config = SandboxConfig {
+            command_reports: true,
+            ..Default::default()
+        };
+        let results = sandbox_service(
+            &config,
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
             },
+        );
+        assert!(results.is_ok());
+        let rusage = results.unwrap()[0].rusage.unwrap();
+        // A busy loop touches memory while starting up, then spends at least a second on the CPU
+        assert!(rusage.minor_page_faults > 0);
+        assert!(rusage.major_page_faults <= rusage.minor_page_faults);
+        assert!(rusage.user_time + rusage.system_time >= 1000);
+    }
+
+    #[test]
+    fn pull_concurrency_limited() {
+        let limiter = Limiter::new();
+        let pulling = Mutex::new(0);
+        let peak = Mutex::new(0);
+        thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|| {
+                    let _permit = limiter.acquire(2);
+                    let now = {
+                        let mut pulling = pulling.lock().unwrap();
+                        *pulling += 1;
+                        *pulling
+                    };
+                    let mut peak_value = peak.lock().unwrap();
+                    *peak_value = (*peak_value).max(now);
+                    drop(peak_value);
+                    thread::sleep(std::time::Duration::from_millis(50));
+                    *pulling.lock().unwrap() -= 1;
+                });
+            }
+        });
+        assert_eq!(*peak.lock().unwrap(), 2);
+        assert_eq!(*limiter.active.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn heavy_images_run_fewer_at_once() {
+        let config = SandboxConfig {
+            image_manifest: images::parse_image_manifest("- image: \"openjdk:*\"\n  weight: 2\n")
+                .unwrap(),
+            ..Default::default()
+        };
+        let peak_running = |image: &str| {
+            let limiter = Limiter::new();
+            let running = Mutex::new(0);
+            let peak = Mutex::new(0);
+            thread::scope(|scope| {
+                for _ in 0..8 {
+                    scope.spawn(|| {
+                        let _permit = limiter.acquire_weighted(config.run_weight(image), 4);
+                        let now = {
+                            let mut running = running.lock().unwrap();
+                            *running += 1;
+                            *running
+                        };
+                        let mut peak_value = peak.lock().unwrap();
+                        *peak_value = (*peak_value).max(now);
+                        drop(peak_value);
+                        thread::sleep(std::time::Duration::from_millis(50));
+                        *running.lock().unwrap() -= 1;
+                    });
+                }
+            });
+            assert_eq!(*limiter.active.lock().unwrap(), 0);
+            *peak.lock().unwrap()
+        };
+        assert_eq!(peak_running("openjdk:21"), 2);
+        assert_eq!(peak_running("gcc:14.2"), 4);
+        // A weight above the limit still runs, alone
+        assert_eq!(Limiter::new().acquire_weighted(10, 4).weight, 4);
+    }
+
+    #[test]
+    fn unlisted_image_rejected() {
+        let config = SandboxConfig {
+            allowed_images: Some(vec!["gcc:*".to_string(), "openjdk:21".to_string()]),
+            ..Default::default()
+        };
+        assert!(image_allowed(&config, "gcc:14.2"));
+        assert!(image_allowed(&config, "openjdk:21"));
+        assert!(!image_allowed(&config, "openjdk:17"));
+        assert!(image_allowed(&SandboxConfig::default(), "anything:latest"));
+
+        // Rejected before docker is asked to pull or run it
+        let form_data = FormData {
+            commands: vec![CMD {
+                command: "./main".to_string(),
+                ..Default::default()
+            }],
+            image: "attacker/miner:latest",
+            ..Default::default()
+        };
+        let error = sandbox_service(&config, &form_data).unwrap_err();
+        assert!(matches!(&error, SandboxError::ImageNotAllowed(image) if image == form_data.image));
+        assert_eq!(
+            error.to_string(),
+            "Image attacker/miner:latest is not allowed"
+        );
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn oversized_args_rejected() {
+        let cmd = |args: Vec<String>| CMD {
+            command: "./main".to_string(),
+            args,
+            ..Default::default()
+        };
+        let config = SandboxConfig {
+            max_args: Some(3),
+            max_args_bytes: Some(16),
+            ..Default::default()
+        };
+        let commands = [
+            cmd(vec!["a".to_string(), "b".to_string()]),
+            cmd(vec!["a".to_string(); 4]),
+            cmd(vec!["x".repeat(17)]),
+        ];
+        assert!(check_args(&config, &commands[..1]).is_ok());
+        let error = check_args(&config, &commands[..2]).unwrap_err();
+        assert_eq!(
+            error,
+            "Command 1 (./main) has 4 args, at most 3 are allowed"
+        );
+        assert!(check_args(&config, &commands[2..]).is_err());
+
+        // Rejected before anything is set up or run
+        let form_data = FormData {
+            commands: vec![commands[0].clone(), commands[2].clone()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        assert!(matches!(
+            sandbox_service(&config, &form_data),
+            Err(SandboxError::InvalidSubmission(_))
+        ));
+    }
+
+    #[test]
+    fn labels_admission() {
+        let form_data = |labels: &[&str]| FormData {
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+            ..Default::default()
+        };
+        let config = SandboxConfig {
+            labels: vec!["high-memory".to_string()],
+            ..Default::default()
+        };
+        assert!(admit(&config, &form_data(&[])).is_ok());
+        assert!(admit(&config, &form_data(&["high-memory"])).is_ok());
+        assert_eq!(
+            admit(&config, &form_data(&["gpu", "high-memory"])),
+            Err("Missing worker labels: gpu".to_string())
+        );
+        assert!(admit(&SandboxConfig::default(), &form_data(&["high-memory"])).is_err());
+    }
+
+    #[test]
+    fn concurrency_limit_wait_reported() {
+        static LIMITER: Limiter = Limiter::new();
+        let config = SandboxConfig {
+            max_concurrent_runs: Some(1),
+            ..Default::default()
+        };
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let running = acquire_run_from(&LIMITER, &config, &form_data);
+        assert!(!running.waited);
+        let waiting = thread::scope(|scope| {
+            let waiting = scope.spawn(|| acquire_run_from(&LIMITER, &config, &form_data).waited);
+            thread::sleep(Duration::from_millis(100));
+            drop(running);
+            waiting.join().unwrap()
+        });
+        assert!(waiting);
+        assert!(!acquire_run_from(&LIMITER, &config, &form_data).waited);
+        assert!(!acquire_run_from(&LIMITER, &SandboxConfig::default(), &form_data).waited);
+    }
+
+    #[test]
+    fn mounts_capped_below_runs() {
+        static RUNS: Limiter = Limiter::new();
+        static MOUNTS: Limiter = Limiter::new();
+        let config = SandboxConfig {
+            max_concurrent_runs: Some(8),
+            max_concurrent_mounts: Some(2),
+            ..Default::default()
+        };
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let mounted = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|| {
+                    let run = acquire_run_from(&RUNS, &config, &form_data);
+                    assert!(!run.waited);
+                    let _mount = acquire_mount(&MOUNTS, &config);
+                    let now = mounted.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    mounted.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert!(acquire_mount(&MOUNTS, &SandboxConfig::default()).is_none());
+    }
+
+    #[test]
+    fn wire_format_round_trip() {
+        let results: Vec<SandboxResult> = serde_yaml::from_str(
+            "- {state: Success, stdout: \"3\\n\", stderr: '', time: 0, memory: 1024}",
+        )
+        .unwrap();
+        let config = SandboxConfig::default();
+        for format in [WireFormat::Yaml, WireFormat::Json] {
+            let sent = FormData {
+                commands: vec![CMD {
+                    command: "./main".to_string(),
+                    args: vec!["tests/1.in".to_string()],
+                    input: "1 2\n".to_string(),
+                    ..Default::default()
+                }],
+                image: "gcc:14.2",
+                submit_id: "42".to_string(),
+                format,
+                ..Default::default()
+            };
+            let message = match format {
+                WireFormat::Yaml => serde_yaml::to_string(&sent).unwrap(),
+                WireFormat::Json => serde_json::to_string(&sent).unwrap(),
+            };
+            let message = decode_message(message).unwrap();
+            let form_data = parse_form_data(&config, &message).unwrap();
+            assert_eq!(form_data.format, format);
+            assert_eq!(form_data.image, "gcc:14.2");
+            assert_eq!(form_data.commands[0].args, ["tests/1.in"]);
+            assert_eq!(form_data.commands[0].input, "1 2\n");
+
+            let encoded = ResponseData::new(&config, &form_data, results.clone()).encode();
+            let received: ResponseData = match format {
+                WireFormat::Yaml => serde_yaml::from_str(&encoded).unwrap(),
+                WireFormat::Json => serde_json::from_str(&encoded).unwrap(),
+            };
+            assert_eq!(encoded.starts_with('{'), format == WireFormat::Json);
+            assert_eq!(received.submit_id, "42");
+            assert_eq!(
+                format!("{:?}", received.sandbox_results),
+                format!("{:?}", results)
+            );
+        }
+        // Escapes only JSON has
+        let message = decode_message(
+            r#"{"image": "gcc:14.2", "submit_id": "a\/b", "commands": [], "format": "json"}"#
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(parse_form_data(&config, &message).unwrap().submit_id, "a/b");
+        assert_eq!(
+            parse_form_data(&config, "image: gcc\nsubmit_id: x\ncommands: []\n")
+                .unwrap()
+                .format,
+            WireFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn workspace_sources() {
+        let form_data = FormData {
+            workspace: Some("not base64!".to_string()),
+            ..Default::default()
+        };
+        assert!(load_workspace(&SandboxConfig::default(), &form_data).is_err());
+        let form_data = FormData {
+            workspace: Some(BASE64_STANDARD.encode(b"archive")),
+            ..Default::default()
+        };
+        let archive = load_workspace(&SandboxConfig::default(), &form_data).unwrap();
+        assert_eq!(archive.as_deref(), Some(&b"archive"[..]));
+        let by_ref = FormData {
+            workspace_ref: Some("project.tar".to_string()),
+            ..Default::default()
+        };
+        assert!(load_workspace(&SandboxConfig::default(), &by_ref).is_err());
+        let both = FormData {
+            workspace_ref: Some("project.tar".to_string()),
+            ..form_data
+        };
+        assert!(load_workspace(&SandboxConfig::default(), &both).is_err());
+        assert_eq!(
+            load_workspace(&SandboxConfig::default(), &FormData::default()),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn data_files_written() {
+        let dir = std::env::temp_dir().join(format!("files-{}", uuid::Uuid::new_v4()));
+        let file = |path: &str, content: &str, encoding| FileEntry {
+            path: path.to_string(),
+            content: content.to_string(),
+            encoding,
+        };
+        let commands = vec![
             CMD {
-                command: "gcc".to_string(),
-                args: vec!["main.c".to_string(), "-o".to_string(), "main".to_string()],
-                input: "".to_string(),
-                config: Config {
-                    time_limit: 1,
-                    time_reserved: 1,
-                    memory_limit: 256000,
-                    memory_reserved: 4096000,
-                    large_stack: false,
-                    output_limit: 0,
-                    process_limit: 0,
-                },
+                files: vec![file("input.txt", "1 2\n", FileEncoding::Raw)],
+                ..Default::default()
             },
             CMD {
-                command: "./main".to_string(),
-                args: vec![],
-                input: "1 2".to_string(),
-                config: Config {
-                    time_limit: 1,
-                    time_reserved: 1,
-                    memory_limit: 256000,
-                    memory_reserved: 4096000,
-                    large_stack: false,
-                    output_limit: 0,
-                    process_limit: 0,
-                },
+                files: vec![file("testdata/1.bin", "AAEC", FileEncoding::Base64)],
+                ..Default::default()
             },
         ];
-        let results = sandbox_service(commands, "gcc:14.2");
+        write_files(&dir, &commands).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("input.txt")).unwrap(), "1 2\n");
+        assert_eq!(fs::read(dir.join("testdata/1.bin")).unwrap(), [0, 1, 2]);
+
+        for escaping in ["../escaped.txt", "/tmp/absolute.txt", "results.yaml"] {
+            let cmd = CMD {
+                files: vec![file(escaping, "x", FileEncoding::Raw)],
+                ..Default::default()
+            };
+            assert!(write_files(&dir, &[cmd]).is_err(), "{}", escaping);
+        }
+        assert!(!dir.join("../escaped.txt").exists());
+        let invalid = CMD {
+            files: vec![file("data.bin", "not base64!", FileEncoding::Base64)],
+            ..Default::default()
+        };
+        assert!(write_files(&dir, &[invalid]).is_err());
+        fs::remove_dir_all(dir).unwrap();
+
+        let form_data = FormData {
+            commands: vec![CMD {
+                command: "cat".to_string(),
+                args: vec!["input.txt".to_string()],
+                input: "ignored\n".to_string(),
+                files: vec![file("input.txt", "1 2\n", FileEncoding::Raw)],
+                ..Default::default()
+            }],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let results = sandbox_service(&SandboxConfig::default(), &form_data).unwrap();
+        assert_eq!(results[0].stdout, "1 2\n");
+    }
+
+    #[test]
+    fn capped_limits_reported() {
+        let message = r#"
+image: gcc:14.2
+submit_id: "1"
+commands:
+  - command: ./main
+    args: []
+    input: ""
+    config:
+      time_limit: 10
+      time_reserved: 1
+      memory_limit: 1024000
+      memory_reserved: 6144000
+      large_stack: false
+      output_limit: 0
+      process_limit: 0
+      wall_time_limit: 20
+"#;
+        let config = SandboxConfig {
+            max_time_limit: Some(5),
+            max_memory_limit: Some(512000),
+            effective_limits: true,
+            ..Default::default()
+        };
+        let form_data = parse_form_data(&config, message).unwrap();
+        let limits = &form_data.commands[0].config;
+        assert_eq!(
+            (
+                limits.time_limit,
+                limits.wall_time_limit,
+                limits.memory_limit
+            ),
+            (5, Some(5), 512000)
+        );
+
+        let output = "- {state: Success, stdout: '', stderr: '', time: 1, memory: 2048}\n";
+        let mut results = parse_results(output, 1, true).unwrap();
+        annotate_results(&config, &form_data.commands, &mut results);
+        assert_eq!(results[0].effective_limits.as_ref(), Some(limits));
+        let mut results = parse_results(output, 1, true).unwrap();
+        annotate_results(&SandboxConfig::default(), &form_data.commands, &mut results);
+        assert_eq!(results[0].effective_limits, None);
+
+        // Unlimited memory is only lowered when there is a ceiling
+        let unlimited = message.replace("1024000", "0");
+        let uncapped = parse_form_data(&SandboxConfig::default(), &unlimited).unwrap();
+        assert_eq!(uncapped.commands[0].config.time_limit, 10);
+        assert_eq!(uncapped.commands[0].config.memory_limit, 0);
+        let capped = parse_form_data(&config, &unlimited).unwrap();
+        assert_eq!(capped.commands[0].config.memory_limit, 512000);
+    }
+
+    #[test]
+    fn submission_validated() {
+        let config = SandboxConfig {
+            allowed_images: Some(vec!["gcc:*".to_string()]),
+            max_time_limit: Some(5),
+            max_memory_limit: Some(512000),
+            ..Default::default()
+        };
+        let valid = FormData {
+            commands: vec![CMD {
+                command: "./main".to_string(),
+                args: vec!["input.txt".to_string()],
+                files: vec![FileEntry {
+                    path: "input.txt".to_string(),
+                    content: "1 2".to_string(),
+                    encoding: FileEncoding::Raw,
+                }],
+                ..Default::default()
+            }],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        assert_eq!(validate_form_data(&config, &valid), Ok(()));
+
+        let mut commands = valid.commands.clone();
+        commands[0].args.push(String::new());
+        commands[0].config.time_limit = 10;
+        commands[0].config.memory_limit = 0;
+        commands[0].files[0].path = "../input.txt".to_string();
+        commands.push(CMD {
+            run_if: Some(RunIf {
+                depends_on: 1,
+                outcome: Outcome::Success,
+            }),
+            ..Default::default()
+        });
+        let invalid = FormData {
+            commands,
+            image: "openjdk:21",
+            ..Default::default()
+        };
+        let ValidationError(problems) = validate_form_data(&config, &invalid).unwrap_err();
+        assert_eq!(
+            problems,
+            [
+                "Image openjdk:21 is not allowed",
+                "Command 0 (./main): arg 1 is empty",
+                "Command 0 (./main): time_limit is 10, at most 5 is allowed",
+                "Command 0 (./main): memory_limit is unlimited, at most 512000 is allowed",
+                "Command 0 (./main): Workspace entry \"../input.txt\" leaves the workspace",
+                "Command 1 is empty",
+                "Command 1 () depends on command 1, which doesn't run before it",
+            ]
+        );
+        let empty = FormData::default();
+        let ValidationError(problems) = validate_form_data(&config, &empty).unwrap_err();
+        assert_eq!(problems, ["No commands", "No image"]);
+    }
+
+    #[test]
+    fn strict_yaml_rejects_typo() {
+        let message = r#"
+image: gcc:14.2
+submit_id: "1"
+commands:
+  - command: gcc
+    args: ["--version"]
+    input: ""
+    config:
+      time_limit: 1
+      time_reserved: 1
+      memory_limit: 256000
+      memory_limt: 128000
+      memory_reserved: 4096000
+      large_stack: false
+      output_limit: 0
+      process_limit: 0
+"#;
+        assert!(parse_form_data(&SandboxConfig::default(), message).is_ok());
+        let config = SandboxConfig {
+            strict_yaml: true,
+            ..Default::default()
+        };
+        let error = parse_form_data(&config, message).err().unwrap();
+        assert!(
+            error.contains("commands[0].config.memory_limt"),
+            "{}",
+            error
+        );
+        assert!(
+            parse_form_data(&config, &message.replace("      memory_limt: 128000\n", "")).is_ok()
+        );
+    }
+
+    #[test]
+    fn command_timestamps_ordered() {
+        let cmd = || CMD {
+            command: "sleep".to_string(),
+            args: vec!["0.3".to_string()],
+            config: Config {
+                time_limit: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // Without reports, commands run in one container share its bounds
+        let config = SandboxConfig {
+            command_reports: true,
+            ..Default::default()
+        };
+        let results = sandbox_service(
+            &config,
+            &FormData {
+                commands: vec![cmd(), cmd()],
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
         assert!(results.is_ok());
-        assert_eq!(results.unwrap()[2].stdout, "1 + 2 = 3\n")
+        let results = results.unwrap();
+        let spans: Vec<(u64, u64)> = results
+            .iter()
+            .map(|result| (result.started_at.unwrap(), result.finished_at.unwrap()))
+            .collect();
+        for (started_at, finished_at) in &spans {
+            // Each command slept for 300ms of wall-clock time
+            assert!(finished_at - started_at >= 300);
+        }
+        assert!(spans[0].1 <= spans[1].0);
     }
 
     #[test]
-    fn cpp_a_add_b() {
+    fn tenant_temp_folders() {
+        let form_data = |tenant: Option<&str>| FormData {
+            tenant: tenant.map(str::to_string),
+            ..Default::default()
+        };
+        let untenanted = temp_folder(&form_data(None));
+        assert!(!untenanted.contains('/'));
+        let alice = temp_folder(&form_data(Some("alice")));
+        let bob = temp_folder(&form_data(Some("bob")));
+        assert!(alice.starts_with("tenants/alice/"));
+        assert!(bob.starts_with("tenants/bob/"));
+        // Path separators and dots can't escape the tenants folder
+        let hostile = temp_folder(&form_data(Some("../../etc")));
+        assert!(hostile.starts_with("tenants/______etc/"));
+        assert_eq!(sanitize_tenant(""), "_");
+        assert_eq!(sanitize_tenant(&"x".repeat(100)).len(), 64);
+    }
+
+    #[test]
+    fn build_memory_kept_apart_from_run() {
         let commands = vec![
-            CMD {
-                command: "bash".to_string(),
-                args: vec![
-                    "-c".to_string(),
-                    r#"echo '#include <iostream>
-using namespace std;
-int main() {
-    int a, b;
-    cin >> a >> b;
-    cout << a << " + " << b << " = " << a + b << endl;
-}' > main.cpp"#
-                        .to_string(),
-                ],
-                input: "".to_string(),
-                config: Config {
-                    time_limit: 1,
-                    time_reserved: 1,
-                    memory_limit: 256000,
-                    memory_reserved: 4096000,
-                    large_stack: false,
-                    output_limit: 0,
-                    process_limit: 0,
-                },
-            },
             CMD {
                 command: "g++".to_string(),
-                args: vec!["main.cpp".to_string(), "-o".to_string(), "main".to_string()],
-                input: "".to_string(),
                 config: Config {
-                    time_limit: 1,
-                    time_reserved: 1,
-                    memory_limit: 256000,
-                    memory_reserved: 4096000,
-                    large_stack: false,
-                    output_limit: 0,
-                    process_limit: 0,
+                    memory_limit: 2048000,
+                    ..Default::default()
                 },
+                ..Default::default()
             },
             CMD {
                 command: "./main".to_string(),
-                args: vec![],
-                input: "1 2".to_string(),
                 config: Config {
-                    time_limit: 1,
-                    time_reserved: 1,
-                    memory_limit: 256000,
-                    memory_reserved: 4096000,
-                    large_stack: false,
-                    output_limit: 0,
-                    process_limit: 0,
+                    memory_limit: 65536,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ];
+        let results = annotated(
+            &SandboxConfig::default(),
+            &commands,
+            r#"
+- {state: Success, stdout: '', stderr: '', time: 900, memory: 1500000}
+- {state: Success, stdout: '3', stderr: '', time: 1, memory: 3072}
+"#,
+        );
+        let memory = PhaseMemory::new(&results);
+        // The compiler's peak is far above the program's limit, but the run is judged alone
+        assert_eq!(memory.build, Some(1500000));
+        assert_eq!(memory.run, Some(3072));
+        assert!(memory.run.unwrap() <= commands[1].config.memory_limit);
+        assert_eq!(results[1].state, ExitState::Success);
+    }
+
+    #[test]
+    fn stdin_closed_after_input() {
+        let cmd = |keep_stdin_open| CMD {
+            command: "cat".to_string(),
+            input: "1 2\n".to_string(),
+            keep_stdin_open,
+            ..Default::default()
+        };
+        // Apart, as the container timeout stopping the second would take the first with it
+        let run = |keep_stdin_open| {
+            sandbox_service(
+                &SandboxConfig::default(),
+                &FormData {
+                    commands: vec![cmd(keep_stdin_open)],
+                    image: "gcc:14.2",
+                    ..Default::default()
                 },
+            )
+            .unwrap()
+            .remove(0)
+        };
+        // `cat` only stops reading at EOF
+        let closed = run(false);
+        assert_eq!(closed.state, ExitState::Success);
+        assert_eq!(closed.stdout, "1 2\n");
+        assert_eq!(run(true).state, ExitState::TimeLimitExceeded);
+    }
+
+    #[test]
+    fn kept_open_stdin_wrapped() {
+        let commands = vec![CMD {
+            input: "1 2\n".to_string(),
+            keep_stdin_open: true,
+            ..cmd("cat", &[])
+        }];
+        let wrapped = commands[0].for_executor(&SandboxConfig::default(), 0);
+        assert_eq!(wrapped.command, "sh");
+        assert_eq!(wrapped.args[2..], ["cat"]);
+        assert!(!wrapped.keep_stdin_open);
+        // The copy into the fifo reads all of the input, whatever the program does
+        let results = annotated(
+            &SandboxConfig::default(),
+            &commands,
+            r#"
+- state: Success
+  stdout: "1 2\n"
+  stderr: ''
+  time: 1
+  memory: 1024
+  input_fully_consumed: true
+"#,
+        );
+        assert_eq!(results[0].input_fully_consumed, None);
+    }
+
+    #[test]
+    fn oversized_template_flagged() {
+        let template = Uuid::new_v4().to_string();
+        fs::create_dir_all(format!("{}/a/b", template)).unwrap();
+        fs::write(format!("{}/sandbox", template), vec![0; 1000]).unwrap();
+        fs::write(format!("{}/a/b/data", template), vec![0; 24]).unwrap();
+        let stats = template_stats(Path::new(&template));
+        let _ = fs::remove_dir_all(&template);
+        let stats = stats.unwrap();
+        assert_eq!(
+            stats,
+            TemplateStats {
+                bytes: 1024,
+                files: 2,
+                depth: 3
+            }
+        );
+
+        assert!(check_template(&SandboxConfig::default(), &stats).is_ok());
+        let config = SandboxConfig {
+            template_max_bytes: Some(4096),
+            template_max_files: Some(2),
+            template_max_depth: Some(3),
+            ..Default::default()
+        };
+        assert!(check_template(&config, &stats).is_ok());
+        for config in [
+            SandboxConfig {
+                template_max_bytes: Some(1023),
+                ..config.clone()
+            },
+            SandboxConfig {
+                template_max_files: Some(1),
+                ..config.clone()
+            },
+            SandboxConfig {
+                template_max_depth: Some(2),
+                ..config.clone()
+            },
+        ] {
+            assert!(check_template(&config, &stats).is_err());
+        }
+    }
+
+    #[test]
+    fn output_cut_at_limit() {
+        let commands = vec![CMD {
+            command: "yes".to_string(),
+            config: Config {
+                output_limit: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        }];
+        let results = annotated(
+            &SandboxConfig::default(),
+            &commands,
+            r#"
+- {state: Success, stdout: "y\ny\ny\n", stderr: 'ééé', time: 0, memory: 1024}
+"#,
+        );
+        assert_eq!(results[0].stdout, "y\ny\ny\n[output truncated]");
+        assert_eq!(results[0].stderr, "éé\n[output truncated]");
+        assert_eq!(results[0].state, ExitState::OtherError);
+        assert_eq!(results[0].limit_hit, Some(LimitKind::Output));
+
+        let within = annotated(
+            &SandboxConfig::default(),
+            &commands,
+            "- {state: Success, stdout: \"y\\n\", stderr: '', time: 0, memory: 1024}",
+        );
+        assert_eq!(within[0].stdout, "y\n");
+        assert_eq!(within[0].state, ExitState::Success);
+        let unlimited = [CMD::default()];
+        let mut long = within.clone();
+        long[0].stdout = "y\n".repeat(1000);
+        annotate_results(&SandboxConfig::default(), &unlimited, &mut long);
+        assert_eq!(long[0].stdout.len(), 2000);
+    }
+
+    #[test]
+    fn truncated_output_warned() {
+        let commands = vec![
+            CMD {
+                command: "yes".to_string(),
+                ..Default::default()
+            },
+            CMD {
+                command: "cat".to_string(),
+                input: "1 2\n".to_string(),
+                ..Default::default()
             },
         ];
-        let results = sandbox_service(commands, "gcc:14.2");
-        assert!(results.is_ok());
-        assert_eq!(results.unwrap()[2].stdout, "1 + 2 = 3\n")
+        let results = annotated(
+            &SandboxConfig::default(),
+            &commands,
+            r#"
+- {state: RuntimeError, stdout: "y\ny\n", stderr: '', time: 1, memory: 1024, signal: 25}
+- {state: Success, stdout: '', stderr: '', time: 1, memory: 1024, input_fully_consumed: false}
+"#,
+        );
+        let form_data = FormData {
+            commands,
+            image: "gcc:14.2",
+            platform: Some(format!("linux/{}", host_arch())),
+            ..Default::default()
+        };
+        let response = ResponseData::new(&SandboxConfig::default(), &form_data, results);
+        assert_eq!(
+            response.warnings,
+            [
+                Warning::OutputTruncated { command: 0 },
+                Warning::InputNotConsumed { command: 1 }
+            ]
+        );
     }
 
     #[test]
-    fn java_version() {
+    fn teardown_result_dropped() {
+        let mut commands = vec![CMD::default(), teardown_command("rm -f core")];
+        assert!(commands[1].teardown);
+        assert_eq!(commands[1].args, ["-c", "rm -f core"]);
+        let mut results: Vec<SandboxResult> = serde_yaml::from_str(
+            r#"
+- {state: RuntimeError, stdout: '', stderr: '', time: 1, memory: 1024}
+- {state: Success, stdout: removed, stderr: '', time: 1, memory: 1024}
+"#,
+        )
+        .unwrap();
+        take_teardown(&mut commands, &mut results, 1);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].state, ExitState::RuntimeError);
+    }
+
+    #[test]
+    fn teardown_runs_apart() {
+        let commands = vec![
+            CMD::default(),
+            CMD::default(),
+            teardown_command("rm -f core"),
+        ];
+        let bounds: Vec<Vec<_>> = execution_stages(&commands, false, false)
+            .iter()
+            .map(|stage| stage.iter().map(|lane| (lane.start, lane.end)).collect())
+            .collect();
+        assert_eq!(bounds, [vec![(0, 2)], vec![(2, 3)]]);
+    }
+
+    #[test]
+    fn teardown_runs_after_failure() {
+        // The teardown only succeeds if the failing command ran before it
         let commands = vec![CMD {
-            command: "java".to_string(),
-            args: vec!["--version".to_string()],
-            input: "".to_string(),
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), "touch failed; exit 1".to_string()],
+            ..Default::default()
+        }];
+        let config = SandboxConfig {
+            teardown_command: Some("test -e failed && rm failed".to_string()),
+            ..Default::default()
+        };
+        let form_data = FormData {
+            commands,
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let results = sandbox_service(&config, &form_data);
+        assert!(results.is_ok());
+        let results = results.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].state, ExitState::RuntimeError);
+    }
+
+    #[test]
+    fn wall_time_limit_independent_of_cpu() {
+        let cmd = |wall_time_limit| CMD {
+            command: "sleep".to_string(),
+            args: vec!["5".to_string()],
             config: Config {
-                time_limit: 1,
-                time_reserved: 1,
-                memory_limit: 256000,
-                memory_reserved: 6144000,
-                large_stack: false,
-                output_limit: 0,
-                process_limit: 0,
+                wall_time_limit: Some(wall_time_limit),
+                cpu_time_limit: Some(1),
+                ..Default::default()
             },
-        }];
-        let results = sandbox_service(commands, "openjdk:21");
+            ..Default::default()
+        };
+        // Each runs in a container of its own, stopped the grace after its wall limit
+        let config = SandboxConfig {
+            run_time_grace: 2,
+            ..Default::default()
+        };
+        let bounds: Vec<Vec<_>> = execution_stages(&[cmd(8), cmd(2)], false, false)
+            .iter()
+            .map(|stage| stage.iter().map(|lane| (lane.start, lane.end)).collect())
+            .collect();
+        assert_eq!(bounds, [vec![(0, 1)], vec![(1, 2)]]);
+        assert_eq!(run_time_limit(&config, &[cmd(2)]), Duration::from_secs(4));
+        let results = sandbox_service(
+            &config,
+            &FormData {
+                commands: vec![cmd(8), cmd(2)],
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
         assert!(results.is_ok());
+        let results = results.unwrap();
+        // Sleeping uses no CPU, so only the wall limit can stop it
+        assert_eq!(results[0].state, ExitState::Success);
+        assert_eq!(results[1].state, ExitState::TimeLimitExceeded);
+        assert_eq!(results[1].limit_hit, Some(LimitKind::Time));
+    }
+
+    #[test]
+    fn queue_wait_measured() {
+        let mut form_data = FormData::default();
+        let received_at = crate::events::now_millis();
+        thread::sleep(std::time::Duration::from_millis(50));
+        let wait = queue_wait_ms(&form_data, received_at, crate::events::now_millis());
+        assert!((50..1000).contains(&wait), "{}", wait);
+
+        // Time on the broker counts when the server says when it enqueued the submission
+        form_data.submitted_at = Some(received_at - 2000);
         assert_eq!(
-            results.unwrap()[0].stdout,
-            "openjdk 21 2023-09-19\nOpenJDK Runtime Environment (build 21+35-2513)\nOpenJDK 64-Bit Server VM (build 21+35-2513, mixed mode, sharing)\n"
+            queue_wait_ms(&form_data, received_at, received_at + 10),
+            2010
+        );
+        // Clock skew never produces a negative wait
+        form_data.submitted_at = Some(received_at + 2000);
+        assert_eq!(queue_wait_ms(&form_data, received_at, received_at), 0);
+    }
+
+    #[test]
+    fn image_manifest_overrides_deployment() {
+        let image_manifest = images::parse_image_manifest(
+            r#"
+- image: "gcc:*"
+  limits: {time_limit: 2, memory_limit: 512000, memory_reserved: 4096000}
+  cpu_ulimit: true
+  capabilities: [SYS_PTRACE]
+  seccomp_profile: gcc.json
+  entrypoint: ""
+"#,
         )
+        .unwrap();
+        let config = SandboxConfig {
+            seccomp_profile: Some("default.json".to_string()),
+            image_manifest,
+            ..Default::default()
+        };
+        let message = r#"
+image: gcc:14.2
+submit_id: "1"
+commands:
+  - command: ./main
+    args: []
+    input: ""
+    config:
+      time_limit: 1
+      time_reserved: 1
+      large_stack: false
+      output_limit: 0
+      process_limit: 0
+"#;
+        // Without the manifest the submission is missing required limits
+        assert!(parse_form_data(&SandboxConfig::default(), message).is_err());
+        let message = apply_image_defaults(&config, message.to_string()).unwrap();
+        let form_data = parse_form_data(&config, &message).unwrap();
+        assert_eq!(form_data.commands[0].config.time_limit, 1);
+        assert_eq!(form_data.commands[0].config.memory_limit, 512000);
+
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["--cap-add", "SYS_PTRACE"]));
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["--security-opt", "seccomp=gcc.json"])
+        );
+        assert!(args.windows(2).any(|w| w == ["--entrypoint", ""]));
+        assert!(args.iter().any(|arg| arg.starts_with("cpu=")));
+
+        // Other images keep the deployment's settings
+        let form_data = FormData {
+            image: "openjdk:21",
+            ..form_data
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["--security-opt", "seccomp=default.json"])
+        );
+        assert!(!args.contains(&"--entrypoint".to_string()));
     }
 
     #[test]
-    fn java_a_add_b() {
-        let commands = vec![
+    fn invalid_results_rejected() {
+        let valid = "- {state: Success, stdout: '', stderr: '', time: 1, memory: 1024}\n";
+        assert!(parse_results(valid, 1, true).is_ok());
+        let inconsistent = [
+            (valid, 2),
+            (&valid.repeat(2), 1),
+            (
+                "- {state: Success, stdout: '', stderr: '', time: 1, memory: 1024, signal: 9}\n",
+                1,
+            ),
+        ];
+        for (output, expected) in inconsistent {
+            let error = parse_results(output, expected, true).err();
+            assert!(
+                matches!(error, Some(SandboxError::InvalidResults(_))),
+                "{}",
+                output
+            );
+        }
+        let unparsable = [
+            "- {state: Success, stdout: ''\n",
+            "- {state: Finished, stdout: '', stderr: '', time: 1, memory: 1024}\n",
+            "- {state: Success, stdout: '', stderr: '', time: -1, memory: 1024}\n",
+        ];
+        for output in unparsable {
+            let error = parse_results(output, 1, true).err();
+            assert!(
+                matches!(error, Some(SandboxError::ResultsParse(_))),
+                "{}",
+                output
+            );
+        }
+        assert!(matches!(
+            parse_results("", 1, true),
+            Err(SandboxError::ResultsMissing)
+        ));
+        // Without validation only unparsable output is rejected
+        assert_eq!(parse_results(valid, 2, false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn repeated_runs_aggregated() {
+        let commands = [
             CMD {
-                command: "bash".to_string(),
-                args: vec![
-                    "-c".to_string(),
-                    r#"echo 'import java.util.Scanner;
-public class Main {
-    public static void main(String[] args) {
-        Scanner sc = new Scanner(System.in);
-        int a = sc.nextInt();
-        int b = sc.nextInt();
-        System.out.println(a + " + " + b + " = " + (a + b));
+                command: "./bench".to_string(),
+                repeat: Some(5),
+                ..Default::default()
+            },
+            CMD {
+                command: "./main".to_string(),
+                ..Default::default()
+            },
+        ];
+        let runs = expand_runs(&commands);
+        assert_eq!(runs.len(), 6);
+        assert_eq!(
+            runs[0].for_executor(&SandboxConfig::default(), 0).repeat,
+            None
+        );
+
+        let output: String = [
+            (120, 2048),
+            (100, 1024),
+            (300, 1024),
+            (110, 4096),
+            (105, 1024),
+            (7, 512),
+        ]
+        .iter()
+        .map(|(time, memory)| {
+            format!(
+                "- {{state: Success, stdout: '', stderr: '', time: {}, memory: {}}}\n",
+                time, memory
+            )
+        })
+        .collect();
+        let results = collapse_runs(&commands, parse_results(&output, 6, true).unwrap());
+        assert_eq!(results.len(), 2);
+        let timing = results[0].timing.unwrap();
+        assert_eq!(
+            timing,
+            TimingStats {
+                runs: 5,
+                min: 100,
+                median: 110,
+                p95: 300,
+                max: 300
+            }
+        );
+        assert!(
+            timing.min <= timing.median && timing.median <= timing.p95 && timing.p95 <= timing.max
+        );
+        assert_eq!((results[0].time, results[0].memory), (110, 4096));
+        assert_eq!(results[1].timing, None);
+        assert_eq!(results[1].time, 7);
+
+        // A failed run is what gets reported, with the runs after it never started
+        let output = "- {state: Success, stdout: '', stderr: '', time: 100, memory: 1024}\n\
+                      - {state: RuntimeError, stdout: '', stderr: 'boom', time: 5, memory: 1024}\n";
+        let parsed = fill_skipped(&runs, parse_results(output, 6, true).unwrap());
+        let results = collapse_runs(&commands, parsed);
+        assert_eq!(results[0].state, ExitState::RuntimeError);
+        assert_eq!(results[0].timing.unwrap().runs, 1);
+        assert_eq!(results[1].state, ExitState::OtherError);
+
+        let config = SandboxConfig {
+            max_repeat: Some(3),
+            ..Default::default()
+        };
+        assert!(check_repeats(&config, &commands[1..]).is_ok());
+        assert_eq!(
+            check_repeats(&config, &commands).unwrap_err(),
+            "Command 0 (./bench) is repeated 5 times, at most 3 are allowed"
+        );
+        // The watchdog allows for every run
+        let once = CMD {
+            repeat: None,
+            ..commands[0].clone()
+        };
+        assert_eq!(
+            run_time_limit(&SandboxConfig::default(), &commands[..1]),
+            run_time_limit(&SandboxConfig::default(), &vec![once; 5])
+        );
     }
-}' > Main.java"#
-                        .to_string(),
-                ],
-                input: "".to_string(),
-                config: Config {
-                    time_limit: 1,
-                    time_reserved: 1,
-                    memory_limit: 256000,
-                    memory_reserved: 6144000,
-                    large_stack: false,
-                    output_limit: 0,
-                    process_limit: 0,
-                },
+
+    #[test]
+    fn conditions_split_stages() {
+        let run_if = |depends_on, outcome| {
+            Some(RunIf {
+                depends_on,
+                outcome,
+            })
+        };
+        let commands = vec![
+            CMD::default(),
+            CMD::default(),
+            CMD {
+                run_if: run_if(1, Outcome::Success),
+                ..Default::default()
             },
+            CMD::default(),
             CMD {
-                command: "javac".to_string(),
-                args: vec!["Main.java".to_string()],
-                input: "".to_string(),
-                config: Config {
-                    time_limit: 1,
-                    time_reserved: 1,
-                    memory_limit: 256000,
-                    memory_reserved: 6144000,
-                    large_stack: false,
-                    output_limit: 0,
-                    process_limit: 0,
-                },
+                run_if: run_if(1, Outcome::Failure),
+                ..Default::default()
             },
             CMD {
-                command: "java".to_string(),
-                args: vec!["Main.java".to_string()],
-                input: "1 2".to_string(),
-                config: Config {
-                    time_limit: 1,
-                    time_reserved: 1,
-                    memory_limit: 256000,
-                    memory_reserved: 6144000,
-                    large_stack: false,
-                    output_limit: 0,
-                    process_limit: 0,
-                },
+                teardown: true,
+                ..Default::default()
             },
         ];
-        let results = sandbox_service(commands, "openjdk:21");
-        assert!(results.is_ok());
-        assert_eq!(results.unwrap()[2].stdout, "1 + 2 = 3\n")
+        let bounds: Vec<Vec<_>> = execution_stages(&commands, false, false)
+            .iter()
+            .map(|stage| stage.iter().map(|lane| (lane.start, lane.end)).collect())
+            .collect();
+        assert_eq!(
+            bounds,
+            [vec![(0, 2)], vec![(2, 4)], vec![(4, 5)], vec![(5, 6)]]
+        );
+
+        let result = |state| SandboxResult {
+            state,
+            ..Default::default()
+        };
+        let built = [result(ExitState::Success), result(ExitState::Success)];
+        let broken = [result(ExitState::Success), result(ExitState::RuntimeError)];
+        let skipped = [result(ExitState::Success), condition_skipped_result()];
+        for (results, success, failure) in [
+            (&built[..], true, false),
+            (&broken[..], false, true),
+            (&skipped[..], false, false),
+            (&built[..1], false, false),
+        ] {
+            assert_eq!(run_if(1, Outcome::Success).unwrap().met(results), success);
+            assert_eq!(run_if(1, Outcome::Failure).unwrap().met(results), failure);
+        }
+        assert!(summarize(&skipped).starts_with("AC"));
+
+        assert!(check_conditions(&commands).is_ok());
+        let mut forward = commands;
+        forward[2].run_if = run_if(2, Outcome::Success);
+        assert!(check_conditions(&forward).is_err());
     }
 
     #[test]
-    fn robust0() {
+    fn run_only_after_compile_succeeded() {
+        let pipeline = |compile: &str| FormData {
+            commands: vec![
+                CMD {
+                    command: "sh".to_string(),
+                    args: vec!["-c".to_string(), compile.to_string()],
+                    ..Default::default()
+                },
+                CMD {
+                    command: "echo".to_string(),
+                    args: vec!["ran".to_string()],
+                    run_if: Some(RunIf {
+                        depends_on: 0,
+                        outcome: Outcome::Success,
+                    }),
+                    ..Default::default()
+                },
+            ],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let config = SandboxConfig::default();
+        let results = sandbox_service(&config, &pipeline("true")).unwrap();
+        assert_eq!(results[1].state, ExitState::Success);
+        assert_eq!(results[1].stdout, "ran\n");
+
+        let results = sandbox_service(&config, &pipeline("exit 1")).unwrap();
+        assert_eq!(results[0].state, ExitState::RuntimeError);
+        assert_eq!(results[1].state, ExitState::Skipped);
+        assert_eq!(results[1].stdout, "");
+    }
+
+    #[test]
+    fn skipped_commands_filled_in() {
+        let cmd = |teardown| CMD {
+            command: "./main".to_string(),
+            teardown,
+            ..Default::default()
+        };
+        let commands = [cmd(false), cmd(false), cmd(false), cmd(true)];
+        let output = "- {state: RuntimeError, stdout: '', stderr: 'boom', time: 0, memory: 1024}\n\
+                      - {state: Success, stdout: 'cleaned', stderr: '', time: 0, memory: 1024}\n";
+        let results = fill_skipped(&commands, parse_results(output, 4, true).unwrap());
+        let states: Vec<&ExitState> = results.iter().map(|result| &result.state).collect();
+        assert_eq!(
+            states,
+            [
+                &ExitState::RuntimeError,
+                &ExitState::OtherError,
+                &ExitState::OtherError,
+                &ExitState::Success
+            ]
+        );
+        assert_eq!(results[1].stderr, "Not run: an earlier command failed");
+        assert_eq!(results[3].stdout, "cleaned");
+
+        // Every command gets a result from a real run too
+        let form_data = FormData {
+            commands: vec![
+                CMD {
+                    command: "false".to_string(),
+                    ..Default::default()
+                },
+                CMD {
+                    command: "echo".to_string(),
+                    args: vec!["ok".to_string()],
+                    ..Default::default()
+                },
+            ],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let results = sandbox_service(&SandboxConfig::default(), &form_data).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].state, ExitState::RuntimeError);
+        assert!(matches!(
+            results[1].state,
+            ExitState::Success | ExitState::OtherError
+        ));
+    }
+
+    #[test]
+    fn resource_summary_attached() {
+        let repeated = CMD {
+            repeat: Some(2),
+            ..Default::default()
+        };
+        let commands = vec![CMD::default(), repeated, CMD::default()];
+        let output = "- {state: Success, stdout: 'hello', stderr: '', time: 10, memory: 2048}\n\
+                      - {state: Success, stdout: '', stderr: '', time: 20, memory: 1024}\n\
+                      - {state: OtherError, stdout: '', stderr: '', time: 0, memory: 0}\n";
+        let mut results = parse_results(output, 3, true).unwrap();
+        // The last command wasn't run, so left no line
+        let summary = "- {run: 0, wall_time: 15, cpu_time: 10, output_bytes: 5, peak_processes: 1}\n\
+                       - {run: 1, wall_time: 40, cpu_time: 20, output_bytes: 0, peak_processes: 2}\n\
+                       - {run: 2, wall_time: 25, cpu_time: 30, output_bytes: 0, peak_processes: 2}\n";
+        attach_resources(&commands, summary, &mut results);
+        let resources = results[0].resources.unwrap();
+        assert_eq!(resources.memory.limit, commands[0].config.memory_limit);
+        assert_eq!(resources.memory.observed, Some(2048));
+        assert_eq!(resources.output.observed, Some(5));
+        assert_eq!(resources.processes.observed, Some(1));
+        let repeated = results[1].resources.unwrap();
+        assert_eq!(repeated.wall_time.observed, Some(40));
+        assert_eq!(repeated.time.observed, Some(30));
+        // The container's peak may be the first command's
+        assert_eq!(repeated.processes.observed, None);
+        let skipped = results[2].resources.unwrap();
+        assert_eq!(skipped.wall_time.observed, None);
+        assert_eq!(skipped.time.observed, Some(0));
+    }
+
+    #[test]
+    fn resource_summary_written() {
+        let config = SandboxConfig {
+            resource_summary: true,
+            ..Default::default()
+        };
+        let form_data = FormData {
+            commands: vec![cmd("sh", &["-c", "echo hi; echo oops >&2; sleep 0.3"])],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let results = sandbox_service(&config, &form_data).unwrap();
+        // The report line is cut from stderr either way
+        assert_eq!(results[0].stderr, "oops\n");
+        let resources = results[0].resources.unwrap();
+        assert_eq!(resources.output.observed, Some(8));
+        let wall_time = resources.wall_time.observed.unwrap();
+        assert!(wall_time >= 300, "{}", wall_time);
+        assert!(resources.time.observed.unwrap() < wall_time);
+    }
+
+    #[test]
+    fn executor_stderr_in_rejection() {
+        let config = SandboxConfig {
+            executor_stderr: true,
+            ..Default::default()
+        };
+        let error = SandboxError::InvalidResults("missing results".to_string());
+        let error = with_executor_stderr(&config, error, "sandbox: failed to open commands.yaml\n");
+        assert_eq!(
+            error.to_string(),
+            "Invalid executor results: missing results; executor stderr: sandbox: failed to open commands.yaml"
+        );
+        let error = SandboxError::InvalidResults("missing results".to_string());
+        let error = with_executor_stderr(&SandboxConfig::default(), error, "sandbox: failed\n");
+        assert_eq!(
+            error.to_string(),
+            "Invalid executor results: missing results"
+        );
+    }
+
+    #[test]
+    fn executor_stderr_returned() {
+        let config = SandboxConfig {
+            executor_stderr: true,
+            ..Default::default()
+        };
+        // The program's own stderr stays in the result, apart from the executor's
         let commands = vec![CMD {
-            command: "reboot".to_string(),
-            args: vec![],
-            input: "".to_string(),
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), "echo program >&2".to_string()],
+            ..Default::default()
+        }];
+        let form_data = FormData {
+            commands,
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let results = sandbox_service(&config, &form_data).unwrap();
+        assert_eq!(results[0].stderr, "program\n");
+        let executor_stderr = results[0].executor_stderr.as_deref().unwrap();
+        assert!(!executor_stderr.contains("program"));
+        let results = sandbox_service(&SandboxConfig::default(), &form_data).unwrap();
+        assert_eq!(results[0].executor_stderr, None);
+    }
+
+    #[test]
+    fn daemon_unavailable_retryable() {
+        let failed = |stderr: &str, code: i32| {
+            Command::new("sh")
+                .arg("-c")
+                .arg(format!("echo '{}' >&2; exit {}", stderr, code))
+                .output()
+        };
+        let restarting = failed(
+            "docker: Cannot connect to the Docker daemon at unix:///var/run/docker.sock. Is the docker daemon running?",
+            125,
+        );
+        let error = docker_unavailable(&restarting).unwrap();
+        assert!(error.is_retryable());
+        assert!(
+            error
+                .to_string()
+                .starts_with("Docker daemon unavailable: docker: Cannot connect")
+        );
+        let lost = failed("error waiting for container: unexpected EOF", 125);
+        assert!(docker_unavailable(&lost).is_some_and(|e| e.is_retryable()));
+        let missing = Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(docker_unavailable(&missing).is_some_and(|e| e.is_retryable()));
+
+        // Failures of the program itself aren't the daemon's
+        assert!(docker_unavailable(&failed("Segmentation fault", 139)).is_none());
+        assert!(docker_unavailable(&failed("error during connect", 0)).is_none());
+        assert!(!SandboxError::InvalidResults(String::new()).is_retryable());
+    }
+
+    #[test]
+    fn invalid_submission_is_error() {
+        // A malformed job fails on its own instead of taking the runner down
+        let form_data = FormData {
+            env_file: Some("not an assignment".to_string()),
+            ..Default::default()
+        };
+        let error = sandbox_service(&SandboxConfig::default(), &form_data).err();
+        assert!(matches!(error, Some(SandboxError::InvalidSubmission(_))));
+        assert!(!error.unwrap().is_retryable());
+    }
+
+    #[test]
+    fn preflight_reports_missing_files() {
+        let root = std::env::temp_dir().join(format!("inputs-{}", Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("1.in"), "1 2\n").unwrap();
+        let cmd = |input_ref: &str| CMD {
+            command: "./main".to_string(),
+            input_ref: Some(input_ref.to_string()),
+            ..Default::default()
+        };
+        let form_data = FormData {
+            commands: vec![cmd("1.in"), cmd("2.in"), cmd("3.in")],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let config = SandboxConfig {
+            input_store: Some(Arc::new(LocalStore::new(&root))),
+            preflight_files: true,
+            ..Default::default()
+        };
+        let error = sandbox_service(&config, &form_data).err();
+        fs::remove_dir_all(root).unwrap();
+        assert_eq!(
+            error.map(|e| e.to_string()).as_deref(),
+            Some("Invalid submission: Missing files: 2.in, 3.in")
+        );
+    }
+
+    #[test]
+    fn watchdog_kills_hung_run() {
+        let config = SandboxConfig {
+            run_time_grace: 1,
+            ..Default::default()
+        };
+        let cmd = |time_limit, wall_time_limit| CMD {
             config: Config {
-                time_limit: 1,
+                time_limit,
                 time_reserved: 1,
-                memory_limit: 256000,
-                memory_reserved: 6144000,
-                large_stack: false,
-                output_limit: 0,
-                process_limit: 0,
+                wall_time_limit,
+                ..Default::default()
             },
-        }];
-        let results = sandbox_service(commands, "gcc:14.2");
-        assert!(results.is_ok());
+            ..Default::default()
+        };
+        let commands = [cmd(1, None), cmd(2, Some(5))];
         assert_eq!(
-            format!("{:?}", results.unwrap()),
-            r#"[SandboxResult { state: OtherError, stdout: "", stderr: "Error occurred", time: 0, memory: 0 }]"#
+            run_time_limit(&config, &commands),
+            Duration::from_secs(2 + 5 + 1)
+        );
+        let capped = SandboxConfig {
+            max_run_time: Some(3),
+            ..config.clone()
+        };
+        assert_eq!(
+            run_time_limit(&capped, &commands),
+            Duration::from_secs(3 + 1)
+        );
+
+        // A child outliving its timeout is killed instead of blocking the runner
+        let started = Instant::now();
+        let mut hung = Command::new("sleep");
+        hung.arg("30");
+        let output = output_with_timeout(
+            "docker",
+            &mut hung,
+            "sandbox-watchdog-test",
+            Duration::from_millis(200),
+        );
+        assert!(output.unwrap().is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let mut quick = Command::new("sh");
+        quick.args(["-c", "echo diagnostics >&2"]);
+        let output = output_with_timeout(
+            "docker",
+            &mut quick,
+            "sandbox-watchdog-test",
+            Duration::from_secs(5),
         );
+        assert_eq!(output.unwrap().unwrap().stderr, b"diagnostics\n");
+
+        let result = timed_out_result(Duration::from_secs(9));
+        assert_eq!(result.state, ExitState::TimeLimitExceeded);
+        assert_eq!(result.limit_hit, Some(LimitKind::Time));
+        assert_eq!(result.time, 9);
     }
 
     #[test]
-    fn robust1() {
+    fn runtime_configurable() {
+        let podman = SandboxConfig {
+            container_runtime: "/usr/bin/podman".to_string(),
+            ..Default::default()
+        };
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let command =
+            docker_command(&podman, &form_data, &form_data.commands, "tmp", None).unwrap();
+        assert_eq!(command.get_program(), "/usr/bin/podman");
+        assert_eq!(PipelineManifest::new(&podman, &form_data).runtime, "podman");
+
+        let missing = SandboxConfig {
+            container_runtime: "no-such-runtime".to_string(),
+            ..Default::default()
+        };
+        let error = verify_runtime(&missing).unwrap_err();
+        assert!(error.contains("not found on PATH"), "{}", error);
+        let version = verify_runtime(&SandboxConfig::default()).unwrap();
+        assert!(version.starts_with("Docker version"), "{}", version);
+    }
+
+    #[test]
+    fn container_named_after_run() {
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let args = docker_args(&SandboxConfig::default(), &form_data);
+        assert!(args.windows(2).any(|w| w == ["--name", "sandbox-tmp"]));
+        assert_eq!(
+            container_name("tenants/acme/1234", Some(2)),
+            "sandbox-1234-lane-2"
+        );
+        let flags = docker_flags(&SandboxConfig::default(), &form_data).unwrap();
+        assert!(!flags.contains(&"--name".to_string()));
+    }
+
+    #[test]
+    fn run_cleaned_up_on_panic() {
+        let tmp_folder = Uuid::new_v4().to_string();
+        fs::create_dir_all(format!("{}/lane-0", tmp_folder)).unwrap();
+        let panicked = std::panic::catch_unwind(|| {
+            let _guard = RunGuard {
+                runtime: "docker".to_string(),
+                tmp_folder: tmp_folder.clone(),
+                containers: vec![container_name(&tmp_folder, None)],
+            };
+            panic!("executor crashed");
+        });
+        assert!(panicked.is_err());
+        assert!(!Path::new(&tmp_folder).exists());
+    }
+
+    #[test]
+    fn home_directory() {
+        let form_data = FormData {
+            commands: vec![CMD::default()],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        assert!(!docker_args(&SandboxConfig::default(), &form_data).contains(&"-e".to_string()));
+        let config = SandboxConfig {
+            home: Some(".home".to_string()),
+            ..Default::default()
+        };
+        let args = docker_args(&config, &form_data);
+        assert!(args.windows(2).any(|w| w == ["-e", "HOME=/sandbox/.home"]));
+        for home in ["", "/root", "../home", "a/./b"] {
+            let config = SandboxConfig {
+                home: Some(home.to_string()),
+                ..Default::default()
+            };
+            assert!(
+                docker_command(&config, &form_data, &form_data.commands, "tmp", None).is_err(),
+                "{}",
+                home
+            );
+        }
+    }
+
+    #[test]
+    fn writable_home() {
         let commands = vec![CMD {
-            command: "rm".to_string(),
-            args: vec!["-rf".to_string(), "/*".to_string()],
-            input: "".to_string(),
-            config: Config {
-                time_limit: 1,
-                time_reserved: 1,
-                memory_limit: 256000,
-                memory_reserved: 6144000,
-                large_stack: false,
-                output_limit: 0,
-                process_limit: 0,
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "mkdir -p $HOME/.cache && echo cached > $HOME/.cache/file && cat $HOME/.cache/file"
+                    .to_string(),
+            ],
+            ..Default::default()
+        }];
+        let config = SandboxConfig {
+            home: Some(".home".to_string()),
+            ..Default::default()
+        };
+        let results = sandbox_service(
+            &config,
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
             },
+        );
+        assert!(results.is_ok());
+        let results = results.unwrap();
+        assert_eq!(results[0].state, ExitState::Success);
+        assert_eq!(results[0].stdout, "cached\n");
+    }
+
+    #[test]
+    fn input_fetched_by_reference() {
+        let root = std::env::temp_dir().join(format!("inputs-{}", Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        let input: String = (0..100_000).map(|n| format!("{}\n", n)).collect();
+        fs::write(root.join("numbers.txt"), &input).unwrap();
+        let commands = vec![CMD {
+            command: "wc".to_string(),
+            args: vec!["-l".to_string()],
+            input_ref: Some("numbers.txt".to_string()),
+            ..Default::default()
         }];
-        let results = sandbox_service(commands, "gcc:14.2");
+        let config = SandboxConfig {
+            input_store: Some(Arc::new(LocalStore::new(&root))),
+            ..Default::default()
+        };
+        let results = sandbox_service(
+            &config,
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                ..Default::default()
+            },
+        );
+        fs::remove_dir_all(root).unwrap();
         assert!(results.is_ok());
+        assert_eq!(results.unwrap()[0].stdout, "100000\n");
+    }
+
+    #[test]
+    fn workspace_built() {
+        let mut project = tar::Builder::new(vec![]);
+        let mut add = |path: &str, contents: &str| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            project
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        };
+        add("include/add.h", "int add(int a, int b);\n");
+        add("src/add.c", "int add(int a, int b) { return a + b; }\n");
+        add(
+            "src/main.c",
+            "#include <stdio.h>\n#include \"add.h\"\nint main() { printf(\"%d\\n\", add(1, 2)); }\n",
+        );
+        let workspace = BASE64_STANDARD.encode(project.into_inner().unwrap());
+        let config = Config {
+            time_limit: 5,
+            time_reserved: 1,
+            memory_limit: 256000,
+            memory_reserved: 4096000,
+            ..Default::default()
+        };
+        let commands = vec![
+            CMD {
+                command: "gcc".to_string(),
+                args: ["-Iinclude", "src/main.c", "src/add.c", "-o", "main"]
+                    .map(String::from)
+                    .to_vec(),
+                config: config.clone(),
+                ..Default::default()
+            },
+            CMD {
+                command: "./main".to_string(),
+                config,
+                ..Default::default()
+            },
+        ];
+        let results = sandbox_service(
+            &SandboxConfig::default(),
+            &FormData {
+                commands,
+                image: "gcc:14.2",
+                workspace: Some(workspace),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(results[1].stdout, "3\n");
+    }
+
+    #[test]
+    fn docker_wall_time_reported() {
+        let sh = |script| cmd("sh", &["-c", script]);
+        let form_data = FormData {
+            commands: vec![sh("sleep 1"), sh("true")],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let run = sandbox_service_with_progress(&SandboxConfig::default(), &form_data, &mut |_| {})
+            .unwrap();
+        assert_eq!(run.results.len(), 2);
+        // The container's run covers the program's own time and then some
+        assert!(run.wall_time_ms >= 1000, "{}", run.wall_time_ms);
+    }
+
+    #[test]
+    fn updates_match_final_results() {
+        let judged = CMD {
+            expected_outputs: vec!["3\n".to_string()],
+            discard_output: true,
+            ..cmd("echo", &["3"])
+        };
+        let form_data = FormData {
+            commands: vec![judged, cmd("echo", &["4"])],
+            image: "gcc:14.2",
+            incremental: true,
+            ..Default::default()
+        };
+        let mut updates = vec![];
+        let run =
+            sandbox_service_with_progress(&SandboxConfig::default(), &form_data, &mut |progress| {
+                if let Progress::Result(index, result) = progress {
+                    updates.push((index, result.clone()));
+                }
+            })
+            .unwrap();
+        assert_eq!(updates.len(), 2);
+        // Judged before its output was dropped, in the update as in the response
+        assert_eq!(updates[0].1.verdict, Some(Verdict::Accepted));
+        assert_eq!(updates[0].1.stdout, "");
+        for (index, update) in &updates {
+            assert_eq!(
+                serde_json::to_value(update).unwrap(),
+                serde_json::to_value(&run.results[*index]).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn total_time_budget_stops_later_commands() {
+        let sh = |script| cmd("sh", &["-c", script]);
+        let busy = sh("i=0; while [ $i -lt 300000 ]; do i=$((i + 1)); done");
+        let config = SandboxConfig {
+            total_time_limit: Some(200),
+            ..Default::default()
+        };
+        let form_data = FormData {
+            commands: vec![busy, sh("sleep 10"), sh("echo late")],
+            image: "gcc:14.2",
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let results = sandbox_service(&config, &form_data).unwrap();
+        // Sleeping alone would have taken longer than the whole run
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(results[0].time > 200, "{}", results[0].time);
+        for result in &results {
+            assert_eq!(result.state, ExitState::TotalTimeLimitExceeded);
+        }
+        for result in &results[1..] {
+            assert_eq!((result.time, result.stdout.as_str()), (0, ""));
+        }
     }
 }
@@ -0,0 +1,149 @@
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::service::CMD;
+
+// Where large test inputs live, so submissions can name them by key instead of
+// carrying them through the broker
+pub trait ObjectStore: fmt::Debug + Send + Sync {
+    fn fetch(&self, key: &str) -> Result<Vec<u8>, String>;
+
+    // Stores that can check for an object without downloading it should override this
+    fn exists(&self, key: &str) -> bool {
+        self.fetch(key).is_ok()
+    }
+}
+
+// Store backed by a directory, e.g. a mounted bucket or a shared volume
+#[derive(Debug)]
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStore { root: root.into() }
+    }
+}
+
+impl LocalStore {
+    // Keys are relative paths that must stay inside the root
+    fn path(&self, key: &str) -> Result<PathBuf, String> {
+        let relative = Path::new(key);
+        if key.is_empty()
+            || !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!("Invalid object key {:?}", key));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl ObjectStore for LocalStore {
+    fn fetch(&self, key: &str) -> Result<Vec<u8>, String> {
+        fs::read(self.path(key)?).map_err(|e| format!("Failed to fetch object {:?}: {}", key, e))
+    }
+
+    fn exists(&self, key: &str) -> bool {
+        self.path(key).is_ok_and(|path| path.is_file())
+    }
+}
+
+// Every `input_ref` that isn't in the store, in command order and without repeats
+pub fn missing_inputs(store: Option<&dyn ObjectStore>, commands: &[CMD]) -> Vec<String> {
+    let mut missing: Vec<String> = vec![];
+    for key in commands.iter().filter_map(|cmd| cmd.input_ref.as_ref()) {
+        if !store.is_some_and(|store| store.exists(key)) && !missing.contains(key) {
+            missing.push(key.clone());
+        }
+    }
+    missing
+}
+
+// Replace each command's `input_ref` with the object it names
+pub fn resolve_input_refs(
+    store: Option<&dyn ObjectStore>,
+    commands: &mut [CMD],
+) -> Result<(), String> {
+    for cmd in commands {
+        let Some(key) = cmd.input_ref.take() else {
+            continue;
+        };
+        if !cmd.input.is_empty() {
+            return Err(format!(
+                "Command {} has both input and input_ref",
+                cmd.command
+            ));
+        }
+        let store = store.ok_or("input_ref given but no input store is configured")?;
+        cmd.input = String::from_utf8(store.fetch(&key)?)
+            .map_err(|_| format!("Object {:?} is not valid UTF-8", key))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod storage_test {
+    use super::*;
+
+    #[test]
+    fn input_ref_resolved_from_store() {
+        let root = std::env::temp_dir().join(format!("store-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("inputs")).unwrap();
+        let input = "1 2\n".repeat(100_000);
+        fs::write(root.join("inputs/large.txt"), &input).unwrap();
+        let store = LocalStore::new(&root);
+
+        let mut commands = vec![
+            CMD {
+                input_ref: Some("inputs/large.txt".to_string()),
+                ..Default::default()
+            },
+            CMD {
+                input: "inline".to_string(),
+                ..Default::default()
+            },
+        ];
+        resolve_input_refs(Some(&store), &mut commands).unwrap();
+        assert_eq!(commands[0].input, input);
+        assert_eq!(commands[0].input_ref, None);
+        assert_eq!(commands[1].input, "inline");
+
+        let with_ref = |key: &str| CMD {
+            input_ref: Some(key.to_string()),
+            ..Default::default()
+        };
+        assert!(resolve_input_refs(Some(&store), &mut [with_ref("missing.txt")]).is_err());
+        assert!(resolve_input_refs(Some(&store), &mut [with_ref("../etc/passwd")]).is_err());
+        assert!(resolve_input_refs(None, &mut [with_ref("inputs/large.txt")]).is_err());
+        let mut both = with_ref("inputs/large.txt");
+        both.input = "inline".to_string();
+        assert!(resolve_input_refs(Some(&store), &mut [both]).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn missing_inputs_listed() {
+        let root = std::env::temp_dir().join(format!("store-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("present.txt"), "1 2\n").unwrap();
+        let store = LocalStore::new(&root);
+        let commands: Vec<CMD> = ["present.txt", "tests/1.in", "present.txt", "tests/2.in"]
+            .iter()
+            .map(|key| CMD {
+                input_ref: Some(key.to_string()),
+                ..Default::default()
+            })
+            .collect();
+        assert_eq!(
+            missing_inputs(Some(&store), &commands),
+            ["tests/1.in", "tests/2.in"]
+        );
+        assert_eq!(missing_inputs(Some(&store), &commands[..1]).len(), 0);
+        assert_eq!(missing_inputs(None, &commands[..1]), ["present.txt"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt;
+
+use serde::Deserialize;
+use serde::de::{self, Deserializer, Visitor};
+use serde_yaml::Value;
+
+use crate::service::{CMD, Config, FileEntry, FormData, RunIf};
+
+// Error carrying the field names a derived `Deserialize` asked for
+#[derive(Debug)]
+struct FieldNames(&'static [&'static str]);
+
+impl fmt::Display for FieldNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl std::error::Error for FieldNames {}
+
+impl de::Error for FieldNames {
+    fn custom<T: fmt::Display>(_: T) -> Self {
+        FieldNames(&[])
+    }
+}
+
+// Deserializer that only records the field list passed to `deserialize_struct`
+struct FieldCapture;
+
+impl<'de> Deserializer<'de> for FieldCapture {
+    type Error = FieldNames;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, FieldNames> {
+        Err(FieldNames(&[]))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, FieldNames> {
+        Err(FieldNames(fields))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+        identifier ignored_any
+    }
+}
+
+// Field names accepted by a struct deriving `Deserialize`
+pub fn struct_fields<T: Deserialize<'static>>() -> &'static [&'static str] {
+    T::deserialize(FieldCapture)
+        .err()
+        .map_or(&[], |names| names.0)
+}
+
+fn unknown_keys(value: &Value, fields: &[&str], path: &str, unknown: &mut Vec<String>) {
+    let Some(mapping) = value.as_mapping() else {
+        return;
+    };
+    for key in mapping.keys() {
+        let key = key
+            .as_str()
+            .map_or_else(|| format!("{:?}", key), str::to_string);
+        if !fields.contains(&key.as_str()) {
+            unknown.push(format!("{}{}", path, key));
+        }
+    }
+}
+
+// Paths of keys in a submission that `FormData`, `CMD`, `Config`, `FileEntry` or `RunIf`
+// don't define, e.g. `commands[0].config.memory_limt`
+pub fn unknown_fields(submission: &Value) -> Vec<String> {
+    let mut unknown = vec![];
+    unknown_keys(submission, struct_fields::<FormData>(), "", &mut unknown);
+    let commands = submission.get("commands").and_then(Value::as_sequence);
+    for (index, command) in commands.into_iter().flatten().enumerate() {
+        let path = format!("commands[{}].", index);
+        unknown_keys(command, struct_fields::<CMD>(), &path, &mut unknown);
+        if let Some(config) = command.get("config") {
+            let path = format!("{}config.", path);
+            unknown_keys(config, struct_fields::<Config>(), &path, &mut unknown);
+        }
+        let files = command.get("files").and_then(Value::as_sequence);
+        for (file_index, file) in files.into_iter().flatten().enumerate() {
+            let path = format!("{}files[{}].", path, file_index);
+            unknown_keys(file, struct_fields::<FileEntry>(), &path, &mut unknown);
+        }
+        if let Some(run_if) = command.get("run_if") {
+            let path = format!("{}run_if.", path);
+            unknown_keys(run_if, struct_fields::<RunIf>(), &path, &mut unknown);
+        }
+    }
+    unknown
+}
+
+#[cfg(test)]
+mod strict_test {
+    use super::*;
+
+    #[test]
+    fn typo_reported_with_path() {
+        let submission: Value = serde_yaml::from_str(
+            r#"
+image: gcc:14.2
+submit_id: "1"
+platfrom: linux/amd64
+commands:
+  - command: gcc
+    args: ["--version"]
+    input: ""
+    files:
+      - {path: input.txt, contnet: "1 2"}
+    run_if: {depends_on: 0, outcom: Failure}
+    config:
+      time_limit: 1
+      time_reserved: 1
+      memory_limt: 256000
+      memory_reserved: 4096000
+      large_stack: false
+      output_limit: 0
+      process_limit: 0
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            unknown_fields(&submission),
+            [
+                "platfrom",
+                "commands[0].config.memory_limt",
+                "commands[0].files[0].contnet",
+                "commands[0].run_if.outcom"
+            ]
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+// Metrics served on `/metrics` in the Prometheus text format
+const JOBS: &str = "runner_jobs_total";
+const RESULTS: &str = "runner_results_total";
+const DOCKER_RUN_SECONDS: &str = "runner_docker_run_seconds";
+const IN_FLIGHT: &str = "runner_jobs_in_flight";
+const BROKER_RECONNECTS: &str = "runner_broker_reconnects_total";
+
+// Most runs are short compilations and test cases, the rest hit a time limit
+const DOCKER_RUN_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+fn builder() -> Result<PrometheusBuilder, BuildError> {
+    PrometheusBuilder::new().set_buckets_for_metric(
+        Matcher::Full(DOCKER_RUN_SECONDS.to_string()),
+        DOCKER_RUN_BUCKETS,
+    )
+}
+
+// Record metrics from here on, returning the handle rendering them. Without it recording is
+// a no-op. Must be called from within the tokio runtime, which runs the recorder's upkeep.
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    let handle = builder()?.install_recorder()?;
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            upkeep.run_upkeep();
+        }
+    });
+    Ok(handle)
+}
+
+// A submission was handled, `failed` when it produced no results
+pub fn job_processed(failed: bool) {
+    let outcome = if failed { "failed" } else { "completed" };
+    counter!(JOBS, "outcome" => outcome).increment(1);
+}
+
+// A command finished in `state`, e.g. `TimeLimitExceeded`
+pub fn result_recorded(state: String) {
+    counter!(RESULTS, "state" => state).increment(1);
+}
+
+pub fn docker_run_finished(elapsed: Duration) {
+    histogram!(DOCKER_RUN_SECONDS).record(elapsed.as_secs_f64());
+}
+
+pub fn in_flight_changed(count: usize) {
+    gauge!(IN_FLIGHT).set(count as f64);
+}
+
+pub fn broker_reconnected() {
+    counter!(BROKER_RECONNECTS).increment(1);
+}
+
+#[cfg(test)]
+mod telemetry_test {
+    use super::*;
+
+    #[test]
+    fn rendered_for_prometheus() {
+        let recorder = builder().unwrap().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            job_processed(false);
+            job_processed(false);
+            job_processed(true);
+            result_recorded("Success".to_string());
+            result_recorded("TimeLimitExceeded".to_string());
+            docker_run_finished(Duration::from_millis(1500));
+            in_flight_changed(3);
+            broker_reconnected();
+        });
+        let rendered = handle.render();
+        for line in [
+            "runner_jobs_total{outcome=\"completed\"} 2",
+            "runner_jobs_total{outcome=\"failed\"} 1",
+            "runner_results_total{state=\"TimeLimitExceeded\"} 1",
+            "runner_docker_run_seconds_bucket{le=\"2\"} 1",
+            "runner_docker_run_seconds_bucket{le=\"1\"} 0",
+            "runner_jobs_in_flight 3",
+            "runner_broker_reconnects_total 1",
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{}\n{}",
+                line,
+                rendered
+            );
+        }
+    }
+}
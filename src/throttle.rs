@@ -0,0 +1,114 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// Token bucket refilled at `rate` tokens per second, holding at most `burst`. Reservations
+// may overdraw it, which makes later ones wait their turn instead of racing for tokens.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32, now: Instant) -> Self {
+        let burst = f64::from(burst.max(1));
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    // Take a token, returning how long to wait before using it
+    pub fn reserve(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+// Spreads result publishing out to a steady rate, so a burst of finished submissions
+// doesn't reach the server all at once. Publishes waiting for a token queue up in order.
+pub struct Throttle(Mutex<TokenBucket>);
+
+impl Throttle {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Throttle(Mutex::new(TokenBucket::new(rate, burst, Instant::now())))
+    }
+
+    // Enabled by `PUBLISH_RATE` (results per second); `PUBLISH_BURST` results, 1 by
+    // default, may go out at once after a quiet period
+    pub fn from_env() -> Option<Self> {
+        let rate: f64 = std::env::var("PUBLISH_RATE").ok()?.parse().ok()?;
+        if !rate.is_finite() || rate <= 0.0 {
+            return None;
+        }
+        let burst = std::env::var("PUBLISH_BURST")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(1);
+        Some(Throttle::new(rate, burst))
+    }
+
+    pub async fn acquire(&self) {
+        let wait = self.0.lock().unwrap().reserve(Instant::now());
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod throttle_test {
+    use std::sync::Arc;
+
+    use tokio::task::JoinSet;
+
+    use super::*;
+
+    #[test]
+    fn reservations_paced_after_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2, start);
+        let waits: Vec<f64> = (0..5)
+            .map(|_| bucket.reserve(start).as_secs_f64())
+            .collect();
+        for (wait, expected) in waits.iter().zip([0.0, 0.0, 0.1, 0.2, 0.3]) {
+            assert!((wait - expected).abs() < 1e-9, "{:?}", waits);
+        }
+        // Idle time refills the bucket, but never beyond the burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.reserve(later), Duration::ZERO);
+        assert_eq!(bucket.reserve(later), Duration::ZERO);
+        assert!(bucket.reserve(later) > Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_smoothed_to_rate() {
+        let throttle = Arc::new(Throttle::new(50.0, 1));
+        let start = Instant::now();
+        let mut publishes = JoinSet::new();
+        for _ in 0..6 {
+            let throttle = throttle.clone();
+            publishes.spawn(async move {
+                throttle.acquire().await;
+                start.elapsed()
+            });
+        }
+        let mut sent: Vec<Duration> = publishes.join_all().await;
+        sent.sort();
+        // One right away, then one every 20 ms, to the timer's millisecond
+        for (index, at) in sent.iter().enumerate() {
+            let due = Duration::from_millis(20 * index as u64);
+            assert!(at.abs_diff(due) <= Duration::from_millis(1), "{:?}", sent);
+        }
+    }
+}
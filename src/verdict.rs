@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+
+// Outcome of comparing a command's stdout against its expected output
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Accepted,
+    WrongAnswer,
+    // Judging was asked for, but the command has no expected output to compare against
+    NoExpectedOutput,
+}
+
+// How actual and expected output are compared
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Comparator {
+    // Byte-for-byte equality
+    #[default]
+    Exact,
+    // Ignores trailing whitespace on each line and trailing blank lines
+    IgnoreTrailingWhitespace,
+    // Compares whitespace-separated tokens
+    Tokens,
+    // Compares tokens like `Tokens`, except that numbers match when within an absolute or
+    // relative error of `FLOAT_TOLERANCE`
+    Float,
+}
+
+const FLOAT_TOLERANCE: f64 = 1e-6;
+
+fn floats_match(actual: &str, expected: &str) -> bool {
+    match (actual.parse::<f64>(), expected.parse::<f64>()) {
+        (Ok(actual), Ok(expected)) if actual.is_finite() && expected.is_finite() => {
+            (actual - expected).abs() <= FLOAT_TOLERANCE * expected.abs().max(1.0)
+        }
+        _ => actual == expected,
+    }
+}
+
+impl Comparator {
+    pub fn matches(self, actual: &str, expected: &str) -> bool {
+        match self {
+            Comparator::Exact => actual == expected,
+            Comparator::IgnoreTrailingWhitespace => {
+                let trimmed = |text: &str| -> Vec<String> {
+                    let mut lines: Vec<String> = text
+                        .lines()
+                        .map(|line| line.trim_end().to_string())
+                        .collect();
+                    while lines.last().is_some_and(|line| line.is_empty()) {
+                        lines.pop();
+                    }
+                    lines
+                };
+                trimmed(actual) == trimmed(expected)
+            }
+            Comparator::Tokens => actual.split_whitespace().eq(expected.split_whitespace()),
+            Comparator::Float => {
+                let actual: Vec<&str> = actual.split_whitespace().collect();
+                let expected: Vec<&str> = expected.split_whitespace().collect();
+                actual.len() == expected.len()
+                    && actual
+                        .iter()
+                        .zip(&expected)
+                        .all(|(a, e)| floats_match(a, e))
+            }
+        }
+    }
+}
+
+// Accepted when `actual` matches any of the accepted answers, `None` when there are none
+pub fn judge(comparator: Comparator, actual: &str, expected: &[String]) -> Option<Verdict> {
+    if expected.is_empty() {
+        return None;
+    }
+    if expected
+        .iter()
+        .any(|answer| comparator.matches(actual, answer))
+    {
+        Some(Verdict::Accepted)
+    } else {
+        Some(Verdict::WrongAnswer)
+    }
+}
+
+#[cfg(test)]
+mod verdict_test {
+    use super::*;
+
+    #[test]
+    fn matches_second_accepted_answer() {
+        let expected = vec!["YES\n".to_string(), "yes\n".to_string()];
+        assert_eq!(
+            judge(Comparator::Exact, "yes\n", &expected),
+            Some(Verdict::Accepted)
+        );
+        assert_eq!(
+            judge(Comparator::Exact, "Yes\n", &expected),
+            Some(Verdict::WrongAnswer)
+        );
+        assert_eq!(judge(Comparator::Exact, "yes\n", &[]), None);
+    }
+
+    #[test]
+    fn comparators() {
+        assert!(!Comparator::Exact.matches("1 2 \n\n", "1 2\n"));
+        assert!(Comparator::IgnoreTrailingWhitespace.matches("1 2 \n\n", "1 2\n"));
+        assert!(!Comparator::IgnoreTrailingWhitespace.matches("1  2\n", "1 2\n"));
+        assert!(Comparator::Tokens.matches("1  2\n3", "1 2 3\n"));
+        assert!(!Comparator::Tokens.matches("1 2", "1 2 3"));
+        assert!(Comparator::Float.matches("0.30000000000000004\n", "0.3\n"));
+        assert!(Comparator::Float.matches("1e6 x", "1000000.5 x"));
+        assert!(!Comparator::Float.matches("0.31", "0.3"));
+        assert!(!Comparator::Float.matches("0.3 y", "0.3 x"));
+        assert!(!Comparator::Float.matches("inf", "1e308"));
+    }
+}
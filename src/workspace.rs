@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use flate2::read::GzDecoder;
+use tar::{Archive, EntryType};
+
+// Ceilings on what a workspace may unpack to
+#[derive(Clone, Copy, Debug)]
+pub struct WorkspaceLimits {
+    pub max_bytes: u64,
+    pub max_entries: usize,
+}
+
+// Top-level names the runner writes itself, which a workspace must not provide
+const RESERVED: &[&str] = &[
+    "sandbox",
+    "commands.yaml",
+    "results.yaml",
+    "resources.yaml",
+    ".env",
+];
+
+// Whether a top-level name in the working directory belongs to the runner
+fn is_reserved(top: &str) -> bool {
+    RESERVED.contains(&top) || top.starts_with("lane-")
+}
+
+// Rejects paths that leave the working directory or name something the runner writes
+pub fn check_path(path: &Path) -> Result<(), String> {
+    let normal = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    let top = path
+        .components()
+        .find_map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .ok_or_else(|| format!("Invalid workspace entry {:?}", path))?;
+    if !normal {
+        return Err(format!("Workspace entry {:?} leaves the workspace", path));
+    }
+    if is_reserved(top) {
+        return Err(format!("Workspace entry {:?} is reserved", path));
+    }
+    Ok(())
+}
+
+// Unpack a tar archive, gzipped or not, into `dest`. Only plain files and directories
+// are accepted, so no link can point outside of `dest`. Everything is made writable by
+// the sandbox user.
+pub fn extract(archive: &[u8], dest: &Path, limits: WorkspaceLimits) -> Result<(), String> {
+    let reader: Box<dyn Read + '_> = if archive.starts_with(&[0x1f, 0x8b]) {
+        Box::new(GzDecoder::new(archive))
+    } else {
+        Box::new(archive)
+    };
+    let mut archive = Archive::new(reader);
+    let entries = archive
+        .entries()
+        .map_err(|e| format!("Invalid workspace: {}", e))?;
+    let mut bytes = 0;
+    for (index, entry) in entries.enumerate() {
+        if index >= limits.max_entries {
+            return Err(format!(
+                "Workspace has more than {} entries",
+                limits.max_entries
+            ));
+        }
+        let mut entry = entry.map_err(|e| format!("Invalid workspace: {}", e))?;
+        let path = entry
+            .path()
+            .map_err(|e| format!("Invalid workspace: {}", e))?
+            .into_owned();
+        check_path(&path)?;
+        let target = dest.join(&path);
+        match entry.header().entry_type() {
+            EntryType::Directory => {
+                fs::create_dir_all(&target).map_err(|e| e.to_string())?;
+                fs::set_permissions(&target, fs::Permissions::from_mode(0o777))
+                    .map_err(|e| e.to_string())?;
+            }
+            EntryType::Regular => {
+                bytes += entry.size();
+                if bytes > limits.max_bytes {
+                    return Err(format!(
+                        "Workspace is larger than {} bytes",
+                        limits.max_bytes
+                    ));
+                }
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+                }
+                let mode = entry.header().mode().unwrap_or(0o644);
+                let mut contents = vec![];
+                entry
+                    .read_to_end(&mut contents)
+                    .map_err(|e| format!("Invalid workspace: {}", e))?;
+                fs::write(&target, contents).map_err(|e| e.to_string())?;
+                fs::set_permissions(&target, fs::Permissions::from_mode(mode & 0o777 | 0o666))
+                    .map_err(|e| e.to_string())?;
+            }
+            other => {
+                return Err(format!(
+                    "Workspace entry {:?} is a {:?}, only files and directories are allowed",
+                    path, other
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Write a file given by the submission to `path` under `dest`, creating its directories
+pub fn write_file(dest: &Path, path: &str, contents: &[u8]) -> Result<(), String> {
+    let path = Path::new(path);
+    check_path(path)?;
+    let target = dest.join(path);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        fs::set_permissions(parent, fs::Permissions::from_mode(0o777))
+            .map_err(|e| e.to_string())?;
+    }
+    fs::write(&target, contents).map_err(|e| e.to_string())?;
+    fs::set_permissions(&target, fs::Permissions::from_mode(0o666)).map_err(|e| e.to_string())
+}
+
+// Size and modification time of every file under `dir` the runner didn't write itself,
+// by path relative to `dir`
+pub type Snapshot = BTreeMap<PathBuf, (u64, SystemTime)>;
+
+pub fn snapshot(dir: &Path) -> Snapshot {
+    fn walk(dir: &Path, relative: &Path, snapshot: &mut Snapshot) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if relative.as_os_str().is_empty() && name.to_str().is_some_and(is_reserved) {
+                continue;
+            }
+            let path = relative.join(&name);
+            // Not following symlinks, so a link out of the sandbox isn't walked
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                walk(&entry.path(), &path, snapshot);
+            } else {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                snapshot.insert(path, (metadata.len(), modified));
+            }
+        }
+    }
+    let mut snapshot = Snapshot::new();
+    walk(dir, Path::new(""), &mut snapshot);
+    snapshot
+}
+
+// Files created or modified between two snapshots, in path order
+pub fn changed_files(before: &Snapshot, after: &Snapshot) -> Vec<String> {
+    after
+        .iter()
+        .filter(|(path, file)| before.get(*path) != Some(*file))
+        .map(|(path, _)| path.to_string_lossy().into_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod workspace_test {
+    use tar::{Builder, Header};
+
+    use super::*;
+
+    const LIMITS: WorkspaceLimits = WorkspaceLimits {
+        max_bytes: 1024,
+        max_entries: 8,
+    };
+
+    fn entry(builder: &mut Builder<Vec<u8>>, path: &str, kind: EntryType, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        // Written directly so paths `set_path` refuses still end up in the archive
+        let name = &mut header.as_old_mut().name;
+        name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(kind);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o755);
+        if kind == EntryType::Symlink {
+            header.set_link_name("/etc/passwd").unwrap();
+        }
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    fn archive(entries: &[(&str, EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        for (path, kind, data) in entries {
+            entry(&mut builder, path, *kind, data);
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn project_extracted() {
+        let dest = std::env::temp_dir().join(format!("workspace-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dest).unwrap();
+        let tarball = archive(&[
+            ("src/", EntryType::Directory, b""),
+            ("src/main.c", EntryType::Regular, b"int main() {}\n"),
+            ("./Makefile", EntryType::Regular, b"main: src/main.c\n"),
+        ]);
+        extract(&tarball, &dest, LIMITS).unwrap();
+        assert_eq!(
+            fs::read_to_string(dest.join("src/main.c")).unwrap(),
+            "int main() {}\n"
+        );
+        assert!(dest.join("Makefile").is_file());
+        let mode = fs::metadata(dest.join("src")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o777);
+
+        let mut gzipped = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        std::io::Write::write_all(&mut gzipped, &tarball).unwrap();
+        let nested = dest.join("gz");
+        extract(&gzipped.finish().unwrap(), &nested, LIMITS).unwrap();
+        assert!(nested.join("src/main.c").is_file());
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn malicious_entries_rejected() {
+        let dest = std::env::temp_dir().join(format!("workspace-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dest.join("inner")).unwrap();
+        let inner = dest.join("inner");
+        let crowded = [("a", EntryType::Regular, &b""[..]); 9];
+        let cases: [&[(&str, EntryType, &[u8])]; 6] = [
+            &[("../escaped", EntryType::Regular, b"x")],
+            &[("/tmp/absolute", EntryType::Regular, b"x")],
+            &[("link", EntryType::Symlink, b"")],
+            &[("results.yaml", EntryType::Regular, b"[]")],
+            &[("big", EntryType::Regular, &[0; 2048])],
+            &crowded,
+        ];
+        for entries in cases {
+            assert!(
+                extract(&archive(entries), &inner, LIMITS).is_err(),
+                "{:?}",
+                entries[0].0
+            );
+        }
+        assert!(!dest.join("escaped").exists());
+        assert!(!inner.join("link").exists());
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn written_files_detected() {
+        let dir = std::env::temp_dir().join(format!("workspace-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("lane-0")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main() {}\n").unwrap();
+        fs::write(dir.join("input.txt"), "1 2\n").unwrap();
+        let before = snapshot(&dir);
+        assert_eq!(before.len(), 2);
+
+        fs::write(dir.join("cache.bin"), "answers").unwrap();
+        fs::write(dir.join("input.txt"), "1 2 3\n").unwrap();
+        fs::write(dir.join("results.yaml"), "[]").unwrap();
+        fs::write(dir.join("lane-0/results.yaml"), "[]").unwrap();
+        let after = snapshot(&dir);
+        assert_eq!(changed_files(&before, &after), ["cache.bin", "input.txt"]);
+        assert!(changed_files(&after, &after).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}